@@ -0,0 +1,431 @@
+//! A headless ROTMG client.
+//!
+//! A `Client` connects to a server and drives the protocol state machine
+//! automatically: it sends the `Hello` packet, loads or creates a character
+//! once the map info is received, and responds to the packets the server
+//! expects acknowledgements for (`Ping`, `NewTick`, `Goto`, `Aoe` and shots).
+//! Everything the server sends is surfaced through `Client::next_event`, and
+//! high-level commands such as moving, chatting and using portals are provided
+//! as methods. A `WorldState` is kept up to date with the packets received,
+//! modelling the current map.
+//!
+//! The client only makes progress while `next_event` is being called, so it
+//! should be called continuously, even when events aren't needed.
+
+mod player;
+pub mod world;
+
+use log::debug;
+pub use player::Player;
+use rotmg_networking::codec::{Decoder, Encoder};
+use rotmg_networking::{connect, ConnectionConfig};
+use rotmg_packets::adapters::PacketFormatError;
+use rotmg_packets::structured::data::WorldPosData;
+use rotmg_packets::structured::packets::client::{
+    AoeAck, Create, GotoAck, Hello, Load, Move, PlayerText, Pong, ShootAck, UsePortal,
+};
+use rotmg_packets::structured::packets::server::{
+    Aoe, CreateSuccess, Death, EnemyShoot, Failure, Goto, MapInfo, NewTick, Ping, Reconnect,
+    ServerPlayerShoot, Text, Update,
+};
+use rotmg_packets::structured::packets::AnyPacket;
+use rotmg_packets::Parameters;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::ToSocketAddrs;
+use world::WorldState;
+
+/// An error which ends a client session.
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// A network error.
+    #[error("Network error: {0}")]
+    Io(#[from] io::Error),
+
+    /// A packet couldn't be encoded or decoded.
+    #[error("Packet format error: {0}")]
+    Packet(#[from] Box<PacketFormatError>),
+
+    /// The RC4 keys in the parameters weren't valid hexadecimal.
+    #[error("Invalid RC4 keys: {0}")]
+    InvalidKeys(#[from] hex::FromHexError),
+
+    /// The server asked the client to reconnect to a port which doesn't exist.
+    #[error("Invalid reconnect port: {0}")]
+    InvalidPort(u32),
+
+    /// The server sent a `Failure` packet.
+    #[error("Server failure {id}: {description}")]
+    Failure {
+        /// The error ID sent by the server.
+        id: u32,
+        /// The error description sent by the server.
+        description: String,
+    },
+}
+
+/// The character a client plays as.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Character {
+    /// Load an existing character with the given ID.
+    Load(u32),
+
+    /// Create a new character.
+    Create {
+        /// The object type of the character's class.
+        class_type: u16,
+        /// The skin to use, or 0 for the default skin.
+        skin_type: u16,
+    },
+}
+
+/// Settings for a `Client`.
+///
+/// The credentials are sent in the `Hello` packet as given, so they should
+/// already be encrypted as the server expects.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// The account GUID.
+    pub guid: String,
+
+    /// The account password.
+    pub password: String,
+
+    /// The account secret, used instead of a password by some platforms.
+    pub secret: String,
+
+    /// The character to play as.
+    pub character: Character,
+
+    /// Settings for the connection.
+    pub connection: ConnectionConfig,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            guid: String::new(),
+            password: String::new(),
+            secret: String::new(),
+            character: Character::Create {
+                // the wizard class
+                class_type: 782,
+                skin_type: 0,
+            },
+            connection: ConnectionConfig::default(),
+        }
+    }
+}
+
+/// An event produced by a `Client`.
+#[derive(Debug)]
+pub enum Event {
+    /// The character was loaded into the map.
+    Joined {
+        /// The map that was joined.
+        map: MapInfo,
+        /// The player's object ID.
+        object_id: u32,
+    },
+
+    /// The player reached the target given to `Client::move_to`.
+    Arrived(WorldPosData),
+
+    /// A chat message was received.
+    Text(Text),
+
+    /// The server asked the client to reconnect, usually after using a portal.
+    /// `Client::reconnect` can be used to follow it.
+    Reconnect(Reconnect),
+
+    /// The player died.
+    Death(Death),
+
+    /// A packet without a dedicated event was received. Any response it needs
+    /// has already been sent.
+    Packet(Box<dyn AnyPacket>),
+}
+
+/// The game to join when connecting
+struct Target {
+    game_id: i32,
+    key_time: u32,
+    key: Vec<u8>,
+}
+
+/// A headless ROTMG client.
+pub struct Client {
+    rx: Decoder<OwnedReadHalf>,
+    tx: Encoder<OwnedWriteHalf>,
+    address: SocketAddr,
+    params: Parameters,
+    config: ClientConfig,
+
+    /// When the connection started, which client times are relative to
+    start: Instant,
+    world: WorldState,
+    player: Player,
+    target: Option<WorldPosData>,
+    events: VecDeque<Event>,
+}
+
+impl Client {
+    /// Connect to a server and join the nexus.
+    pub async fn connect(
+        address: impl ToSocketAddrs,
+        params: &Parameters,
+        config: &ClientConfig,
+    ) -> Result<Self, ClientError> {
+        let target = Target {
+            game_id: params.basic.nexus_game_id,
+            key_time: 0,
+            key: Vec::new(),
+        };
+        Self::connect_to(address, params.clone(), config.clone(), target).await
+    }
+
+    /// Follow a `Reconnect` packet, closing this connection and opening a new
+    /// one to the given game.
+    pub async fn reconnect(self, reconnect: &Reconnect) -> Result<Self, ClientError> {
+        // an empty host or a port of -1 means the current server
+        let host = if reconnect.host.is_empty() {
+            self.address.ip().to_string()
+        } else {
+            reconnect.host.clone()
+        };
+        let port = match reconnect.port {
+            u32::MAX => self.address.port(),
+            port if port <= u16::MAX as u32 => port as u16,
+            port => return Err(ClientError::InvalidPort(port)),
+        };
+
+        let target = Target {
+            game_id: reconnect.game_id as i32,
+            key_time: reconnect.key_time,
+            key: reconnect.key.clone(),
+        };
+        Self::connect_to((host.as_str(), port), self.params, self.config, target).await
+    }
+
+    async fn connect_to(
+        address: impl ToSocketAddrs,
+        params: Parameters,
+        config: ClientConfig,
+        target: Target,
+    ) -> Result<Self, ClientError> {
+        let keys = hex::decode(&params.rc4)?;
+        let (rx, tx) = connect(address, &keys, &config.connection).await?;
+        let address = rx.inner().as_ref().peer_addr()?;
+
+        let mut client = Self {
+            rx,
+            tx,
+            address,
+            params,
+            config,
+            start: Instant::now(),
+            world: WorldState::new(),
+            player: Player::default(),
+            target: None,
+            events: VecDeque::new(),
+        };
+
+        let hello = Hello {
+            build_version: client.params.basic.version.clone(),
+            game_id: target.game_id as u32,
+            guid: client.config.guid.clone(),
+            password: client.config.password.clone(),
+            secret: client.config.secret.clone(),
+            key_time: target.key_time,
+            key: target.key,
+            ..Hello::default()
+        };
+        client.send(hello).await?;
+
+        Ok(client)
+    }
+
+    /// Get the address of the server this client is connected to.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Get the map the client is in, once the map info has been received.
+    pub fn map(&self) -> Option<&MapInfo> {
+        self.world.map()
+    }
+
+    /// Get the state of the current map.
+    pub fn world(&self) -> &WorldState {
+        &self.world
+    }
+
+    /// Get the state of the player.
+    pub fn player(&self) -> &Player {
+        &self.player
+    }
+
+    /// Get the current client time, in milliseconds since the connection
+    /// started.
+    pub fn time(&self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+
+    /// Send a packet to the server.
+    pub async fn send(&mut self, packet: impl AnyPacket) -> Result<(), ClientError> {
+        let packet = Box::new(packet).into_raw_mapped(&self.params.packets)?;
+        self.tx.send(packet).await?;
+        Ok(())
+    }
+
+    /// Send a chat message.
+    pub async fn say(&mut self, text: &str) -> Result<(), ClientError> {
+        self.send(PlayerText {
+            text: text.to_string(),
+        })
+        .await
+    }
+
+    /// Use the portal with the given object ID. The server will usually
+    /// respond with a `Reconnect` event.
+    pub async fn use_portal(&mut self, object_id: u32) -> Result<(), ClientError> {
+        self.send(UsePortal { object_id }).await
+    }
+
+    /// Start moving towards the given position, at the player's speed. An
+    /// `Arrived` event is produced once it's reached.
+    pub fn move_to(&mut self, target: WorldPosData) {
+        self.target = Some(target);
+    }
+
+    /// Stop moving.
+    pub fn stop(&mut self) {
+        self.target = None;
+    }
+
+    /// Wait for the next event, handling packets from the server in the
+    /// meantime. Returns `None` once the server closes the connection.
+    pub async fn next_event(&mut self) -> Result<Option<Event>, ClientError> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+
+            let packet = match self.rx.recv().await? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+            let packet = match packet.parse(&self.params.packets) {
+                Ok((packet, _)) => packet,
+                Err(e) => match *e {
+                    PacketFormatError::UnmappedID(id) => {
+                        debug!("Ignoring unmapped packet ID {}", id);
+                        continue;
+                    }
+                    _ => return Err(e.into()),
+                },
+            };
+
+            let event = self.handle_packet(packet).await?;
+            self.events.push_back(event);
+        }
+    }
+
+    /// Send any response a packet needs and update the client state,
+    /// returning the event for the packet
+    async fn handle_packet(&mut self, packet: Box<dyn AnyPacket>) -> Result<Event, ClientError> {
+        let time = self.time();
+        self.world.handle_packet(&*packet);
+
+        if let Some(failure) = packet.downcast_ref::<Failure>() {
+            return Err(ClientError::Failure {
+                id: failure.error_id,
+                description: failure.error_description.clone(),
+            });
+        } else if packet.is::<MapInfo>() {
+            match self.config.character {
+                Character::Load(char_id) => {
+                    self.send(Load {
+                        char_id,
+                        ..Load::default()
+                    })
+                    .await?
+                }
+                Character::Create {
+                    class_type,
+                    skin_type,
+                } => {
+                    self.send(Create {
+                        class_type,
+                        skin_type,
+                        ..Create::default()
+                    })
+                    .await?
+                }
+            }
+        } else if let Some(success) = packet.downcast_ref::<CreateSuccess>() {
+            self.player.object_id = Some(success.object_id);
+            self.config.character = Character::Load(success.char_id);
+            return Ok(Event::Joined {
+                map: self.world.map().cloned().unwrap_or_default(),
+                object_id: success.object_id,
+            });
+        } else if let Some(ping) = packet.downcast_ref::<Ping>() {
+            self.send(Pong {
+                serial: ping.serial,
+                time,
+            })
+            .await?;
+        } else if let Some(update) = packet.downcast_ref::<Update>() {
+            for obj in &update.new_objs {
+                self.player.apply_status(&obj.status, true);
+            }
+        } else if let Some(tick) = packet.downcast_ref::<NewTick>() {
+            for status in &tick.statuses {
+                self.player.apply_status(status, false);
+            }
+            if let Some(target) = &self.target {
+                if self.player.move_towards(target, tick.tick_time) {
+                    self.events
+                        .push_back(Event::Arrived(self.player.position.clone()));
+                    self.target = None;
+                }
+            }
+            self.send(Move {
+                tick_id: tick.tick_id,
+                time,
+                new_position: self.player.position.clone(),
+                records: Vec::new(),
+            })
+            .await?;
+        } else if let Some(goto) = packet.downcast_ref::<Goto>() {
+            if self.player.object_id == Some(goto.object_id) {
+                self.player.position = goto.pos.clone();
+            }
+            self.send(GotoAck { time }).await?;
+        } else if packet.is::<Aoe>() {
+            self.send(AoeAck {
+                time,
+                position: self.player.position.clone(),
+            })
+            .await?;
+        } else if packet.is::<EnemyShoot>() {
+            self.send(ShootAck { time }).await?;
+        } else if let Some(shoot) = packet.downcast_ref::<ServerPlayerShoot>() {
+            if self.player.object_id == Some(shoot.owner_id) {
+                self.send(ShootAck { time }).await?;
+            }
+        } else if packet.is::<Text>() {
+            return Ok(Event::Text(*packet.downcast().unwrap()));
+        } else if packet.is::<Reconnect>() {
+            return Ok(Event::Reconnect(*packet.downcast().unwrap()));
+        } else if packet.is::<Death>() {
+            return Ok(Event::Death(*packet.downcast().unwrap()));
+        }
+
+        Ok(Event::Packet(packet))
+    }
+}
@@ -0,0 +1,106 @@
+//! The state of the client's own player.
+
+use rotmg_packets::structured::data::{ObjectStatusData, StatData, StatType, WorldPosData};
+use std::collections::HashMap;
+
+/// The slowest a player can move, in tiles per millisecond.
+const MIN_MOVE_SPEED: f32 = 0.004;
+
+/// The fastest a player can move without speed boosts, in tiles per
+/// millisecond.
+const MAX_MOVE_SPEED: f32 = 0.0096;
+
+/// The speed stat at which players move at `MAX_MOVE_SPEED`.
+const MAX_SPEED_STAT: f32 = 75.0;
+
+/// The state of the player controlled by a client, as last reported by the
+/// server.
+#[derive(Debug, Clone, Default)]
+pub struct Player {
+    /// The player's object ID, once the client has joined the map.
+    pub object_id: Option<u32>,
+
+    /// The player's position.
+    pub position: WorldPosData,
+
+    /// The player's stats.
+    pub stats: HashMap<StatType, StatData>,
+}
+
+impl Player {
+    /// Get an integer stat, if it's known.
+    pub fn stat(&self, typ: StatType) -> Option<i32> {
+        match self.stats.get(&typ) {
+            Some(StatData::Integer(_, value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Get the speed at which the player moves, in tiles per millisecond.
+    pub fn move_speed(&self) -> f32 {
+        let speed = self.stat(StatType::SPEED_STAT).unwrap_or(0).max(0) as f32;
+        let fraction = (speed / MAX_SPEED_STAT).min(1.0);
+        MIN_MOVE_SPEED + fraction * (MAX_MOVE_SPEED - MIN_MOVE_SPEED)
+    }
+
+    /// Apply an object status if it's for this player.
+    ///
+    /// The position is only taken from the first status received, since
+    /// afterwards the client is responsible for the player's movement.
+    pub(crate) fn apply_status(&mut self, status: &ObjectStatusData, initial: bool) {
+        if self.object_id != Some(status.object_id) {
+            return;
+        }
+
+        if initial {
+            self.position = status.pos.clone();
+        }
+        for stat in &status.stats {
+            let typ = match stat {
+                StatData::Integer(typ, _) | StatData::String(typ, _) => *typ,
+            };
+            self.stats.insert(typ, stat.clone());
+        }
+    }
+
+    /// Move towards the target for the given number of milliseconds, returning
+    /// whether it was reached.
+    pub(crate) fn move_towards(&mut self, target: &WorldPosData, elapsed: u32) -> bool {
+        let (dx, dy) = (target.x - self.position.x, target.y - self.position.y);
+        let distance = (dx * dx + dy * dy).sqrt();
+        let step = self.move_speed() * elapsed as f32;
+
+        if step >= distance {
+            self.position = target.clone();
+            true
+        } else {
+            self.position.x += dx / distance * step;
+            self.position.y += dy / distance * step;
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Player;
+    use rotmg_packets::structured::data::{StatData, StatType, WorldPosData};
+
+    #[test]
+    fn test_move_towards() {
+        let mut player = Player::default();
+        player.stats.insert(
+            StatType::SPEED_STAT,
+            StatData::Integer(StatType::SPEED_STAT, 75),
+        );
+        let target = WorldPosData { x: 3.0, y: 4.0 };
+
+        // at max speed, the player moves 0.96 tiles in 100ms
+        assert!(!player.move_towards(&target, 100));
+        assert!((player.position.x - 0.576).abs() < 1e-4);
+        assert!((player.position.y - 0.768).abs() < 1e-4);
+
+        assert!(player.move_towards(&target, 1000));
+        assert_eq!(player.position, target);
+    }
+}
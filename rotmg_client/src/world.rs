@@ -0,0 +1,319 @@
+//! A model of the current map, built from the packets sent by the server.
+
+use log::debug;
+use rotmg_packets::structured::data::{ObjectStatusData, StatData, StatType, WorldPosData};
+use rotmg_packets::structured::packets::server::{CreateSuccess, Goto, MapInfo, NewTick, Update};
+use rotmg_packets::structured::packets::AnyPacket;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// An object in the world, such as a player, enemy, or portal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameObject {
+    /// The object's unique ID within the map.
+    pub object_id: u32,
+
+    /// The object's type, as defined in the game's object XML.
+    pub object_type: u16,
+
+    /// The object's position.
+    pub position: WorldPosData,
+
+    /// The object's stats, merged from every status update received.
+    pub stats: HashMap<StatType, StatData>,
+}
+
+impl GameObject {
+    /// Get an integer stat, if it's known.
+    pub fn stat(&self, typ: StatType) -> Option<i32> {
+        match self.stats.get(&typ) {
+            Some(StatData::Integer(_, value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Get a string stat, such as the object's name, if it's known.
+    pub fn stat_str(&self, typ: StatType) -> Option<&str> {
+        match self.stats.get(&typ) {
+            Some(StatData::String(_, value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Get the distance from this object to the given position, in tiles.
+    pub fn distance_to(&self, pos: &WorldPosData) -> f32 {
+        let (dx, dy) = (self.position.x - pos.x, self.position.y - pos.y);
+        (dx * dx + dy * dy).sqrt()
+    }
+
+    /// Apply a status update to this object
+    fn apply_status(&mut self, status: &ObjectStatusData) {
+        self.position = status.pos.clone();
+        for stat in &status.stats {
+            let typ = match stat {
+                StatData::Integer(typ, _) | StatData::String(typ, _) => *typ,
+            };
+            self.stats.insert(typ, stat.clone());
+        }
+    }
+}
+
+/// The state of the current map, as last reported by the server.
+///
+/// Packets received from the server should be passed to `handle_packet` in
+/// order. A `MapInfo` packet starts a new map, clearing all existing state.
+#[derive(Debug, Default)]
+pub struct WorldState {
+    map: Option<MapInfo>,
+
+    /// Tile types, by position
+    ///
+    /// Tiles are stored sparsely, since only those near the player are sent,
+    /// and the map size comes from the server.
+    tiles: HashMap<(i32, i32), u16>,
+    objects: HashMap<u32, GameObject>,
+    player_id: Option<u32>,
+
+    tick_id: Option<u32>,
+    tick_time: u32,
+    last_tick: Option<Instant>,
+}
+
+impl WorldState {
+    /// Create an empty world state, with no map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the state using a packet received from the server. Packets which
+    /// don't affect the state are ignored.
+    pub fn handle_packet(&mut self, packet: &dyn AnyPacket) {
+        if let Some(map) = packet.downcast_ref::<MapInfo>() {
+            *self = Self {
+                map: Some(map.clone()),
+                ..Self::default()
+            };
+        } else if let Some(success) = packet.downcast_ref::<CreateSuccess>() {
+            self.player_id = Some(success.object_id);
+        } else if let Some(update) = packet.downcast_ref::<Update>() {
+            for tile in &update.tiles {
+                let (x, y) = (tile.x.into(), tile.y.into());
+                if self.in_bounds(x, y) {
+                    self.tiles.insert((x, y), tile.tile_type);
+                } else {
+                    debug!("Ignoring tile outside map at ({}, {})", x, y);
+                }
+            }
+            for obj in &update.new_objs {
+                let mut object = GameObject {
+                    object_id: obj.status.object_id,
+                    object_type: obj.object_type,
+                    position: WorldPosData::default(),
+                    stats: HashMap::new(),
+                };
+                object.apply_status(&obj.status);
+                self.objects.insert(object.object_id, object);
+            }
+            for &id in &update.drops {
+                self.objects.remove(&(id as u32));
+            }
+        } else if let Some(tick) = packet.downcast_ref::<NewTick>() {
+            for status in &tick.statuses {
+                match self.objects.get_mut(&status.object_id) {
+                    Some(object) => object.apply_status(status),
+                    None => debug!("Ignoring status of unknown object {}", status.object_id),
+                }
+            }
+            self.tick_id = Some(tick.tick_id);
+            self.tick_time = tick.tick_time;
+            self.last_tick = Some(Instant::now());
+        } else if let Some(goto) = packet.downcast_ref::<Goto>() {
+            if let Some(object) = self.objects.get_mut(&goto.object_id) {
+                object.position = goto.pos.clone();
+            }
+        }
+    }
+
+    fn in_bounds(&self, x: i32, y: i32) -> bool {
+        match &self.map {
+            Some(map) => x >= 0 && y >= 0 && (x as u32) < map.width && y < map.height,
+            None => false,
+        }
+    }
+
+    /// Get the info of the current map, if one has been joined.
+    pub fn map(&self) -> Option<&MapInfo> {
+        self.map.as_ref()
+    }
+
+    /// Get the type of the tile at the given position, if it's known.
+    pub fn tile(&self, x: i32, y: i32) -> Option<u16> {
+        self.tiles.get(&(x, y)).copied()
+    }
+
+    /// Get the type of the tile under the given world position, if it's known.
+    pub fn tile_at(&self, pos: &WorldPosData) -> Option<u16> {
+        self.tile(pos.x.floor() as i32, pos.y.floor() as i32)
+    }
+
+    /// Get an object by its ID.
+    pub fn object(&self, object_id: u32) -> Option<&GameObject> {
+        self.objects.get(&object_id)
+    }
+
+    /// Get all objects in the world, in no particular order.
+    pub fn objects(&self) -> impl Iterator<Item = &GameObject> {
+        self.objects.values()
+    }
+
+    /// Get the object ID of the player, once the client has joined the map.
+    pub fn player_id(&self) -> Option<u32> {
+        self.player_id
+    }
+
+    /// Get the player's own object, once the server has sent it.
+    pub fn player(&self) -> Option<&GameObject> {
+        self.object(self.player_id?)
+    }
+
+    /// Get all objects within the given distance of a position, in no
+    /// particular order.
+    pub fn objects_within<'a>(
+        &'a self,
+        pos: &'a WorldPosData,
+        radius: f32,
+    ) -> impl Iterator<Item = &'a GameObject> + 'a {
+        self.objects().filter(move |o| o.distance_to(pos) <= radius)
+    }
+
+    /// Get the object of the given type nearest to a position.
+    pub fn nearest_of_type(&self, object_type: u16, pos: &WorldPosData) -> Option<&GameObject> {
+        // objects with invalid positions can't be compared, so they're skipped
+        self.objects()
+            .filter(|o| o.object_type == object_type)
+            .map(|o| (o, o.distance_to(pos)))
+            .filter(|(_, distance)| !distance.is_nan())
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+            .map(|(o, _)| o)
+    }
+
+    /// Get the ID of the last tick received.
+    pub fn tick_id(&self) -> Option<u32> {
+        self.tick_id
+    }
+
+    /// Get the time between the last two ticks according to the server, in
+    /// milliseconds.
+    pub fn tick_time(&self) -> u32 {
+        self.tick_time
+    }
+
+    /// Get when the last tick was received.
+    pub fn last_tick(&self) -> Option<Instant> {
+        self.last_tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WorldState;
+    use rotmg_packets::structured::data::{
+        GroundTileData, ObjectData, ObjectStatusData, StatData, StatType, WorldPosData,
+    };
+    use rotmg_packets::structured::packets::server::{CreateSuccess, MapInfo, NewTick, Update};
+
+    fn object(object_id: u32, object_type: u16, x: f32, y: f32) -> ObjectData {
+        ObjectData {
+            object_type,
+            status: ObjectStatusData {
+                object_id,
+                pos: WorldPosData { x, y },
+                stats: vec![StatData::Integer(StatType::HP_STAT, 100)],
+            },
+        }
+    }
+
+    #[test]
+    fn test_world_state() {
+        let mut world = WorldState::new();
+        world.handle_packet(&MapInfo {
+            width: 16,
+            height: 8,
+            name: "Nexus".to_string(),
+            ..MapInfo::default()
+        });
+        world.handle_packet(&CreateSuccess {
+            object_id: 1,
+            char_id: 2,
+        });
+        world.handle_packet(&Update {
+            tiles: vec![
+                GroundTileData {
+                    x: 3,
+                    y: 4,
+                    tile_type: 0x70,
+                },
+                // outside the map, so ignored
+                GroundTileData {
+                    x: 16,
+                    y: 0,
+                    tile_type: 0x70,
+                },
+            ],
+            new_objs: vec![
+                object(1, 782, 3.5, 4.5),
+                object(2, 0x0700, 5.0, 4.5),
+                object(3, 0x0700, 10.0, 4.5),
+                object(4, 0x0701, 4.0, 4.5),
+                object(5, 0x0700, f32::NAN, 4.5),
+            ],
+            drops: vec![4],
+        });
+
+        assert_eq!(world.tile(3, 4), Some(0x70));
+        assert_eq!(world.tile(4, 4), None);
+        assert_eq!(world.tile(16, 0), None);
+        assert_eq!(world.tile_at(&WorldPosData { x: 3.9, y: 4.1 }), Some(0x70));
+        assert!(world.object(4).is_none());
+
+        // statuses are merged into the existing stats
+        world.handle_packet(&NewTick {
+            tick_id: 7,
+            tick_time: 200,
+            statuses: vec![ObjectStatusData {
+                object_id: 3,
+                pos: WorldPosData { x: 6.0, y: 4.5 },
+                stats: vec![StatData::String(StatType::NAME_STAT, "Guard".to_string())],
+            }],
+        });
+        let guard = world.object(3).unwrap();
+        assert_eq!(guard.position, WorldPosData { x: 6.0, y: 4.5 });
+        assert_eq!(guard.stat(StatType::HP_STAT), Some(100));
+        assert_eq!(guard.stat_str(StatType::NAME_STAT), Some("Guard"));
+        assert_eq!(world.tick_id(), Some(7));
+        assert_eq!(world.tick_time(), 200);
+
+        let player = world.player().unwrap();
+        assert_eq!(player.object_type, 782);
+        let nearest = world.nearest_of_type(0x0700, &player.position).unwrap();
+        assert_eq!(nearest.object_id, 2);
+        let mut near = world
+            .objects_within(&player.position, 2.0)
+            .map(|o| o.object_id)
+            .collect::<Vec<_>>();
+        near.sort();
+        assert_eq!(near, vec![1, 2]);
+
+        // a new map clears everything, and tiles are only stored once
+        // they're sent, however large the map
+        world.handle_packet(&MapInfo {
+            width: u32::MAX,
+            height: i32::MAX,
+            ..MapInfo::default()
+        });
+        assert!(world.player().is_none());
+        assert_eq!(world.objects().count(), 0);
+        assert_eq!(world.tile(3, 4), None);
+    }
+}
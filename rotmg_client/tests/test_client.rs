@@ -0,0 +1,178 @@
+use rotmg_client::{Client, ClientConfig, ClientError, Event};
+use rotmg_mock_server::{MockServer, MockServerConfig, Scenario};
+use rotmg_packets::structured::data::{
+    ObjectData, ObjectStatusData, StatData, StatType, WorldPosData,
+};
+use rotmg_packets::structured::packets::client::{GotoAck, Move, PlayerText};
+use rotmg_packets::structured::packets::server::{Failure, Goto, Reconnect, Text, Update};
+use rotmg_packets::structured::packets::PacketType;
+use rotmg_packets::{BasicParameters, PacketMappings, Parameters};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+fn params() -> Parameters {
+    Parameters {
+        packets: PacketMappings::new(
+            PacketType::VALUES
+                .iter()
+                .map(|&t| (t, (t as u8).wrapping_add(100))),
+        ),
+        rc4: "0102030405060708090a".to_string(),
+        basic: BasicParameters {
+            version: "1.0".to_string(),
+            port: 2050,
+            tutorial_game_id: -1,
+            nexus_game_id: -2,
+            random_game_id: -3,
+        },
+    }
+}
+
+fn pos(x: f32, y: f32) -> WorldPosData {
+    WorldPosData { x, y }
+}
+
+async fn start_server(scenario: Scenario) -> (MockServer, SocketAddr) {
+    let server = MockServer::bind(
+        (Ipv4Addr::LOCALHOST, 0),
+        &params(),
+        scenario,
+        MockServerConfig::default(),
+    )
+    .await
+    .unwrap();
+    let address = server.local_addr().unwrap();
+    (server, address)
+}
+
+#[tokio::test]
+async fn test_session() {
+    // spawn the player at full speed, then teleport them
+    let player = ObjectData {
+        object_type: 782,
+        status: ObjectStatusData {
+            object_id: Scenario::PLAYER_ID,
+            pos: pos(10.0, 10.0),
+            stats: vec![StatData::Integer(StatType::SPEED_STAT, 75)],
+        },
+    };
+    let scenario = Scenario::join("Nexus")
+        .send(Update {
+            new_objs: vec![player],
+            ..Update::default()
+        })
+        .ping()
+        .send(Goto {
+            object_id: Scenario::PLAYER_ID,
+            pos: pos(20.0, 20.0),
+        })
+        .expect::<GotoAck>()
+        .send(Text {
+            name: "Oryx".to_string(),
+            text: "hello".to_string(),
+            ..Text::default()
+        })
+        .expect_with(|text: &PlayerText| text.text == "hello back")
+        .ticks(3, Duration::from_millis(100));
+
+    let (mut server, address) = start_server(scenario).await;
+    let server = tokio::spawn(async move { server.run_session().await.unwrap() });
+
+    let mut client = Client::connect(address, &params(), &ClientConfig::default())
+        .await
+        .unwrap();
+    let mut joined = false;
+    let mut arrived = None;
+    while let Some(event) = client.next_event().await.unwrap() {
+        match event {
+            Event::Joined { map, object_id } => {
+                assert_eq!(map.name, "Nexus");
+                assert_eq!(object_id, Scenario::PLAYER_ID);
+                joined = true;
+                client.move_to(pos(21.0, 20.0));
+            }
+            Event::Text(text) => {
+                assert_eq!(text.text, "hello");
+                client.say("hello back").await.unwrap();
+            }
+            Event::Arrived(position) => arrived = Some(position),
+            _ => {}
+        }
+    }
+
+    assert!(joined);
+    assert_eq!(arrived, Some(pos(21.0, 20.0)));
+    assert_eq!(client.player().move_speed(), 0.0096);
+
+    // the movement should have started from where the player was teleported
+    let report = server.await.unwrap();
+    let moves = report.received_of::<Move>().collect::<Vec<_>>();
+    assert_eq!(moves.len(), 3);
+    assert!(moves[0].new_position.x >= 20.0 && moves[0].new_position.x < 21.0);
+    assert_eq!(moves[2].new_position, pos(21.0, 20.0));
+}
+
+#[tokio::test]
+async fn test_reconnect() {
+    let scenario = Scenario::join("Nexus")
+        .send(Reconnect {
+            name: "Realm".to_string(),
+            host: "localhost".to_string(),
+            port: u32::MAX,
+            game_id: 5,
+            key_time: 7,
+            key: vec![1, 2, 3],
+            ..Reconnect::default()
+        })
+        .expect_disconnect();
+
+    let (mut server, address) = start_server(scenario).await;
+    let server = tokio::spawn(async move {
+        let first = server.run_session().await.unwrap();
+        let second = server.run_session().await.unwrap();
+        (first, second)
+    });
+
+    let mut client = Client::connect(address, &params(), &ClientConfig::default())
+        .await
+        .unwrap();
+    let mut reconnected = false;
+    while let Some(event) = client.next_event().await.unwrap() {
+        if let Event::Reconnect(reconnect) = event {
+            if reconnected {
+                break;
+            }
+            client = client.reconnect(&reconnect).await.unwrap();
+            reconnected = true;
+        }
+    }
+    drop(client);
+
+    let (first, second) = server.await.unwrap();
+    assert_eq!(first.hello.game_id, -2i32 as u32);
+    assert_eq!(second.hello.game_id, 5);
+    assert_eq!(second.hello.key_time, 7);
+    assert_eq!(second.hello.key, vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn test_failure() {
+    let scenario = Scenario::new().send(Failure {
+        error_id: 4,
+        error_description: "Client version mismatch".to_string(),
+        ..Failure::default()
+    });
+
+    let (mut server, address) = start_server(scenario).await;
+    tokio::spawn(async move { server.run_session().await });
+
+    let mut client = Client::connect(address, &params(), &ClientConfig::default())
+        .await
+        .unwrap();
+    match client.next_event().await {
+        Err(ClientError::Failure { id: 4, description }) => {
+            assert_eq!(description, "Client version mismatch");
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
@@ -0,0 +1,457 @@
+//! A scripted mock ROTMG server.
+//!
+//! This allows clients and proxies to be tested entirely offline. A
+//! `MockServer` accepts connections using the same networking code as a real
+//! server would, handling policy file requests, and runs a `Scenario` for each
+//! session: a scripted sequence of packets to send and packets the client is
+//! expected to respond with.
+//!
+//! Along with the packets the scenario expects, `Pong` and `Move` responses
+//! are validated against the `Ping` and `NewTick` they respond to, and the
+//! client timestamps they contain are checked to be consistent with the time
+//! actually elapsed.
+
+mod scenario;
+
+use log::{debug, info, warn};
+use rotmg_networking::codec::{Decoder, Encoder};
+use rotmg_networking::rewind::Rewind;
+use rotmg_networking::{accept_session, ConnectionConfig, PolicyFile};
+use rotmg_packets::adapters::PacketFormatError;
+use rotmg_packets::structured::packets::client::{Hello, Move, Pong};
+use rotmg_packets::structured::packets::server::{NewTick, Ping};
+use rotmg_packets::structured::packets::{AnyPacket, PacketType, StructuredPacket};
+use rotmg_packets::{PacketMappings, Parameters};
+pub use scenario::Scenario;
+use scenario::Step;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::time::{delay_for, timeout_at, Instant};
+
+/// An error causing a mock session to fail.
+#[derive(Debug, thiserror::Error)]
+pub enum MockError {
+    /// A network error, or an error accepting the session.
+    #[error("Network error: {0}")]
+    Io(#[from] io::Error),
+
+    /// A packet received from the client couldn't be parsed.
+    #[error("Error parsing packet: {0}")]
+    InvalidPacket(#[from] Box<PacketFormatError>),
+
+    /// The client closed the connection while a response was expected.
+    #[error("Connection closed while waiting for {0}")]
+    Disconnected(String),
+
+    /// The client didn't respond in time.
+    #[error("Timed out waiting for {0}")]
+    Timeout(String),
+
+    /// The client sent a packet which wasn't allowed at that point.
+    #[error("Unexpected {0:?} packet")]
+    UnexpectedPacket(PacketType),
+
+    /// An expected packet didn't pass the scenario's check.
+    #[error("{0:?} packet failed check: {1}")]
+    CheckFailed(PacketType, String),
+
+    /// A `Pong` didn't match the `Ping` it responded to.
+    #[error("Expected Pong with serial {expected}, but received serial {received}")]
+    PongMismatch {
+        /// The serial of the outstanding `Ping`.
+        expected: u32,
+        /// The serial of the `Pong` received.
+        received: u32,
+    },
+
+    /// A `Move` didn't match the `NewTick` it responded to.
+    #[error("Expected Move for tick {expected}, but received tick {received}")]
+    MoveMismatch {
+        /// The ID of the outstanding `NewTick`.
+        expected: u32,
+        /// The tick ID of the `Move` received.
+        received: u32,
+    },
+
+    /// A client timestamp was earlier than the previous one.
+    #[error("{packet:?} time {received} is before the previous time {previous}")]
+    TimeWentBackwards {
+        /// The packet with the invalid timestamp.
+        packet: PacketType,
+        /// The previous client timestamp.
+        previous: u32,
+        /// The timestamp received.
+        received: u32,
+    },
+
+    /// A client timestamp advanced further than the time actually elapsed.
+    #[error("{packet:?} time advanced by {client}ms, but only {server}ms passed")]
+    ClockTooFast {
+        /// The packet with the invalid timestamp.
+        packet: PacketType,
+        /// How far the client's clock advanced, in milliseconds.
+        client: u32,
+        /// How much time passed on the server, in milliseconds.
+        server: u32,
+    },
+}
+
+/// Settings for a `MockServer`.
+#[derive(Debug, Clone)]
+pub struct MockServerConfig {
+    /// Maximum time to wait for each packet the scenario expects.
+    pub response_timeout: Duration,
+
+    /// How far the client's clock may run ahead of the server's between
+    /// timestamped packets, to allow for scheduling jitter.
+    pub clock_tolerance: Duration,
+
+    /// Settings for accepted connections. The handshake timeout applies to
+    /// receiving the `Hello` packet.
+    pub connection: ConnectionConfig,
+}
+
+impl Default for MockServerConfig {
+    fn default() -> Self {
+        Self {
+            response_timeout: Duration::from_secs(5),
+            clock_tolerance: Duration::from_millis(100),
+            connection: ConnectionConfig {
+                handshake_timeout: Some(Duration::from_secs(5)),
+                ..ConnectionConfig::default()
+            },
+        }
+    }
+}
+
+/// The result of a successful mock session.
+#[derive(Debug)]
+pub struct SessionReport {
+    /// The client's address.
+    pub client: SocketAddr,
+
+    /// The `Hello` packet that started the session.
+    pub hello: Hello,
+
+    /// Every packet received from the client after the `Hello`, in order.
+    pub received: Vec<Box<dyn AnyPacket>>,
+}
+
+impl SessionReport {
+    /// Get all received packets of the given type.
+    pub fn received_of<P: StructuredPacket>(&self) -> impl Iterator<Item = &P> {
+        self.received.iter().filter_map(|p| p.downcast_ref::<P>())
+    }
+}
+
+/// A mock ROTMG server running a scripted scenario for each session.
+pub struct MockServer {
+    listener: TcpListener,
+    keys: Vec<u8>,
+    mappings: PacketMappings,
+    scenario: Scenario,
+    config: MockServerConfig,
+}
+
+impl MockServer {
+    /// Bind a mock server to the given address, using the RC4 keys and packet
+    /// mappings from the given parameters.
+    pub async fn bind(
+        address: impl ToSocketAddrs,
+        params: &Parameters,
+        scenario: Scenario,
+        config: MockServerConfig,
+    ) -> io::Result<Self> {
+        let keys =
+            hex::decode(&params.rc4).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        Ok(Self {
+            listener: TcpListener::bind(address).await?,
+            keys,
+            mappings: params.packets.clone(),
+            scenario,
+            config,
+        })
+    }
+
+    /// Get the address this server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Wait for the next session and run the scenario for it.
+    ///
+    /// Policy file requests are handled while waiting, and don't count as
+    /// sessions.
+    pub async fn run_session(&mut self) -> Result<SessionReport, MockError> {
+        loop {
+            let (conn, client) = self.listener.accept().await?;
+            if let Some(report) = self.handle_connection(conn, client).await? {
+                return Ok(report);
+            }
+        }
+    }
+
+    /// Run sessions one at a time until accepting a connection fails, logging
+    /// the result of each.
+    pub async fn run(mut self) -> io::Result<()> {
+        loop {
+            let (conn, client) = self.listener.accept().await?;
+            match self.handle_connection(conn, client).await {
+                Ok(Some(_)) => info!("Mock session with {} succeeded", client),
+                Ok(None) => {}
+                Err(e) => warn!("Mock session with {} failed: {}", client, e),
+            }
+        }
+    }
+
+    /// Run the scenario for a connection, returning `None` if it was a policy
+    /// file request
+    async fn handle_connection(
+        &self,
+        conn: TcpStream,
+        client: SocketAddr,
+    ) -> Result<Option<SessionReport>, MockError> {
+        let accepted = accept_session(
+            conn,
+            &self.keys,
+            &PolicyFile::ALLOW_ALL,
+            &self.mappings,
+            &self.config.connection,
+        )
+        .await?;
+        let (hello, rx, tx) = match accepted {
+            Some(accepted) => accepted,
+            None => return Ok(None),
+        };
+
+        debug!("Starting mock session with {}", client);
+        let session = Session::new(rx, tx, &self.mappings, &self.config);
+        let received = session.run(&self.scenario).await?;
+        Ok(Some(SessionReport {
+            client,
+            hello,
+            received,
+        }))
+    }
+}
+
+/// The state of a running mock session
+struct Session<'a> {
+    rx: Decoder<Rewind<OwnedReadHalf>>,
+    tx: Encoder<OwnedWriteHalf>,
+    mappings: &'a PacketMappings,
+    config: &'a MockServerConfig,
+    received: Vec<Box<dyn AnyPacket>>,
+
+    ping_serial: u32,
+    pending_ping: Option<u32>,
+    tick_id: u32,
+    pending_tick: Option<u32>,
+    last_tick: Instant,
+
+    /// The last client timestamp, and when it was received
+    last_client_time: Option<(u32, Instant)>,
+}
+
+impl<'a> Session<'a> {
+    fn new(
+        rx: Decoder<Rewind<OwnedReadHalf>>,
+        tx: Encoder<OwnedWriteHalf>,
+        mappings: &'a PacketMappings,
+        config: &'a MockServerConfig,
+    ) -> Self {
+        Self {
+            rx,
+            tx,
+            mappings,
+            config,
+            received: Vec::new(),
+            ping_serial: 0,
+            pending_ping: None,
+            tick_id: 0,
+            pending_tick: None,
+            last_tick: Instant::now(),
+            last_client_time: None,
+        }
+    }
+
+    async fn run(mut self, scenario: &Scenario) -> Result<Vec<Box<dyn AnyPacket>>, MockError> {
+        for step in &scenario.steps {
+            debug!("Running mock step {:?}", step);
+            match step {
+                Step::Send(typ, packet) => {
+                    let id = self
+                        .mappings
+                        .to_game(*typ)
+                        .ok_or_else(|| Box::new(PacketFormatError::UnmappedType(*typ)))?;
+                    let mut packet = packet.clone();
+                    packet.set_id(id);
+                    self.tx.send(packet).await?;
+                }
+                Step::Expect(types, check) => {
+                    let waiting_for = types
+                        .iter()
+                        .map(|t| t.name())
+                        .collect::<Vec<_>>()
+                        .join(" or ");
+                    let deadline = self.deadline();
+                    loop {
+                        let packet = self.expect_packet(deadline, &waiting_for).await?;
+                        let typ = packet.packet_type();
+                        if !types.contains(&typ) {
+                            continue;
+                        }
+                        if let Some(check) = check {
+                            if !check(packet) {
+                                return Err(MockError::CheckFailed(typ, format!("{:?}", packet)));
+                            }
+                        }
+                        break;
+                    }
+                }
+                Step::Ping => {
+                    self.ping_serial += 1;
+                    let serial = self.ping_serial;
+                    self.send(Ping { serial }).await?;
+                    self.pending_ping = Some(serial);
+
+                    let deadline = self.deadline();
+                    while self.pending_ping.is_some() {
+                        self.expect_packet(deadline, "Pong").await?;
+                    }
+                }
+                Step::Tick => {
+                    self.tick_id += 1;
+                    let tick_id = self.tick_id;
+                    let now = Instant::now();
+                    let tick_time = now.duration_since(self.last_tick).as_millis() as u32;
+                    self.last_tick = now;
+                    self.send(NewTick {
+                        tick_id,
+                        tick_time,
+                        statuses: Vec::new(),
+                    })
+                    .await?;
+                    self.pending_tick = Some(tick_id);
+
+                    let deadline = self.deadline();
+                    while self.pending_tick.is_some() {
+                        self.expect_packet(deadline, "Move").await?;
+                    }
+                }
+                Step::Delay(duration) => delay_for(*duration).await,
+                Step::ExpectDisconnect => {
+                    let deadline = self.deadline();
+                    if let Some(typ) = self
+                        .recv(deadline, "disconnect")
+                        .await?
+                        .map(|i| self.received[i].packet_type())
+                    {
+                        return Err(MockError::UnexpectedPacket(typ));
+                    }
+                }
+            }
+        }
+
+        self.tx.flush().await?;
+        Ok(self.received)
+    }
+
+    fn deadline(&self) -> Instant {
+        Instant::now() + self.config.response_timeout
+    }
+
+    async fn send(&mut self, packet: impl AnyPacket) -> Result<(), MockError> {
+        let packet = Box::new(packet).into_raw_mapped(self.mappings)?;
+        self.tx.send(packet).await?;
+        Ok(())
+    }
+
+    /// Receive a packet, failing if the connection is closed
+    async fn expect_packet(
+        &mut self,
+        deadline: Instant,
+        waiting_for: &str,
+    ) -> Result<&dyn AnyPacket, MockError> {
+        match self.recv(deadline, waiting_for).await? {
+            Some(i) => Ok(&*self.received[i]),
+            None => Err(MockError::Disconnected(waiting_for.to_string())),
+        }
+    }
+
+    /// Receive, validate and record the next packet, returning its index in
+    /// the received packets, or `None` if the connection was closed
+    async fn recv(
+        &mut self,
+        deadline: Instant,
+        waiting_for: &str,
+    ) -> Result<Option<usize>, MockError> {
+        let packet = match timeout_at(deadline, self.rx.recv()).await {
+            Ok(Ok(Some(packet))) => packet.parse(self.mappings)?.0,
+            Ok(Ok(None)) => return Ok(None),
+            // the connection being reset counts as closing it
+            Ok(Err(e)) if e.kind() == ErrorKind::ConnectionReset => return Ok(None),
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(MockError::Timeout(waiting_for.to_string())),
+        };
+
+        if let Some(pong) = packet.downcast_ref::<Pong>() {
+            match self.pending_ping.take() {
+                Some(serial) if serial == pong.serial => {}
+                Some(serial) => {
+                    return Err(MockError::PongMismatch {
+                        expected: serial,
+                        received: pong.serial,
+                    })
+                }
+                None => return Err(MockError::UnexpectedPacket(PacketType::Pong)),
+            }
+            self.check_client_time(PacketType::Pong, pong.time)?;
+        } else if let Some(m) = packet.downcast_ref::<Move>() {
+            match self.pending_tick.take() {
+                Some(tick_id) if tick_id == m.tick_id => {}
+                Some(tick_id) => {
+                    return Err(MockError::MoveMismatch {
+                        expected: tick_id,
+                        received: m.tick_id,
+                    })
+                }
+                None => return Err(MockError::UnexpectedPacket(PacketType::Move)),
+            }
+            self.check_client_time(PacketType::Move, m.time)?;
+        }
+
+        self.received.push(packet);
+        Ok(Some(self.received.len() - 1))
+    }
+
+    /// Check that a client timestamp is consistent with the previous one
+    fn check_client_time(&mut self, packet: PacketType, time: u32) -> Result<(), MockError> {
+        let now = Instant::now();
+        if let Some((previous, at)) = self.last_client_time {
+            if time < previous {
+                return Err(MockError::TimeWentBackwards {
+                    packet,
+                    previous,
+                    received: time,
+                });
+            }
+
+            let client = time - previous;
+            let server = now.duration_since(at);
+            if Duration::from_millis(client.into()) > server + self.config.clock_tolerance {
+                return Err(MockError::ClockTooFast {
+                    packet,
+                    client,
+                    server: server.as_millis() as u32,
+                });
+            }
+        }
+
+        self.last_client_time = Some((time, now));
+        Ok(())
+    }
+}
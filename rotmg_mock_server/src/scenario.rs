@@ -0,0 +1,172 @@
+//! Scripted scenarios run by the mock server for each session.
+
+use rotmg_packets::raw::RawPacket;
+use rotmg_packets::structured::packets::server::{CreateSuccess, MapInfo, Update};
+use rotmg_packets::structured::packets::{AnyPacket, PacketType, StructuredPacket};
+use std::fmt;
+use std::time::Duration;
+
+/// A check applied to a received packet, which is known to be of the expected
+/// type.
+pub(crate) type Check = Box<dyn Fn(&dyn AnyPacket) -> bool + Send + Sync>;
+
+/// A single step of a scenario.
+pub(crate) enum Step {
+    /// Send a packet, which is encoded with its internal ID.
+    Send(PacketType, Box<RawPacket>),
+
+    /// Wait for a packet of any of the given types, which must pass the check
+    /// if any.
+    Expect(Vec<PacketType>, Option<Check>),
+
+    /// Send a `Ping` and wait for the matching `Pong`.
+    Ping,
+
+    /// Send a `NewTick` and wait for the matching `Move`.
+    Tick,
+
+    /// Wait before running the next step.
+    Delay(Duration),
+
+    /// Wait for the client to close the connection.
+    ExpectDisconnect,
+}
+
+impl fmt::Debug for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Send(typ, _) => f.debug_tuple("Send").field(typ).finish(),
+            Step::Expect(types, _) => f.debug_tuple("Expect").field(types).finish(),
+            Step::Ping => f.write_str("Ping"),
+            Step::Tick => f.write_str("Tick"),
+            Step::Delay(duration) => f.debug_tuple("Delay").field(duration).finish(),
+            Step::ExpectDisconnect => f.write_str("ExpectDisconnect"),
+        }
+    }
+}
+
+/// A scripted sequence of steps run by a `MockServer` for each session.
+///
+/// Every session starts with the client's `Hello` packet, which is received
+/// before the first step runs. Steps are then run in order, and the session
+/// ends once they're complete or as soon as one fails.
+///
+/// While waiting for a packet, any other packets received from the client are
+/// recorded and skipped. `Pong` and `Move` packets are always validated
+/// against the most recent `Ping` and `NewTick`, whichever step they arrive
+/// during.
+///
+/// ```no_run
+/// # use rotmg_mock_server::Scenario;
+/// # use rotmg_packets::structured::packets::client::PlayerText;
+/// let scenario = Scenario::join("Nexus")
+///     .tick()
+///     .ping()
+///     .expect_with(|text: &PlayerText| text.text == "hello")
+///     .expect_disconnect();
+/// ```
+#[derive(Debug, Default)]
+pub struct Scenario {
+    pub(crate) steps: Vec<Step>,
+}
+
+impl Scenario {
+    /// The object ID assigned to the player by `join`.
+    pub const PLAYER_ID: u32 = 1;
+
+    /// Create an empty scenario.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a scenario for joining the given map, as the official server
+    /// does: `MapInfo` is sent in response to `Hello`, then `CreateSuccess`
+    /// in response to `Load` or `Create`, followed by an empty `Update`.
+    pub fn join(map_name: &str) -> Self {
+        Self::new()
+            .send(MapInfo {
+                width: 64,
+                height: 64,
+                name: map_name.to_string(),
+                display_name: map_name.to_string(),
+                allow_player_teleport: true,
+                show_displays: true,
+                max_players: 85,
+                ..MapInfo::default()
+            })
+            .expect_any(&[PacketType::Load, PacketType::Create])
+            .send(CreateSuccess {
+                object_id: Self::PLAYER_ID,
+                char_id: 1,
+            })
+            .send(Update::default())
+    }
+
+    /// Send a packet to the client.
+    pub fn send<P: AnyPacket>(mut self, packet: P) -> Self {
+        // the packet is encoded up front so that scenarios can be shared
+        // between sessions, and mapped to its ROTMG ID when it's sent
+        let typ = packet.packet_type();
+        let packet = Box::new(packet)
+            .into_raw()
+            .expect("error encoding scenario packet");
+        self.steps.push(Step::Send(typ, packet));
+        self
+    }
+
+    /// Wait for the client to send a packet of the given type.
+    pub fn expect<P: StructuredPacket>(self) -> Self {
+        self.expect_any(&[P::TYPE])
+    }
+
+    /// Wait for the client to send a packet of any of the given types.
+    pub fn expect_any(mut self, types: &[PacketType]) -> Self {
+        self.steps.push(Step::Expect(types.to_vec(), None));
+        self
+    }
+
+    /// Wait for the client to send a packet of the given type, which must pass
+    /// the given check.
+    pub fn expect_with<P: StructuredPacket>(
+        mut self,
+        check: impl Fn(&P) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        let check: Check = Box::new(move |packet| check(packet.downcast_ref::<P>().unwrap()));
+        self.steps.push(Step::Expect(vec![P::TYPE], Some(check)));
+        self
+    }
+
+    /// Send a `Ping` to the client and wait for a `Pong` with the same serial.
+    pub fn ping(mut self) -> Self {
+        self.steps.push(Step::Ping);
+        self
+    }
+
+    /// Send a `NewTick` to the client and wait for a `Move` with the same tick
+    /// ID.
+    pub fn tick(mut self) -> Self {
+        self.steps.push(Step::Tick);
+        self
+    }
+
+    /// Repeatedly send a `NewTick` and wait for the matching `Move`, the given
+    /// number of times, with the given delay between each tick.
+    pub fn ticks(mut self, count: usize, interval: Duration) -> Self {
+        for _ in 0..count {
+            self = self.tick().delay(interval);
+        }
+        self
+    }
+
+    /// Wait for the given duration before running the next step.
+    pub fn delay(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Delay(duration));
+        self
+    }
+
+    /// Wait for the client to close the connection.
+    pub fn expect_disconnect(mut self) -> Self {
+        self.steps.push(Step::ExpectDisconnect);
+        self
+    }
+}
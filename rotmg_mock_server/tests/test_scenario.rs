@@ -0,0 +1,194 @@
+use rotmg_mock_server::{MockError, MockServer, MockServerConfig, Scenario};
+use rotmg_networking::{connect, ConnectionConfig, PolicyFile};
+use rotmg_packets::structured::packets::client::{Hello, Load, Move, PlayerText, Pong};
+use rotmg_packets::structured::packets::server::{MapInfo, NewTick, Ping};
+use rotmg_packets::structured::packets::{AnyPacket, PacketType};
+use rotmg_packets::{BasicParameters, PacketMappings, Parameters};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn params() -> Parameters {
+    // offset the IDs so that using internal IDs by mistake would be caught
+    Parameters {
+        packets: PacketMappings::new(
+            PacketType::VALUES
+                .iter()
+                .map(|&t| (t, (t as u8).wrapping_add(100))),
+        ),
+        rc4: "0102030405060708090a".to_string(),
+        basic: BasicParameters {
+            version: "1.0".to_string(),
+            port: 2050,
+            tutorial_game_id: -1,
+            nexus_game_id: -2,
+            random_game_id: -3,
+        },
+    }
+}
+
+/// How the test client misbehaves, if at all
+#[derive(Default)]
+struct Misbehaviour {
+    /// Added to the serial of each `Pong`
+    serial_offset: u32,
+    /// Added to the time of each response after the first
+    clock_skew: u32,
+}
+
+/// A minimal client which loads into the map, responds to pings and ticks, and
+/// says "done" and disconnects after the given number of ticks
+async fn run_client(address: SocketAddr, ticks: u32, misbehaviour: Misbehaviour) {
+    let params = params();
+    let keys = hex::decode(&params.rc4).unwrap();
+    let (mut rx, mut tx) = connect(address, &keys, &ConnectionConfig::default())
+        .await
+        .unwrap();
+
+    let send = |packet: Box<dyn AnyPacket>| packet.into_raw_mapped(&params.packets).unwrap();
+    let hello = Hello {
+        build_version: params.basic.version.clone(),
+        game_id: params.basic.nexus_game_id as u32,
+        ..Hello::default()
+    };
+    tx.send(send(Box::new(hello))).await.unwrap();
+
+    let start = Instant::now();
+    let mut skew = 0;
+    let mut time = || {
+        let time = start.elapsed().as_millis() as u32 + skew;
+        skew += misbehaviour.clock_skew;
+        time
+    };
+
+    while let Some(packet) = rx.recv().await.unwrap() {
+        let (packet, _) = packet.parse(&params.packets).unwrap();
+        if packet.is::<MapInfo>() {
+            tx.send(send(Box::<Load>::default())).await.unwrap();
+        } else if let Some(ping) = packet.downcast_ref::<Ping>() {
+            let pong = Pong {
+                serial: ping.serial + misbehaviour.serial_offset,
+                time: time(),
+            };
+            tx.send(send(Box::new(pong))).await.unwrap();
+        } else if let Some(tick) = packet.downcast_ref::<NewTick>() {
+            let m = Move {
+                tick_id: tick.tick_id,
+                time: time(),
+                ..Move::default()
+            };
+            tx.send(send(Box::new(m))).await.unwrap();
+
+            if tick.tick_id == ticks {
+                let text = PlayerText {
+                    text: "done".to_string(),
+                };
+                tx.send(send(Box::new(text))).await.unwrap();
+                return;
+            }
+        }
+    }
+}
+
+async fn start_server(scenario: Scenario) -> (MockServer, SocketAddr) {
+    let server = MockServer::bind(
+        (Ipv4Addr::LOCALHOST, 0),
+        &params(),
+        scenario,
+        MockServerConfig::default(),
+    )
+    .await
+    .unwrap();
+    let address = server.local_addr().unwrap();
+    (server, address)
+}
+
+fn scenario() -> Scenario {
+    Scenario::join("Nexus")
+        .ping()
+        .ticks(2, Duration::from_millis(50))
+        .expect_with(|text: &PlayerText| text.text == "done")
+        .expect_disconnect()
+}
+
+#[tokio::test]
+async fn test_scenario() {
+    let (mut server, address) = start_server(scenario()).await;
+    let client = tokio::spawn(async move {
+        // policy file requests should be handled without starting a session
+        let mut conn = TcpStream::connect(address).await.unwrap();
+        conn.write_all(PolicyFile::REQUEST_MESSAGE).await.unwrap();
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).await.unwrap();
+        assert!(!response.is_empty());
+
+        run_client(address, 2, Misbehaviour::default()).await;
+    });
+
+    let report = server.run_session().await.unwrap();
+    client.await.unwrap();
+
+    assert_eq!(report.hello.build_version, "1.0");
+    assert_eq!(report.received_of::<Load>().count(), 1);
+    assert_eq!(report.received_of::<Pong>().next().unwrap().serial, 1);
+    let ticks = report
+        .received_of::<Move>()
+        .map(|m| m.tick_id)
+        .collect::<Vec<_>>();
+    assert_eq!(ticks, vec![1, 2]);
+}
+
+#[tokio::test]
+async fn test_pong_mismatch() {
+    let (mut server, address) = start_server(scenario()).await;
+    tokio::spawn(run_client(
+        address,
+        2,
+        Misbehaviour {
+            serial_offset: 1,
+            ..Misbehaviour::default()
+        },
+    ));
+
+    match server.run_session().await {
+        Err(MockError::PongMismatch {
+            expected: 1,
+            received: 2,
+        }) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[tokio::test]
+async fn test_clock_too_fast() {
+    let (mut server, address) = start_server(scenario()).await;
+    tokio::spawn(run_client(
+        address,
+        2,
+        Misbehaviour {
+            clock_skew: 10_000,
+            ..Misbehaviour::default()
+        },
+    ));
+
+    match server.run_session().await {
+        Err(MockError::ClockTooFast {
+            packet: PacketType::Move,
+            ..
+        }) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[tokio::test]
+async fn test_missing_response() {
+    // the client disconnects after one tick instead of two
+    let (mut server, address) = start_server(scenario()).await;
+    tokio::spawn(run_client(address, 1, Misbehaviour::default()));
+
+    match server.run_session().await {
+        Err(MockError::Disconnected(waiting_for)) if waiting_for == "Move" => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
@@ -0,0 +1,78 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rotmg_networking::rc4::Rc4;
+
+const KEY: &[u8] = b"0123456789abcdef";
+const SIZES: &[usize] = &[16, 256, 4 * 1024, 64 * 1024];
+
+/// The original byte-at-a-time implementation, used as a baseline
+struct ReferenceRc4 {
+    i: u8,
+    j: u8,
+    state: [u8; 256],
+}
+
+impl ReferenceRc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self {
+            i: 0,
+            j: 0,
+            state: [0u8; 256],
+        };
+
+        for (i, x) in rc4.state.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j
+                .wrapping_add(rc4.state[i])
+                .wrapping_add(key[i % key.len()]);
+            rc4.state.swap(i, j as usize);
+        }
+
+        rc4
+    }
+
+    fn next(&mut self) -> u8 {
+        self.i = self.i.wrapping_add(1);
+        self.j = self.j.wrapping_add(self.state[self.i as usize]);
+        self.state.swap(self.i as usize, self.j as usize);
+        self.state[(self.state[self.i as usize].wrapping_add(self.state[self.j as usize])) as usize]
+    }
+
+    fn process(&mut self, bytes: &mut [u8]) {
+        for n in bytes.iter_mut() {
+            *n ^= self.next();
+        }
+    }
+}
+
+fn bench_rc4(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rc4");
+
+    for &size in SIZES {
+        let mut data = vec![0u8; size];
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("reference", size), &size, |b, _| {
+            let mut rc4 = ReferenceRc4::new(KEY);
+            b.iter(|| rc4.process(&mut data))
+        });
+
+        group.bench_with_input(BenchmarkId::new("process", size), &size, |b, _| {
+            let mut rc4 = Rc4::new(KEY);
+            b.iter(|| rc4.process(&mut data))
+        });
+
+        group.bench_with_input(BenchmarkId::new("skip", size), &size, |b, _| {
+            let mut rc4 = Rc4::new(KEY);
+            b.iter(|| rc4.skip(size))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_rc4);
+criterion_main!(benches);
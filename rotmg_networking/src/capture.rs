@@ -0,0 +1,539 @@
+//! Recording and replaying packet captures.
+//!
+//! Captures are stored in the `.rotcap` format, which records every packet
+//! passing through a connection after decryption, along with the `Parameters`
+//! in use so that captures can be parsed later even if the game has changed.
+//!
+//! All integers are big-endian, and strings are prefixed with their length as
+//! a `u16`, matching the ROTMG protocol. A capture starts with a header:
+//!
+//! - the magic bytes `ROTCAP`
+//! - the format version, as a `u16`
+//! - the time the capture started, as milliseconds since the Unix epoch (`u64`)
+//! - the parameters: game version (string), port (`u16`), tutorial, nexus and
+//!   random realm game IDs (`i32`), RC4 keys (string), and the number of packet
+//!   mappings (`u16`) followed by each packet name (string) and game ID (`u8`)
+//!
+//! The header is followed by any number of records, each consisting of the
+//! time since the capture started in microseconds (`u64`), the direction
+//! (`u8`, 0 for inbound and 1 for outbound), and the raw packet including its
+//! header, which contains its length and game ID.
+
+use crate::codec::{Decoder, Encoder};
+use crate::stats::{Direction, PacketObserver};
+use crate::ConnectionConfig;
+use log::debug;
+use rotmg_packets::raw::{InvalidPacket, RawPacket};
+use rotmg_packets::structured::packets::PacketType;
+use rotmg_packets::{BasicParameters, PacketMappings, Parameters};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// The magic bytes at the start of every capture.
+const MAGIC: &[u8] = b"ROTCAP";
+
+/// The current version of the capture format.
+const VERSION: u16 = 1;
+
+/// An error encountered reading an invalid capture.
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidCapture {
+    /// The capture didn't start with the expected magic bytes.
+    #[error("Not a capture file")]
+    BadMagic,
+
+    /// The capture was written using an unsupported version of the format.
+    #[error("Unsupported capture version {0}")]
+    UnsupportedVersion(u16),
+
+    /// A string in the capture wasn't valid UTF-8.
+    #[error("Invalid string in capture")]
+    InvalidString,
+
+    /// A record had an invalid direction.
+    #[error("Invalid packet direction {0}")]
+    InvalidDirection(u8),
+
+    /// A record contained a packet larger than the maximum packet size.
+    #[error("Packet of size {0} in capture is too large")]
+    PacketTooLarge(u32),
+
+    /// A record contained an invalid packet.
+    #[error("Invalid packet in capture: {0}")]
+    InvalidPacket(#[from] InvalidPacket),
+}
+
+impl From<InvalidCapture> for io::Error {
+    fn from(e: InvalidCapture) -> Self {
+        io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+/// A single packet from a capture.
+#[derive(Debug, Clone)]
+pub struct CaptureRecord {
+    /// The time since the capture started.
+    pub timestamp: Duration,
+
+    /// The direction the packet was travelling in, relative to the end of the
+    /// connection the capture was recorded from.
+    pub direction: Direction,
+
+    /// The decrypted packet.
+    pub packet: Box<RawPacket>,
+}
+
+fn write_str(w: &mut impl Write, s: &str) -> io::Result<()> {
+    let len: u16 = s
+        .len()
+        .try_into()
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "string too long for capture"))?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+fn write_parameters(w: &mut impl Write, params: &Parameters) -> io::Result<()> {
+    write_str(w, &params.basic.version)?;
+    w.write_all(&params.basic.port.to_be_bytes())?;
+    w.write_all(&params.basic.tutorial_game_id.to_be_bytes())?;
+    w.write_all(&params.basic.nexus_game_id.to_be_bytes())?;
+    w.write_all(&params.basic.random_game_id.to_be_bytes())?;
+    write_str(w, &params.rc4)?;
+
+    // mappings are stored by name, since internal IDs aren't stable
+    w.write_all(&(params.packets.0.len() as u16).to_be_bytes())?;
+    for (typ, id) in params.packets.0.iter() {
+        write_str(w, typ.name())?;
+        w.write_all(&[*id])?;
+    }
+
+    Ok(())
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_i32(r: &mut impl Read) -> io::Result<i32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(i32::from_be_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_str(r: &mut impl Read) -> io::Result<String> {
+    let mut buf = vec![0u8; read_u16(r)? as usize];
+    r.read_exact(&mut buf)?;
+    Ok(String::from_utf8(buf).map_err(|_| InvalidCapture::InvalidString)?)
+}
+
+fn read_parameters(r: &mut impl Read) -> io::Result<Parameters> {
+    let basic_version = read_str(r)?;
+    let port = read_u16(r)?;
+    let tutorial_game_id = read_i32(r)?;
+    let nexus_game_id = read_i32(r)?;
+    let random_game_id = read_i32(r)?;
+    let rc4 = read_str(r)?;
+
+    let mut mappings = Vec::new();
+    for _ in 0..read_u16(r)? {
+        let name = read_str(r)?;
+        let id = read_u8(r)?;
+        match PacketType::VALUES.iter().find(|t| t.name() == name) {
+            Some(&typ) => mappings.push((typ, id)),
+            None => debug!("Ignoring mapping for unknown packet type {}", name),
+        }
+    }
+
+    Ok(Parameters {
+        packets: PacketMappings::new(mappings),
+        rc4,
+        basic: BasicParameters {
+            version: basic_version,
+            port,
+            tutorial_game_id,
+            nexus_game_id,
+            random_game_id,
+        },
+    })
+}
+
+fn write_record(
+    w: &mut impl Write,
+    timestamp: Duration,
+    direction: Direction,
+    packet: &RawPacket,
+) -> io::Result<()> {
+    let direction = match direction {
+        Direction::Inbound => 0u8,
+        Direction::Outbound => 1u8,
+    };
+
+    w.write_all(&(timestamp.as_micros() as u64).to_be_bytes())?;
+    w.write_all(&[direction])?;
+    w.write_all(packet.bytes())
+}
+
+struct WriterState<W> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+struct WriterInner<W> {
+    start: Instant,
+    state: Mutex<WriterState<W>>,
+}
+
+/// A `PacketObserver` that records packets to a capture.
+///
+/// Like `ConnectionMetrics`, the capture is shared between clones, so the same
+/// writer can be attached to both halves of a connection. Since observers
+/// can't return errors, the first error encountered while writing is stored
+/// and returned from `CaptureWriter::flush`, and no further packets are
+/// written after an error.
+pub struct CaptureWriter<W>(Arc<WriterInner<W>>);
+
+impl<W> Clone for CaptureWriter<W> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl CaptureWriter<BufWriter<File>> {
+    /// Create a new capture file at the given path.
+    pub fn create(path: impl AsRef<Path>, params: &Parameters) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), params)
+    }
+}
+
+impl<W: Write + Send + 'static> CaptureWriter<W> {
+    /// Start a new capture, writing the header to the given writer.
+    pub fn new(mut writer: W, params: &Parameters) -> io::Result<Self> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        writer.write_all(&(started.as_millis() as u64).to_be_bytes())?;
+        write_parameters(&mut writer, params)?;
+
+        Ok(Self(Arc::new(WriterInner {
+            start: Instant::now(),
+            state: Mutex::new(WriterState {
+                writer,
+                error: None,
+            }),
+        })))
+    }
+
+    /// Attach this capture to both halves of a connection.
+    pub fn attach<R, T>(&self, decoder: &mut Decoder<R>, encoder: &mut Encoder<T>)
+    where
+        R: AsyncRead + Unpin,
+        T: AsyncWrite + Unpin,
+    {
+        decoder.add_observer(Arc::new(self.clone()));
+        encoder.add_observer(Arc::new(self.clone()));
+    }
+
+    /// Write a single packet to the capture, timestamped with the current time.
+    pub fn write_packet(&self, direction: Direction, packet: &RawPacket) -> io::Result<()> {
+        let mut state = self.0.state.lock().unwrap();
+        write_record(&mut state.writer, self.0.start.elapsed(), direction, packet)
+    }
+
+    /// Flush the capture, returning the first error encountered while
+    /// recording packets, if any.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut state = self.0.state.lock().unwrap();
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        state.writer.flush()
+    }
+}
+
+impl<W: Write + Send + 'static> PacketObserver for CaptureWriter<W> {
+    fn observe(&self, direction: Direction, packet: &RawPacket) {
+        let mut state = self.0.state.lock().unwrap();
+        if state.error.is_some() {
+            return;
+        }
+
+        let timestamp = self.0.start.elapsed();
+        if let Err(e) = write_record(&mut state.writer, timestamp, direction, packet) {
+            state.error = Some(e);
+        }
+    }
+}
+
+/// A reader for captures written by `CaptureWriter`.
+///
+/// Records are read by iterating over the reader.
+pub struct CaptureReader<R> {
+    reader: R,
+    started: SystemTime,
+    params: Parameters,
+}
+
+impl CaptureReader<BufReader<File>> {
+    /// Open the capture file at the given path.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Read the header of a capture from the given reader.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 6];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(InvalidCapture::BadMagic.into());
+        }
+
+        let version = read_u16(&mut reader)?;
+        if version != VERSION {
+            return Err(InvalidCapture::UnsupportedVersion(version).into());
+        }
+
+        let started = UNIX_EPOCH + Duration::from_millis(read_u64(&mut reader)?);
+        let params = read_parameters(&mut reader)?;
+
+        Ok(Self {
+            reader,
+            started,
+            params,
+        })
+    }
+
+    /// Get the time the capture was started.
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// Get the parameters in use when the capture was recorded.
+    pub fn parameters(&self) -> &Parameters {
+        &self.params
+    }
+
+    /// Read the next record, or `None` if the end of the capture is reached.
+    pub fn read_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        // distinguish the end of the capture from a truncated record
+        let mut timestamp = [0u8; 8];
+        let n = self.reader.read(&mut timestamp)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut timestamp[n..])?;
+        let timestamp = Duration::from_micros(u64::from_be_bytes(timestamp));
+
+        let direction = match read_u8(&mut self.reader)? {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            d => return Err(InvalidCapture::InvalidDirection(d).into()),
+        };
+
+        let mut header = [0u8; 4];
+        self.reader.read_exact(&mut header)?;
+        let size = u32::from_be_bytes(header);
+        if size > ConnectionConfig::DEFAULT_MAX_PACKET_SIZE {
+            return Err(InvalidCapture::PacketTooLarge(size).into());
+        }
+
+        let mut bytes = vec![0u8; size.max(4) as usize];
+        bytes[..4].copy_from_slice(&header);
+        self.reader.read_exact(&mut bytes[4..])?;
+        let packet = RawPacket::from_box(bytes.into_boxed_slice()).map_err(InvalidCapture::from)?;
+
+        Ok(Some(CaptureRecord {
+            timestamp,
+            direction,
+            packet,
+        }))
+    }
+
+    /// Send the packets recorded in the given direction using an encoder,
+    /// waiting between packets to match the original timing.
+    ///
+    /// Timing is relative to when the replay starts, and packets in the other
+    /// direction are skipped.
+    pub async fn replay<W>(
+        &mut self,
+        encoder: &mut Encoder<W>,
+        direction: Direction,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let start = tokio::time::Instant::now();
+        while let Some(record) = self.read_record()? {
+            if record.direction != direction {
+                continue;
+            }
+
+            tokio::time::delay_until(start + record.timestamp).await;
+            encoder.send(record.packet).await?;
+        }
+        encoder.flush().await
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rc4::Rc4;
+
+    const PING: &[u8] = &[0, 0, 0, 9, 8, 0, 0, 0, 1];
+    const PONG: &[u8] = &[0, 0, 0, 13, 9, 0, 0, 0, 1, 0, 0, 0, 2];
+
+    fn params() -> Parameters {
+        Parameters {
+            packets: PacketMappings::new(vec![(PacketType::Ping, 8), (PacketType::Pong, 9)]),
+            rc4: "abcd".to_string(),
+            basic: BasicParameters {
+                version: "1.2.3".to_string(),
+                port: 2050,
+                tutorial_game_id: -1,
+                nexus_game_id: -2,
+                random_game_id: -3,
+            },
+        }
+    }
+
+    /// Write a capture with records at the given times in milliseconds
+    fn write_capture(records: &[(u64, Direction, &[u8])]) -> Vec<u8> {
+        let shared = Arc::new(Mutex::new(Vec::new()));
+        CaptureWriter::new(SharedBuffer(shared.clone()), &params()).unwrap();
+
+        let mut bytes = shared.lock().unwrap().clone();
+        for &(millis, direction, packet) in records {
+            let packet = RawPacket::from_slice(packet).unwrap();
+            write_record(&mut bytes, Duration::from_millis(millis), direction, packet).unwrap();
+        }
+        bytes
+    }
+
+    /// A writer which can be inspected after being moved into a capture
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let capture = write_capture(&[
+            (0, Direction::Outbound, PING),
+            (5, Direction::Inbound, PONG),
+        ]);
+
+        let reader = CaptureReader::new(&capture[..]).unwrap();
+        assert_eq!(reader.parameters(), &params());
+        let records = reader.collect::<io::Result<Vec<_>>>().unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].timestamp, Duration::from_millis(0));
+        assert_eq!(records[0].direction, Direction::Outbound);
+        assert_eq!(records[0].packet.bytes(), PING);
+        assert_eq!(records[1].timestamp, Duration::from_millis(5));
+        assert_eq!(records[1].direction, Direction::Inbound);
+        assert_eq!(records[1].packet.bytes(), PONG);
+    }
+
+    #[test]
+    fn test_invalid_capture() {
+        let mut capture = write_capture(&[(0, Direction::Outbound, PING)]);
+
+        // truncated records are errors, rather than the end of the capture
+        let truncated = &capture[..capture.len() - 1];
+        let mut reader = CaptureReader::new(truncated).unwrap();
+        assert!(reader.read_record().is_err());
+
+        capture[0] = b'X';
+        let err = CaptureReader::new(&capture[..]).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_observer() {
+        let shared = Arc::new(Mutex::new(Vec::new()));
+        let writer = CaptureWriter::new(SharedBuffer(shared.clone()), &params()).unwrap();
+
+        let mut encoder = Encoder::new(vec![], Rc4::new(b"key"));
+        encoder.add_observer(Arc::new(writer.clone()));
+        encoder
+            .send(RawPacket::from_slice(PING).unwrap().to_owned())
+            .await
+            .unwrap();
+        writer.flush().unwrap();
+
+        let capture = shared.lock().unwrap().clone();
+        let records = CaptureReader::new(&capture[..])
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].direction, Direction::Outbound);
+        assert_eq!(records[0].packet.bytes(), PING);
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let capture = write_capture(&[
+            (0, Direction::Outbound, PING),
+            (10, Direction::Inbound, PONG),
+            (50, Direction::Outbound, PING),
+        ]);
+
+        let mut reader = CaptureReader::new(&capture[..]).unwrap();
+        let mut encoder = Encoder::new(vec![], Rc4::new(b"key"));
+        let start = Instant::now();
+        reader
+            .replay(&mut encoder, Direction::Outbound)
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let sent = encoder.into_inner().ok().unwrap();
+        let mut decoder = Decoder::new(&sent[..], Rc4::new(b"key"));
+        for _ in 0..2 {
+            let packet = decoder.recv().await.unwrap().unwrap();
+            assert_eq!(packet.bytes(), PING);
+        }
+        assert!(decoder.recv().await.unwrap().is_none());
+    }
+}
@@ -1,39 +1,378 @@
+use crate::config::{ConnectionConfig, WriteBufferConfig};
 use crate::rc4::Rc4;
+use crate::stats::{Direction, PacketObserver};
 use futures::io::ErrorKind;
+use log::debug;
 use rotmg_packets::raw::RawPacket;
 use std::io;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 use tokio::prelude::*;
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+use tokio::time::{delay_for, timeout, Instant};
 
 /// An encoder for writing ROTMG packets.
+///
+/// By default, each packet is written to the underlying stream as soon as it's
+/// sent. When write buffering is enabled via `ConnectionConfig::write_buffer`,
+/// packets are instead encrypted and held in an internal buffer until it's
+/// flushed, reducing the number of writes when many packets are sent at once.
 pub struct Encoder<T> {
-    stream: T,
+    output: Output<T>,
     cipher: Rc4,
+    write_buffer: Option<WriteBufferConfig>,
+    observers: Vec<Arc<dyn PacketObserver>>,
+}
+
+/// Where an encoder writes encrypted packets.
+enum Output<T> {
+    /// The encoder writes to the stream itself, holding packets in the buffer
+    /// until it's full or flushed if buffering is enabled.
+    Direct { stream: T, buffer: Vec<u8> },
+
+    /// The stream is shared with the tasks which flush the buffer once packets
+    /// have waited in it for the maximum delay.
+    Delayed(Delayed<T>),
+}
+
+/// A stream and its write buffer, shared with the tasks flushing it on time.
+struct DelayedWriter<T> {
+    stream: T,
+    buffer: Vec<u8>,
+
+    /// The number of times the buffer was flushed, which lets a scheduled flush
+    /// tell whether the packets it was scheduled for were already written.
+    flushes: u64,
+
+    /// An error from a scheduled flush, returned by the next write or flush.
+    error: Option<io::Error>,
+}
+
+/// A flush of a shared writer, spawned as a background task.
+///
+/// The flush is scheduled after the given delay if the writer hasn't been
+/// flushed since then, or immediately if no delay is given.
+type SpawnFlush<T> = fn(Arc<Mutex<DelayedWriter<T>>>, Option<(u64, Duration)>);
+
+/// The encoder's handle to a shared writer, which flushes any buffered packets
+/// in the background when it's dropped.
+struct Delayed<T> {
+    /// The shared writer, which is only taken when unwrapping the stream.
+    writer: Option<Arc<Mutex<DelayedWriter<T>>>>,
+
+    /// Spawns flushes of the writer, which requires the stream to be `Send`,
+    /// so the bound is only needed when creating a delayed encoder.
+    spawn_flush: SpawnFlush<T>,
+}
+
+impl<T> Delayed<T> {
+    fn writer(&self) -> &Arc<Mutex<DelayedWriter<T>>> {
+        self.writer
+            .as_ref()
+            .expect("the stream was already unwrapped")
+    }
+
+    /// Take the stream, unless a scheduled flush is currently writing to it.
+    fn take_stream(&mut self) -> Option<T> {
+        let writer = self.writer.take()?;
+        match Arc::try_unwrap(writer) {
+            Ok(writer) => Some(writer.into_inner().stream),
+            Err(writer) => {
+                self.writer = Some(writer);
+                None
+            }
+        }
+    }
+}
+
+impl<T> Drop for Delayed<T> {
+    fn drop(&mut self) {
+        let writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => return,
+        };
+
+        let pending = match writer.try_lock() {
+            Ok(writer) => !writer.buffer.is_empty(),
+            // a scheduled flush is running, but more packets may have been
+            // buffered since it started
+            Err(_) => true,
+        };
+        if pending {
+            (self.spawn_flush)(writer, None);
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> DelayedWriter<T> {
+    async fn flush(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.flushes += 1;
+        write_buffer(&mut self.stream, &mut self.buffer).await
+    }
+}
+
+/// Write all buffered bytes to a stream and flush it.
+async fn write_buffer<T: AsyncWrite + Unpin>(
+    stream: &mut T,
+    buffer: &mut Vec<u8>,
+) -> io::Result<()> {
+    if !buffer.is_empty() {
+        stream.write_all(buffer).await?;
+        buffer.clear();
+    }
+    stream.flush().await
+}
+
+/// Spawn a task flushing a shared writer.
+fn spawn_flush<T: AsyncWrite + Unpin + Send + 'static>(
+    writer: Arc<Mutex<DelayedWriter<T>>>,
+    scheduled: Option<(u64, Duration)>,
+) {
+    match scheduled {
+        Some((flushes, delay)) => {
+            tokio::spawn(flush_after(Arc::downgrade(&writer), flushes, delay));
+        }
+        // the encoder was dropped, so it can only be flushed if it was dropped
+        // within a runtime
+        None => {
+            if let Ok(handle) = Handle::try_current() {
+                handle.spawn(async move {
+                    let mut writer = writer.lock().await;
+                    if let Err(e) = writer.flush().await {
+                        debug!("Error flushing a dropped encoder: {}", e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Flush a shared writer once the given delay has passed, unless the buffer
+/// was already flushed since the flush was scheduled.
+async fn flush_after<T: AsyncWrite + Unpin>(
+    writer: Weak<Mutex<DelayedWriter<T>>>,
+    flushes: u64,
+    delay: Duration,
+) {
+    delay_for(delay).await;
+
+    // the encoder was dropped and flushed the buffer itself, or its stream
+    // was unwrapped
+    let writer = match writer.upgrade() {
+        Some(writer) => writer,
+        None => return,
+    };
+
+    let mut writer = writer.lock().await;
+    if writer.flushes == flushes && writer.error.is_none() {
+        if let Err(e) = writer.flush().await {
+            writer.error = Some(e);
+        }
+    }
 }
 
 impl<T: AsyncWrite + Unpin> Encoder<T> {
+    /// Create a new unbuffered encoder.
     pub fn new(stream: T, cipher: Rc4) -> Self {
-        Self { stream, cipher }
+        Self {
+            output: Output::Direct {
+                stream,
+                buffer: Vec::new(),
+            },
+            cipher,
+            write_buffer: None,
+            observers: Vec::new(),
+        }
+    }
+
+    /// Add an observer to be notified of every packet sent by this encoder.
+    pub fn add_observer(&mut self, observer: Arc<dyn PacketObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Encrypt the given packet in place.
+    ///
+    /// Packets are always encrypted in the order they're passed to the encoder,
+    /// so the keystream stays in sync with the receiver regardless of when the
+    /// buffer is actually written.
+    fn encrypt(&mut self, packet: &mut RawPacket) {
+        for observer in &self.observers {
+            observer.observe(Direction::Outbound, packet);
+        }
+        self.cipher.process(packet.payload_mut());
+    }
+
+    /// Write encrypted packets to the stream, or buffer them and flush the
+    /// buffer if a threshold was reached.
+    async fn write_or_buffer(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let WriteBufferConfig {
+            capacity,
+            max_delay,
+        } = match (&mut self.output, self.write_buffer) {
+            (Output::Direct { stream, .. }, None) => return stream.write_all(bytes).await,
+            (_, Some(config)) => config,
+            (Output::Delayed(_), None) => unreachable!("delayed encoders are always buffered"),
+        };
+
+        match &mut self.output {
+            Output::Direct { stream, buffer } => {
+                buffer.extend_from_slice(bytes);
+                if buffer.len() >= capacity {
+                    write_buffer(stream, buffer).await?;
+                }
+            }
+            Output::Delayed(delayed) => {
+                let shared = delayed.writer();
+                let mut writer = shared.lock().await;
+                if let Some(e) = writer.error.take() {
+                    return Err(e);
+                }
+
+                let was_empty = writer.buffer.is_empty();
+                writer.buffer.extend_from_slice(bytes);
+                if writer.buffer.len() >= capacity {
+                    writer.flush().await?;
+                } else if let (true, Some(delay)) = (was_empty, max_delay) {
+                    (delayed.spawn_flush)(shared.clone(), Some((writer.flushes, delay)));
+                }
+            }
+        }
+
+        Ok(())
     }
 
     /// Write the given packet to this encoder.
     ///
+    /// If write buffering is enabled, the packet may not be written to the
+    /// underlying stream until the buffer is full, its maximum delay has
+    /// passed, or the encoder is flushed.
+    ///
     /// An error leaves the encoder in an undefined state, and future attempts
     /// to write with the same encoder are likely to fail.
     pub async fn send(&mut self, mut packet: impl AsMut<RawPacket>) -> io::Result<()> {
         let packet = packet.as_mut();
-        self.cipher.process(packet.payload_mut());
-        self.stream.write_all(packet.bytes()).await?;
-        Ok(())
+        self.encrypt(packet);
+        self.write_or_buffer(packet.bytes()).await
+    }
+
+    /// Write several packets to this encoder at once.
+    ///
+    /// The packets are combined and written to the underlying stream using as
+    /// few writes as possible, even if write buffering is disabled.
+    ///
+    /// An error leaves the encoder in an undefined state, and future attempts
+    /// to write with the same encoder are likely to fail.
+    pub async fn send_batch<P: AsMut<RawPacket>>(
+        &mut self,
+        packets: impl IntoIterator<Item = P>,
+    ) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        for mut packet in packets {
+            let packet = packet.as_mut();
+            self.encrypt(packet);
+            bytes.extend_from_slice(packet.bytes());
+        }
+        self.write_or_buffer(&bytes).await
+    }
+
+    /// Write all buffered packets to the underlying stream and flush it.
+    ///
+    /// An error leaves the encoder in an undefined state, and future attempts
+    /// to write with the same encoder are likely to fail.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            Output::Direct { stream, buffer } => write_buffer(stream, buffer).await,
+            Output::Delayed(delayed) => delayed.writer().lock().await.flush().await,
+        }
+    }
+
+    /// Write all buffered packets, then shut down the underlying stream, which
+    /// tells the other end that no more packets will be sent.
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            Output::Direct { stream, buffer } => {
+                write_buffer(stream, buffer).await?;
+                stream.shutdown().await
+            }
+            Output::Delayed(delayed) => {
+                let mut writer = delayed.writer().lock().await;
+                writer.flush().await?;
+                writer.stream.shutdown().await
+            }
+        }
     }
 
     /// Get a reference to the underlying data stream.
-    pub fn inner(&self) -> &T {
-        &self.stream
+    ///
+    /// Returns `None` if write buffering with a maximum delay is enabled, since
+    /// the stream is then shared with the tasks flushing the buffer.
+    pub fn inner(&self) -> Option<&T> {
+        match &self.output {
+            Output::Direct { stream, .. } => Some(stream),
+            Output::Delayed(_) => None,
+        }
     }
 
     /// Unwrap the underlying stream.
-    pub fn into_inner(self) -> T {
-        self.stream
+    ///
+    /// Any buffered packets that haven't been flushed will be lost. If write
+    /// buffering with a maximum delay is enabled and a scheduled flush is
+    /// currently writing to the stream, the encoder is returned instead.
+    #[allow(clippy::result_large_err)]
+    pub fn into_inner(self) -> Result<T, Self> {
+        let mut delayed = match self.output {
+            Output::Direct { stream, .. } => return Ok(stream),
+            Output::Delayed(delayed) => delayed,
+        };
+
+        match delayed.take_stream() {
+            Some(stream) => Ok(stream),
+            None => Err(Self {
+                output: Output::Delayed(delayed),
+                cipher: self.cipher,
+                write_buffer: self.write_buffer,
+                observers: self.observers,
+            }),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin + Send + 'static> Encoder<T> {
+    /// Create a new encoder, applying the write buffering settings from the
+    /// given config.
+    ///
+    /// When a maximum delay is set for the write buffer, the buffer is flushed
+    /// by background tasks, which is why the stream must be `Send` and
+    /// `'static`. This must be called from within a tokio runtime in that case.
+    pub fn with_config(stream: T, cipher: Rc4, config: &ConnectionConfig) -> Self {
+        let output = match config.write_buffer {
+            Some(WriteBufferConfig {
+                max_delay: Some(_), ..
+            }) => Output::Delayed(Delayed {
+                writer: Some(Arc::new(Mutex::new(DelayedWriter {
+                    stream,
+                    buffer: Vec::new(),
+                    flushes: 0,
+                    error: None,
+                }))),
+                spawn_flush: spawn_flush::<T>,
+            }),
+            _ => Output::Direct {
+                stream,
+                buffer: Vec::new(),
+            },
+        };
+
+        Self {
+            output,
+            cipher,
+            write_buffer: config.write_buffer,
+            observers: Vec::new(),
+        }
     }
 }
 
@@ -42,57 +381,85 @@ pub struct Decoder<T> {
     stream: T,
     cipher: Rc4,
     buffer: Vec<u8>,
+    max_packet_size: u32,
+    idle_timeout: Option<Duration>,
+    rate_limit: Option<RateLimit>,
+    observers: Vec<Arc<dyn PacketObserver>>,
+}
+
+/// Tracks the number of packets received within the current one second window.
+struct RateLimit {
+    limit: u32,
+    window_start: Instant,
+    received: u32,
 }
 
 /// An error returned when a `Decoder` attempts to decode an excessively large
 /// packet.
 ///
-/// When a packet larger than the limit (`Decoder::MAX_PACKET_SIZE`) is
-/// encountered, this error will be returned instead.
-#[error("Packet size limit of {limit} bytes was exceeded: {size} bytes specified")]
+/// When a packet larger than the limit (`ConnectionConfig::max_packet_size`)
+/// is encountered, this error will be returned instead.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Packet size limit of {limit} bytes was exceeded: {size} bytes specified")]
 pub struct PacketSizeLimitExceeded {
     pub limit: u32,
     pub size: u32,
 }
 
-impl<T: AsyncRead + Unpin> Decoder<T> {
-    /// Maximum allowed packet size, in bytes.
-    ///
-    /// Although the layout of packets allows sizes of up to 2^32 bytes, a
-    /// reasonable upper bound is set to prevent malicious connections
-    /// attempting to exhaust system memory.
-    pub const MAX_PACKET_SIZE: u32 = 10 * 1024 * 1024;
+/// An error returned when a `Decoder` receives packets faster than allowed.
+///
+/// When more packets than the limit (`ConnectionConfig::max_packets_per_second`)
+/// are received within a single second, this error will be returned instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Packet rate limit of {limit} packets per second was exceeded")]
+pub struct PacketRateLimitExceeded {
+    pub limit: u32,
+}
 
+impl<T: AsyncRead + Unpin> Decoder<T> {
+    /// Create a new decoder using the default `ConnectionConfig`.
     pub fn new(stream: T, cipher: Rc4) -> Self {
+        Self::with_config(stream, cipher, &ConnectionConfig::default())
+    }
+
+    /// Create a new decoder, applying the limits from the given config.
+    pub fn with_config(stream: T, cipher: Rc4, config: &ConnectionConfig) -> Self {
         Self {
             stream,
             cipher,
             buffer: Vec::new(),
+            max_packet_size: config.max_packet_size,
+            idle_timeout: config.idle_timeout,
+            rate_limit: config.max_packets_per_second.map(|limit| RateLimit {
+                limit,
+                window_start: Instant::now(),
+                received: 0,
+            }),
+            observers: Vec::new(),
         }
     }
 
-    /// Read a packet from this decoder.
-    ///
-    /// A value of `None` indicates that the stream has closed and no further
-    /// packets can be read.
-    ///
-    /// An error leaves the decoder in an undefined state, and future attempts
-    /// to read with the same decoder are likely to fail.
-    pub async fn recv(&mut self) -> io::Result<Option<&mut RawPacket>> {
+    /// Add an observer to be notified of every packet received by this decoder.
+    pub fn add_observer(&mut self, observer: Arc<dyn PacketObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Read the next encrypted packet into the buffer, returning `false` if
+    /// the stream was closed.
+    async fn read_frame(&mut self) -> io::Result<bool> {
         // receive packet length
         let mut len_buf = [0, 0, 0, 0];
         let len = match self.stream.read_exact(&mut len_buf).await {
             Ok(_) => u32::from_be_bytes(len_buf),
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
             Err(e) => return Err(e),
         };
 
-        if len > Self::MAX_PACKET_SIZE {
+        if len > self.max_packet_size {
             return Err(io::Error::new(
                 ErrorKind::Other,
                 PacketSizeLimitExceeded {
-                    limit: Self::MAX_PACKET_SIZE,
+                    limit: self.max_packet_size,
                     size: len,
                 },
             ));
@@ -102,15 +469,55 @@ impl<T: AsyncRead + Unpin> Decoder<T> {
         self.buffer.resize(len as usize, 0);
         self.buffer[..4].copy_from_slice(&len_buf);
         match self.stream.read_exact(&mut self.buffer[4..]).await {
-            Ok(_) => {}
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
-            Err(e) => return Err(e),
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read a packet from this decoder.
+    ///
+    /// A value of `None` indicates that the stream has closed and no further
+    /// packets can be read. If an idle timeout is configured and no packet is
+    /// received in time, an error of kind `TimedOut` is returned.
+    ///
+    /// An error leaves the decoder in an undefined state, and future attempts
+    /// to read with the same decoder are likely to fail.
+    pub async fn recv(&mut self) -> io::Result<Option<&mut RawPacket>> {
+        let received = match self.idle_timeout {
+            Some(idle_timeout) => timeout(idle_timeout, self.read_frame()).await??,
+            None => self.read_frame().await?,
+        };
+
+        if !received {
+            return Ok(None);
+        }
+
+        if let Some(rate) = &mut self.rate_limit {
+            let now = Instant::now();
+            if now.duration_since(rate.window_start) >= Duration::from_secs(1) {
+                rate.window_start = now;
+                rate.received = 0;
+            }
+
+            rate.received += 1;
+            if rate.received > rate.limit {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    PacketRateLimitExceeded { limit: rate.limit },
+                ));
+            }
         }
 
         // decrypt payload and wrap packet
         self.cipher.process(&mut self.buffer[5..]);
         match RawPacket::from_mut(&mut self.buffer) {
-            Ok(p) => Ok(Some(p)),
+            Ok(p) => {
+                for observer in &self.observers {
+                    observer.observe(Direction::Inbound, p);
+                }
+                Ok(Some(p))
+            }
             Err(e) => Err(io::Error::new(ErrorKind::InvalidData, e)),
         }
     }
@@ -130,3 +537,170 @@ impl<T: AsyncRead + Unpin> Decoder<T> {
         self.stream
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    const DATA: &[u8] = &[0, 0, 0, 7, 5, 6, 7];
+    const KEY: &[u8] = b"key";
+
+    fn packet() -> Box<RawPacket> {
+        RawPacket::from_slice(DATA).unwrap().to_owned()
+    }
+
+    async fn decode_all(bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut decoder = Decoder::new(bytes, Rc4::new(KEY));
+        let mut packets = vec![];
+        while let Some(p) = decoder.recv().await.unwrap() {
+            packets.push(p.bytes().to_vec());
+        }
+        packets
+    }
+
+    /// A stream which can still be read after an encoder takes ownership of it
+    #[derive(Clone, Default)]
+    struct SharedStream(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl SharedStream {
+        fn written(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl AsyncWrite for SharedStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_buffered_writes() {
+        let config = ConnectionConfig {
+            write_buffer: Some(WriteBufferConfig {
+                capacity: 1024,
+                max_delay: None,
+            }),
+            ..ConnectionConfig::default()
+        };
+        let mut encoder = Encoder::with_config(vec![], Rc4::new(KEY), &config);
+
+        encoder.send(packet()).await.unwrap();
+        encoder.send_batch(vec![packet(), packet()]).await.unwrap();
+        assert!(encoder.inner().unwrap().is_empty());
+
+        encoder.flush().await.unwrap();
+        assert_eq!(
+            decode_all(encoder.inner().unwrap()).await,
+            vec![DATA.to_vec(); 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffer_capacity_flush() {
+        let config = ConnectionConfig {
+            write_buffer: Some(WriteBufferConfig {
+                capacity: DATA.len() * 2,
+                max_delay: None,
+            }),
+            ..ConnectionConfig::default()
+        };
+        let mut encoder = Encoder::with_config(vec![], Rc4::new(KEY), &config);
+
+        encoder.send(packet()).await.unwrap();
+        assert!(encoder.inner().unwrap().is_empty());
+        encoder.send(packet()).await.unwrap();
+        assert_eq!(encoder.inner().unwrap().len(), DATA.len() * 2);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_delay_flush() {
+        let config = ConnectionConfig {
+            write_buffer: Some(WriteBufferConfig {
+                capacity: 1024,
+                max_delay: Some(Duration::from_millis(20)),
+            }),
+            ..ConnectionConfig::default()
+        };
+        let stream = SharedStream::default();
+        let mut encoder = Encoder::with_config(stream.clone(), Rc4::new(KEY), &config);
+
+        encoder.send(packet()).await.unwrap();
+        encoder.send(packet()).await.unwrap();
+        assert!(stream.written().is_empty());
+
+        // the buffer is flushed without sending any more packets
+        delay_for(Duration::from_millis(100)).await;
+        assert_eq!(decode_all(&stream.written()).await, vec![DATA.to_vec(); 2]);
+
+        encoder.send(packet()).await.unwrap();
+        encoder.flush().await.unwrap();
+        assert_eq!(stream.written().len(), DATA.len() * 3);
+
+        // shutting down writes anything still buffered first
+        encoder.send(packet()).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        assert_eq!(stream.written().len(), DATA.len() * 4);
+    }
+
+    #[tokio::test]
+    async fn test_delayed_buffer_flushed_on_drop() {
+        let config = ConnectionConfig {
+            write_buffer: Some(WriteBufferConfig {
+                capacity: 1024,
+                max_delay: Some(Duration::from_secs(60)),
+            }),
+            ..ConnectionConfig::default()
+        };
+        let stream = SharedStream::default();
+        let mut encoder = Encoder::with_config(stream.clone(), Rc4::new(KEY), &config);
+        assert!(encoder.inner().is_none());
+
+        encoder.send(packet()).await.unwrap();
+        drop(encoder);
+        delay_for(Duration::from_millis(50)).await;
+        assert_eq!(decode_all(&stream.written()).await, vec![DATA.to_vec()]);
+
+        // the stream can still be unwrapped when no flush is running
+        let encoder = Encoder::with_config(stream.clone(), Rc4::new(KEY), &config);
+        assert!(encoder.into_inner().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_buffered_matches_unbuffered() {
+        let mut unbuffered = Encoder::new(vec![], Rc4::new(KEY));
+        for _ in 0..4 {
+            unbuffered.send(packet()).await.unwrap();
+        }
+
+        let config = ConnectionConfig {
+            write_buffer: Some(WriteBufferConfig::default()),
+            ..ConnectionConfig::default()
+        };
+        let stream = SharedStream::default();
+        let mut buffered = Encoder::with_config(stream.clone(), Rc4::new(KEY), &config);
+        buffered.send(packet()).await.unwrap();
+        buffered
+            .send_batch(vec![packet(), packet(), packet()])
+            .await
+            .unwrap();
+        buffered.flush().await.unwrap();
+
+        assert_eq!(unbuffered.inner(), Some(&stream.written()));
+    }
+}
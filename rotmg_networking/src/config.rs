@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+/// Limits and socket options applied to a ROTMG network connection.
+///
+/// The default configuration matches the behaviour of previous versions - a
+/// 10 MiB packet size limit and no timeouts or rate limits - so it's usually
+/// worth tightening these values for servers exposed to untrusted clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionConfig {
+    /// Maximum allowed packet size, in bytes.
+    ///
+    /// Although the layout of packets allows sizes of up to 2^32 bytes, a
+    /// reasonable upper bound should be set to prevent malicious connections
+    /// attempting to exhaust system memory.
+    pub max_packet_size: u32,
+
+    /// Maximum time to wait for a packet to be received before the connection
+    /// is considered dead, or `None` to wait forever.
+    pub idle_timeout: Option<Duration>,
+
+    /// Maximum time to wait while determining whether a new connection is a
+    /// policy file request, or `None` to wait forever.
+    pub policy_timeout: Option<Duration>,
+
+    /// Maximum time to wait for a new session's `Hello` packet when accepting
+    /// connections with `accept_session`, or `None` to wait forever.
+    ///
+    /// This includes the time taken to handle policy file requests.
+    pub handshake_timeout: Option<Duration>,
+
+    /// Maximum number of packets that may be received in a single second, or
+    /// `None` for no limit.
+    pub max_packets_per_second: Option<u32>,
+
+    /// Whether to set `TCP_NODELAY` on the socket, disabling Nagle's
+    /// algorithm so that each packet is sent as soon as possible.
+    pub nodelay: bool,
+
+    /// The size of the socket send buffer (`SO_SNDBUF`), or `None` to use the
+    /// system default.
+    pub send_buffer_size: Option<usize>,
+
+    /// Buffering to apply to outgoing packets, or `None` to write each packet
+    /// to the socket as soon as it's sent.
+    pub write_buffer: Option<WriteBufferConfig>,
+}
+
+impl ConnectionConfig {
+    /// The default maximum packet size, in bytes.
+    pub const DEFAULT_MAX_PACKET_SIZE: u32 = 10 * 1024 * 1024;
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_packet_size: Self::DEFAULT_MAX_PACKET_SIZE,
+            idle_timeout: None,
+            policy_timeout: None,
+            handshake_timeout: None,
+            max_packets_per_second: None,
+            nodelay: false,
+            send_buffer_size: None,
+            write_buffer: None,
+        }
+    }
+}
+
+/// Settings for buffering outgoing packets in an `Encoder`.
+///
+/// Buffered packets are written once either threshold is reached, or when the
+/// encoder is explicitly flushed. When a maximum delay is set, the buffer is
+/// flushed by a background task, so packets are written on time even if no
+/// more are sent.
+///
+/// **Without a maximum delay, packets still in the buffer when the encoder is
+/// dropped are lost**, so the encoder must be flushed before it's dropped.
+/// With a maximum delay, dropping the encoder flushes the buffer in the
+/// background instead, although any error from that flush is only logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteBufferConfig {
+    /// The number of buffered bytes at which the buffer is flushed.
+    pub capacity: usize,
+
+    /// The maximum amount of time packets may be held in the buffer before it's
+    /// flushed, or `None` to only flush when the buffer is full.
+    pub max_delay: Option<Duration>,
+}
+
+impl Default for WriteBufferConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 16 * 1024,
+            max_delay: Some(Duration::from_millis(5)),
+        }
+    }
+}
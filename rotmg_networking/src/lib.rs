@@ -1,13 +1,50 @@
+pub mod capture;
 pub mod codec;
-mod policy;
-mod rc4;
+mod config;
+pub mod policy;
+pub mod rc4;
+pub mod rewind;
+pub mod stats;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "websocket")]
+pub mod ws;
 
 use crate::codec::{Decoder, Encoder};
 use crate::rc4::Rc4;
+pub use config::{ConnectionConfig, WriteBufferConfig};
+use log::debug;
 pub use policy::PolicyFile;
-use std::io;
+use rewind::Rewind;
+use rotmg_packets::adapters::{FromPacketBytes, PacketFormatError, PacketReader};
+use rotmg_packets::structured::packets::{client::Hello, PacketType};
+use rotmg_packets::PacketMappings;
+use std::io::{self, ErrorKind};
+#[cfg(feature = "tls")]
+pub use tls::{accept_tls, connect_tls};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time::timeout;
+#[cfg(feature = "websocket")]
+pub use ws::{accept_ws, connect_ws};
+
+/// An error returned when a new session doesn't start with a valid `Hello`
+/// packet.
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    /// The first packet received wasn't a `Hello` packet.
+    #[error("Expected Hello packet (ID {expected:?}) but received packet ID {received}")]
+    UnexpectedPacket {
+        /// The ID of `Hello` packets in the mappings used, if any.
+        expected: Option<u8>,
+        /// The ID of the packet that was received instead.
+        received: u8,
+    },
+
+    /// The `Hello` packet couldn't be parsed.
+    #[error("Error parsing Hello packet: {0}")]
+    InvalidHello(#[from] Box<PacketFormatError>),
+}
 
 /// Create cipher instances from the ROTMG RC4 keys
 fn create_ciphers(keys: &[u8]) -> (Rc4, Rc4) {
@@ -15,26 +52,67 @@ fn create_ciphers(keys: &[u8]) -> (Rc4, Rc4) {
     (Rc4::new(key0), Rc4::new(key1))
 }
 
+/// Apply the socket options from the given config to a TCP stream
+fn configure_socket(conn: &TcpStream, config: &ConnectionConfig) -> io::Result<()> {
+    conn.set_nodelay(config.nodelay)?;
+    if let Some(size) = config.send_buffer_size {
+        conn.set_send_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+/// Split a stream into codec halves using the given ciphers
+#[cfg(any(feature = "tls", feature = "websocket"))]
+fn split_stream<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static>(
+    stream: S,
+    rx_rc4: Rc4,
+    tx_rc4: Rc4,
+    config: &ConnectionConfig,
+) -> (
+    Decoder<tokio::io::ReadHalf<S>>,
+    Encoder<tokio::io::WriteHalf<S>>,
+) {
+    let (rx, tx) = tokio::io::split(stream);
+    (
+        Decoder::with_config(rx, rx_rc4, config),
+        Encoder::with_config(tx, tx_rc4, config),
+    )
+}
+
 /// Open a new ROTMG network connection using the given RC4 keys, as a client.
 ///
 /// The raw binary keys should be used, decoded from hexadecimal.
 pub async fn connect(
     address: impl ToSocketAddrs,
     keys: &[u8],
+    config: &ConnectionConfig,
 ) -> io::Result<(Decoder<OwnedReadHalf>, Encoder<OwnedWriteHalf>)> {
     let (tx_rc4, rx_rc4) = create_ciphers(keys);
-    let (rx, tx) = TcpStream::connect(address).await?.into_split();
-    Ok((Decoder::new(rx, rx_rc4), Encoder::new(tx, tx_rc4)))
+    let conn = TcpStream::connect(address).await?;
+    configure_socket(&conn, config)?;
+    let (rx, tx) = conn.into_split();
+    Ok((
+        Decoder::with_config(rx, rx_rc4, config),
+        Encoder::with_config(tx, tx_rc4, config),
+    ))
 }
 
 /// Accept an incoming ROTMG network connection using the given RC4 keys, as a
 /// server.
 ///
 /// The raw binary keys should be used, decoded from hexadecimal.
-pub fn accept(conn: TcpStream, keys: &[u8]) -> (Decoder<OwnedReadHalf>, Encoder<OwnedWriteHalf>) {
+pub fn accept(
+    conn: TcpStream,
+    keys: &[u8],
+    config: &ConnectionConfig,
+) -> io::Result<(Decoder<OwnedReadHalf>, Encoder<OwnedWriteHalf>)> {
     let (rx_rc4, tx_rc4) = create_ciphers(keys);
+    configure_socket(&conn, config)?;
     let (rx, tx) = conn.into_split();
-    (Decoder::new(rx, rx_rc4), Encoder::new(tx, tx_rc4))
+    Ok((
+        Decoder::with_config(rx, rx_rc4, config),
+        Encoder::with_config(tx, tx_rc4, config),
+    ))
 }
 
 /// Accept an incoming ROTMG network connection as a server, handling policy
@@ -43,16 +121,99 @@ pub fn accept(conn: TcpStream, keys: &[u8]) -> (Decoder<OwnedReadHalf>, Encoder<
 /// The raw binary keys should be used, decoded from hexadecimal.
 ///
 /// This method will automatically detect and handle policy file requests using
-/// the given policy file. A timeout should be used to avoid malicious
-/// connections that intentionally never close. When a policy file request is
+/// the given policy file. The policy timeout from the given config is used to
+/// avoid malicious connections that intentionally never close, so one should
+/// be set when accepting untrusted connections. When a policy file request is
 /// handled, `None` will be returned.
+///
+/// Any bytes read while checking for a policy file request are replayed to the
+/// returned decoder, which is why it reads from a `Rewind` stream.
 pub async fn accept_with_policy(
     conn: TcpStream,
     keys: &[u8],
     policy: &PolicyFile,
-) -> io::Result<Option<(Decoder<OwnedReadHalf>, Encoder<OwnedWriteHalf>)>> {
-    match policy.handle_connection(conn).await? {
-        None => Ok(None),
-        Some(conn) => Ok(Some(accept(conn, keys))),
+    config: &ConnectionConfig,
+) -> io::Result<Option<(Decoder<Rewind<OwnedReadHalf>>, Encoder<OwnedWriteHalf>)>> {
+    let handled = match config.policy_timeout {
+        Some(policy_timeout) => timeout(policy_timeout, policy.handle_connection(conn)).await??,
+        None => policy.handle_connection(conn).await?,
+    };
+
+    let (prefix, conn) = match handled {
+        None => return Ok(None),
+        Some(conn) => conn.into_parts(),
+    };
+
+    let (rx_rc4, tx_rc4) = create_ciphers(keys);
+    configure_socket(&conn, config)?;
+    let (rx, tx) = conn.into_split();
+    Ok(Some((
+        Decoder::with_config(Rewind::new(rx, prefix), rx_rc4, config),
+        Encoder::with_config(tx, tx_rc4, config),
+    )))
+}
+
+/// Accept an incoming ROTMG network connection as a server, handling policy
+/// file requests and reading the `Hello` packet that starts the session.
+///
+/// The raw binary keys should be used, decoded from hexadecimal. The given
+/// mappings are used to identify and parse the `Hello` packet.
+///
+/// Policy file requests are handled as in `accept_with_policy`, returning
+/// `None`. Otherwise, the first packet is decrypted and parsed, and returned
+/// along with the codec halves for the rest of the session. If the first packet
+/// isn't a valid `Hello` packet, an error of kind `InvalidData` wrapping a
+/// `HandshakeError` is returned. The handshake timeout from the given config
+/// applies to the whole process.
+pub async fn accept_session(
+    conn: TcpStream,
+    keys: &[u8],
+    policy: &PolicyFile,
+    mappings: &PacketMappings,
+    config: &ConnectionConfig,
+) -> io::Result<
+    Option<(
+        Hello,
+        Decoder<Rewind<OwnedReadHalf>>,
+        Encoder<OwnedWriteHalf>,
+    )>,
+> {
+    let handshake = async {
+        let (mut rx, tx) = match accept_with_policy(conn, keys, policy, config).await? {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        let packet = rx.recv().await?.ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before Hello packet was received",
+            )
+        })?;
+
+        let expected = mappings.to_game(PacketType::Hello);
+        if expected != Some(packet.id()) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                HandshakeError::UnexpectedPacket {
+                    expected,
+                    received: packet.id(),
+                },
+            ));
+        }
+
+        let mut reader = PacketReader::new(packet);
+        let hello = Hello::from_packet(&mut reader)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, HandshakeError::from(e)))?;
+        if !reader.is_empty() {
+            debug!("Ignoring {} extra bytes in Hello packet", reader.len());
+        }
+
+        Ok(Some((hello, rx, tx)))
+    };
+
+    match config.handshake_timeout {
+        Some(handshake_timeout) => timeout(handshake_timeout, handshake).await?,
+        None => handshake.await,
     }
 }
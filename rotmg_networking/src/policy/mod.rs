@@ -0,0 +1,214 @@
+mod rules;
+mod server;
+
+pub use rules::{AllowRule, InvalidPolicyFile, PolicyFileBuilder, PolicyRules, Ports, SiteControl};
+pub use server::{PolicyServer, PolicyServerConfig};
+
+use crate::rewind::Rewind;
+use log::debug;
+use std::borrow::Cow;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// A utility for responding to policy file requests from flash.
+///
+/// Policy files specify what ports are accessible from a flash player. Before
+/// connecting, a flash player may send a policy file request, and then parse
+/// the response to determine whether the connection is legal. This type
+/// provides a simple way to handle policy file requests.
+#[derive(Debug, Clone)]
+pub struct PolicyFile(Cow<'static, str>);
+
+impl PolicyFile {
+    /// The binary message denoting a policy file request.
+    pub const REQUEST_MESSAGE: &'static [u8] = b"<policy-file-request/>\0";
+
+    /// Create a new policy file with the given response.
+    pub fn new(policy_file: impl Into<Cow<'static, str>>) -> Self {
+        Self(policy_file.into())
+    }
+
+    /// A policy file that allows access to all ports from all domains.
+    ///
+    /// Using this policy file is a minor security risk, as it can allow flash
+    /// to access other services unexpectedly.
+    pub const ALLOW_ALL: Self = Self(Cow::Borrowed(include_str!("unrestricted_policy_file.xml")));
+
+    /// Create a builder for generating a policy file from typed rules.
+    ///
+    /// ```
+    /// use rotmg_networking::policy::SiteControl;
+    /// use rotmg_networking::PolicyFile;
+    ///
+    /// let policy = PolicyFile::builder()
+    ///     .site_control(SiteControl::MasterOnly)
+    ///     .allow_domain("*.example.com", 2050..=2050)
+    ///     .build();
+    /// assert!(policy.parse().unwrap().permits("www.example.com", 2050));
+    /// ```
+    pub fn builder() -> PolicyFileBuilder {
+        PolicyFileBuilder::default()
+    }
+
+    /// Get the contents of this policy file.
+    pub fn contents(&self) -> &str {
+        self.0.as_ref()
+    }
+
+    /// Parse and validate this policy file, returning the rules it specifies.
+    pub fn parse(&self) -> Result<PolicyRules, InvalidPolicyFile> {
+        PolicyRules::parse(self.contents())
+    }
+
+    /// Handle a given new connection, determining whether it's a policy file
+    /// request and handling it appropriately.
+    ///
+    /// When a policy file request is detected, the response will be sent,
+    /// connection closed, and `None` returned. Otherwise, the stream will be
+    /// returned wrapped in a `Rewind`, which replays any bytes read while
+    /// checking for a request. Only as many bytes as necessary are read, and
+    /// this works with any transport, such as TLS or in-memory streams.
+    ///
+    /// Note that this method should be used in conjunction with a timeout to
+    /// avoid the possibility of malicious connections that never close, such
+    /// as the one set by `ConnectionConfig::policy_timeout` when connections
+    /// are accepted using `accept_with_policy`.
+    pub async fn handle_connection<T>(&self, mut conn: T) -> io::Result<Option<Rewind<T>>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let request = Self::REQUEST_MESSAGE;
+        let mut buffer = [0u8; Self::REQUEST_MESSAGE.len()];
+        let mut len = 0;
+
+        while len < request.len() {
+            let n = conn.read(&mut buffer[len..]).await?;
+            len += n;
+
+            // stop as soon as the data can't be a policy file request, or the
+            // stream is closed before a full request is received
+            if n == 0 || buffer[..len] != request[..len] {
+                return Ok(Some(Rewind::new(conn, buffer[..len].to_vec())));
+            }
+        }
+
+        // definitely a policy file request
+        // send response, then close connection
+        debug!("Sending policy file");
+        conn.write_all(self.0.as_bytes()).await?;
+        conn.shutdown().await?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PolicyFile;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+    /// An in-memory stream which returns input in separate chunks, like a
+    /// socket receiving data in multiple segments
+    #[derive(Default)]
+    struct ChunkedStream {
+        input: VecDeque<Vec<u8>>,
+        output: Vec<u8>,
+        closed: bool,
+    }
+
+    impl ChunkedStream {
+        fn new(chunks: &[&[u8]]) -> Self {
+            Self {
+                input: chunks.iter().map(|c| c.to_vec()).collect(),
+                ..Self::default()
+            }
+        }
+    }
+
+    impl AsyncRead for ChunkedStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let chunk = match self.input.front_mut() {
+                Some(chunk) => chunk,
+                None => return Poll::Ready(Ok(0)),
+            };
+
+            let n = chunk.len().min(buf.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            chunk.drain(..n);
+            if chunk.is_empty() {
+                self.input.pop_front();
+            }
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for ChunkedStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.output.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.closed = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy_request() {
+        let (start, end) = PolicyFile::REQUEST_MESSAGE.split_at(10);
+        let mut conn = ChunkedStream::new(&[start, end]);
+
+        let policy = PolicyFile::ALLOW_ALL;
+        assert!(policy.handle_connection(&mut conn).await.unwrap().is_none());
+        assert_eq!(conn.output, policy.contents().as_bytes());
+        assert!(conn.closed);
+    }
+
+    #[tokio::test]
+    async fn test_not_policy_request() {
+        let mut conn = ChunkedStream::new(&[b"<pol", b"l", b"ing data"]);
+
+        let mut rewind = PolicyFile::ALLOW_ALL
+            .handle_connection(&mut conn)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut received = Vec::new();
+        rewind.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, b"<polling data");
+        assert!(conn.output.is_empty());
+        assert!(!conn.closed);
+    }
+
+    #[tokio::test]
+    async fn test_partial_policy_request() {
+        let mut conn = ChunkedStream::new(&[b"<policy"]);
+
+        let mut rewind = PolicyFile::ALLOW_ALL
+            .handle_connection(&mut conn)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut received = Vec::new();
+        rewind.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, b"<policy");
+    }
+}
@@ -0,0 +1,566 @@
+//! Typed representation of the rules in a cross-domain policy file.
+
+use std::fmt::{self, Display, Formatter, Write};
+use std::ops::{RangeFull, RangeInclusive};
+use std::str::FromStr;
+
+/// An error parsing a policy file.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidPolicyFile {
+    /// The XML couldn't be parsed.
+    #[error("Malformed XML: {0}")]
+    Malformed(&'static str),
+
+    /// The root `<cross-domain-policy>` element is missing.
+    #[error("Missing <cross-domain-policy> root element")]
+    MissingRoot,
+
+    /// An element that isn't supported in socket policy files was found.
+    #[error("Unsupported element: <{0}>")]
+    UnsupportedElement(String),
+
+    /// A required attribute of an element is missing.
+    #[error("Missing attribute {attribute} on <{element}>")]
+    MissingAttribute {
+        /// The name of the element.
+        element: &'static str,
+        /// The name of the missing attribute.
+        attribute: &'static str,
+    },
+
+    /// A `to-ports` attribute couldn't be parsed.
+    #[error("Invalid port specification: {0:?}")]
+    InvalidPorts(String),
+
+    /// A `permitted-cross-domain-policies` attribute has an unknown value.
+    #[error("Invalid site control policy: {0:?}")]
+    InvalidSiteControl(String),
+}
+
+/// The meta-policy of a policy file, specifying which other policy files may
+/// be used for the same host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiteControl {
+    /// No policy files are allowed, including this one.
+    None,
+
+    /// Only this master policy file is allowed.
+    MasterOnly,
+
+    /// Only policy files served with the correct content type are allowed.
+    ByContentType,
+
+    /// Only policy files named `crossdomain.xml` are allowed.
+    ByFtpFilename,
+
+    /// All policy files are allowed.
+    All,
+}
+
+impl SiteControl {
+    /// Get the value of this meta-policy used in policy files.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SiteControl::None => "none",
+            SiteControl::MasterOnly => "master-only",
+            SiteControl::ByContentType => "by-content-type",
+            SiteControl::ByFtpFilename => "by-ftp-filename",
+            SiteControl::All => "all",
+        }
+    }
+}
+
+impl FromStr for SiteControl {
+    type Err = InvalidPolicyFile;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SiteControl::None),
+            "master-only" => Ok(SiteControl::MasterOnly),
+            "by-content-type" => Ok(SiteControl::ByContentType),
+            "by-ftp-filename" => Ok(SiteControl::ByFtpFilename),
+            "all" => Ok(SiteControl::All),
+            _ => Err(InvalidPolicyFile::InvalidSiteControl(s.to_string())),
+        }
+    }
+}
+
+/// A set of ports that access is allowed to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ports {
+    /// All ports (`*`).
+    All,
+
+    /// Only ports within the given ranges.
+    Ranges(Vec<RangeInclusive<u16>>),
+}
+
+impl Ports {
+    /// Check whether the given port is included.
+    pub fn contains(&self, port: u16) -> bool {
+        match self {
+            Ports::All => true,
+            Ports::Ranges(ranges) => ranges.iter().any(|r| r.contains(&port)),
+        }
+    }
+}
+
+impl From<u16> for Ports {
+    fn from(port: u16) -> Self {
+        Ports::Ranges(vec![port..=port])
+    }
+}
+
+impl From<RangeInclusive<u16>> for Ports {
+    fn from(range: RangeInclusive<u16>) -> Self {
+        Ports::Ranges(vec![range])
+    }
+}
+
+impl From<RangeFull> for Ports {
+    fn from(_: RangeFull) -> Self {
+        Ports::All
+    }
+}
+
+impl Display for Ports {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Ports::All => f.write_str("*"),
+            Ports::Ranges(ranges) => {
+                for (i, range) in ranges.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    if range.start() == range.end() {
+                        write!(f, "{}", range.start())?;
+                    } else {
+                        write!(f, "{}-{}", range.start(), range.end())?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for Ports {
+    type Err = InvalidPolicyFile;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidPolicyFile::InvalidPorts(s.to_string());
+
+        if s.trim() == "*" {
+            return Ok(Ports::All);
+        }
+
+        let parse_port = |p: &str| p.trim().parse::<u16>().map_err(|_| invalid());
+
+        s.split(',')
+            .map(|part| match part.find('-') {
+                Some(i) => {
+                    let (start, end) = (parse_port(&part[..i])?, parse_port(&part[i + 1..])?);
+                    if start <= end {
+                        Ok(start..=end)
+                    } else {
+                        Err(invalid())
+                    }
+                }
+                None => parse_port(part).map(|p| p..=p),
+            })
+            .collect::<Result<_, _>>()
+            .map(Ports::Ranges)
+    }
+}
+
+/// A single rule allowing access from a domain to a set of ports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowRule {
+    /// The domain access is allowed from, which may include wildcards, such as
+    /// `*` or `*.example.com`.
+    pub domain: String,
+
+    /// The ports access is allowed to.
+    pub ports: Ports,
+}
+
+impl AllowRule {
+    /// Check whether this rule allows access from the given domain to the
+    /// given port.
+    pub fn permits(&self, domain: &str, port: u16) -> bool {
+        let domain_matches = if self.domain == "*" {
+            true
+        } else if self.domain.starts_with("*.") {
+            let suffix = &self.domain[2..];
+            domain == suffix || domain.ends_with(&self.domain[1..])
+        } else {
+            domain == self.domain
+        };
+
+        domain_matches && self.ports.contains(port)
+    }
+}
+
+/// The rules specified by a policy file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyRules {
+    /// The meta-policy of the file, if specified.
+    pub site_control: Option<SiteControl>,
+
+    /// Rules allowing access to ports from specific domains.
+    pub allow: Vec<AllowRule>,
+}
+
+impl PolicyRules {
+    /// Check whether these rules allow access from the given domain to the
+    /// given port.
+    pub fn permits(&self, domain: &str, port: u16) -> bool {
+        self.site_control != Some(SiteControl::None)
+            && self.allow.iter().any(|r| r.permits(domain, port))
+    }
+
+    /// Generate the XML policy file for these rules.
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from(concat!(
+            "<?xml version=\"1.0\"?>\n",
+            "<!DOCTYPE cross-domain-policy SYSTEM \"/xml/dtds/cross-domain-policy.dtd\">\n",
+            "<cross-domain-policy>\n"
+        ));
+
+        if let Some(site_control) = self.site_control {
+            xml.push_str(&format!(
+                "\t<site-control permitted-cross-domain-policies=\"{}\"/>\n",
+                site_control.as_str()
+            ));
+        }
+
+        for rule in &self.allow {
+            xml.push_str(&format!(
+                "\t<allow-access-from domain=\"{}\" to-ports=\"{}\"/>\n",
+                escape(&rule.domain),
+                rule.ports
+            ));
+        }
+
+        xml.push_str("</cross-domain-policy>\n");
+        xml
+    }
+
+    /// Parse and validate the rules in the given policy file.
+    pub fn parse(xml: &str) -> Result<Self, InvalidPolicyFile> {
+        let mut rules = PolicyRules::default();
+        let mut found_root = false;
+        let mut depth = 0;
+
+        for token in Tokens(xml) {
+            match token? {
+                Token::Open {
+                    name,
+                    attributes,
+                    self_closing,
+                } => {
+                    match (depth, name) {
+                        (0, "cross-domain-policy") if !found_root => found_root = true,
+                        (0, _) => return Err(InvalidPolicyFile::MissingRoot),
+                        (1, "site-control") => {
+                            let value = attribute(
+                                &attributes,
+                                "site-control",
+                                "permitted-cross-domain-policies",
+                            )?;
+                            rules.site_control = Some(value.parse()?);
+                        }
+                        (1, "allow-access-from") => {
+                            let domain = attribute(&attributes, "allow-access-from", "domain")?;
+                            let ports = attribute(&attributes, "allow-access-from", "to-ports")?;
+                            rules.allow.push(AllowRule {
+                                domain,
+                                ports: ports.parse()?,
+                            });
+                        }
+                        _ => return Err(InvalidPolicyFile::UnsupportedElement(name.to_string())),
+                    }
+
+                    if !self_closing {
+                        depth += 1;
+                    }
+                }
+                Token::Close => {
+                    if depth == 0 {
+                        return Err(InvalidPolicyFile::Malformed("unexpected closing tag"));
+                    }
+                    depth -= 1;
+                }
+            }
+        }
+
+        if !found_root {
+            Err(InvalidPolicyFile::MissingRoot)
+        } else if depth != 0 {
+            Err(InvalidPolicyFile::Malformed("unclosed element"))
+        } else {
+            Ok(rules)
+        }
+    }
+}
+
+/// A builder for generating policy files from typed rules.
+#[derive(Debug, Clone, Default)]
+pub struct PolicyFileBuilder {
+    rules: PolicyRules,
+}
+
+impl PolicyFileBuilder {
+    /// Allow access from the given domain to the given ports.
+    ///
+    /// The domain may be `*` to allow all domains, or start with `*.` to allow
+    /// all subdomains of a domain. Ports may be a single port, an inclusive
+    /// range of ports, or `..` to allow all ports.
+    pub fn allow_domain(mut self, domain: impl Into<String>, ports: impl Into<Ports>) -> Self {
+        self.rules.allow.push(AllowRule {
+            domain: domain.into(),
+            ports: ports.into(),
+        });
+        self
+    }
+
+    /// Set the meta-policy of the policy file.
+    pub fn site_control(mut self, site_control: SiteControl) -> Self {
+        self.rules.site_control = Some(site_control);
+        self
+    }
+
+    /// Get the rules that will be used to generate the policy file.
+    pub fn rules(&self) -> &PolicyRules {
+        &self.rules
+    }
+
+    /// Generate the policy file.
+    pub fn build(self) -> super::PolicyFile {
+        super::PolicyFile::new(self.rules.to_xml())
+    }
+}
+
+/// Escape special characters in an XML attribute value.
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Replace XML entities in an attribute value.
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Get the value of a required attribute.
+fn attribute(
+    attributes: &[(&str, String)],
+    element: &'static str,
+    attribute: &'static str,
+) -> Result<String, InvalidPolicyFile> {
+    attributes
+        .iter()
+        .find(|(name, _)| *name == attribute)
+        .map(|(_, value)| value.clone())
+        .ok_or(InvalidPolicyFile::MissingAttribute { element, attribute })
+}
+
+/// An element tag in an XML document.
+enum Token<'a> {
+    Open {
+        name: &'a str,
+        attributes: Vec<(&'a str, String)>,
+        self_closing: bool,
+    },
+    Close,
+}
+
+/// A minimal tokenizer for the subset of XML used by policy files.
+///
+/// Declarations, comments, and whitespace are skipped, and only element tags
+/// are returned.
+struct Tokens<'a>(&'a str);
+
+impl<'a> Tokens<'a> {
+    /// Skip past the given terminator, returning an error if it's not found.
+    fn skip_past(&mut self, terminator: &str) -> Result<(), InvalidPolicyFile> {
+        match self.0.find(terminator) {
+            Some(i) => {
+                self.0 = &self.0[i + terminator.len()..];
+                Ok(())
+            }
+            None => Err(InvalidPolicyFile::Malformed("unterminated tag")),
+        }
+    }
+
+    fn parse_tag(&mut self) -> Result<Token<'a>, InvalidPolicyFile> {
+        let end = self
+            .0
+            .find('>')
+            .ok_or(InvalidPolicyFile::Malformed("unterminated tag"))?;
+        let tag = &self.0[1..end];
+        self.0 = &self.0[end + 1..];
+
+        if tag.starts_with('/') {
+            return Ok(Token::Close);
+        }
+
+        let self_closing = tag.ends_with('/');
+        let mut tag = tag.trim_end_matches('/');
+
+        let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+        let name = &tag[..name_end];
+        if name.is_empty() {
+            return Err(InvalidPolicyFile::Malformed("missing element name"));
+        }
+        tag = tag[name_end..].trim_start();
+
+        let mut attributes = vec![];
+        while !tag.is_empty() {
+            let eq = tag
+                .find('=')
+                .ok_or(InvalidPolicyFile::Malformed("attribute without value"))?;
+            let key = tag[..eq].trim_end();
+            let rest = tag[eq + 1..].trim_start();
+
+            let quote = rest
+                .chars()
+                .next()
+                .filter(|&c| c == '"' || c == '\'')
+                .ok_or(InvalidPolicyFile::Malformed("unquoted attribute value"))?;
+            let value_end = rest[1..]
+                .find(quote)
+                .ok_or(InvalidPolicyFile::Malformed("unterminated attribute value"))?;
+
+            attributes.push((key, unescape(&rest[1..value_end + 1])));
+            tag = rest[value_end + 2..].trim_start();
+        }
+
+        Ok(Token::Open {
+            name,
+            attributes,
+            self_closing,
+        })
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Result<Token<'a>, InvalidPolicyFile>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.0 = self
+                .0
+                .trim_start_matches(|c: char| c.is_whitespace() || c == '\0');
+
+            if self.0.is_empty() {
+                return None;
+            }
+
+            let skipped = if self.0.starts_with("<?") {
+                self.skip_past("?>")
+            } else if self.0.starts_with("<!--") {
+                self.skip_past("-->")
+            } else if self.0.starts_with("<!") {
+                self.skip_past(">")
+            } else if self.0.starts_with('<') {
+                return Some(self.parse_tag());
+            } else {
+                self.0 = "";
+                return Some(Err(InvalidPolicyFile::Malformed("unexpected text")));
+            };
+
+            if let Err(e) = skipped {
+                self.0 = "";
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PolicyFile;
+
+    #[test]
+    fn test_builder_roundtrip() {
+        let policy = PolicyFile::builder()
+            .site_control(SiteControl::MasterOnly)
+            .allow_domain("*.example.com", 2050..=2050)
+            .allow_domain("localhost", 2000..=2100)
+            .build();
+
+        let rules = policy.parse().unwrap();
+        assert_eq!(rules.site_control, Some(SiteControl::MasterOnly));
+        assert_eq!(rules.allow.len(), 2);
+        assert!(rules.permits("example.com", 2050));
+        assert!(rules.permits("www.example.com", 2050));
+        assert!(!rules.permits("www.example.com", 2051));
+        assert!(!rules.permits("badexample.com", 2050));
+        assert!(rules.permits("localhost", 2051));
+        assert!(!rules.permits("otherhost", 2051));
+    }
+
+    #[test]
+    fn test_parse_allow_all() {
+        let rules = PolicyFile::ALLOW_ALL.parse().unwrap();
+        assert_eq!(
+            rules,
+            PolicyRules {
+                site_control: Some(SiteControl::All),
+                allow: vec![AllowRule {
+                    domain: "*".to_string(),
+                    ports: Ports::All,
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_ports() {
+        assert_eq!(
+            "507,516-523".parse::<Ports>().unwrap(),
+            Ports::Ranges(vec![507..=507, 516..=523])
+        );
+        assert_eq!(
+            Ports::Ranges(vec![507..=507, 516..=523]).to_string(),
+            "507,516-523"
+        );
+        assert!("523-516".parse::<Ports>().is_err());
+        assert!("70000".parse::<Ports>().is_err());
+        assert!("".parse::<Ports>().is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let invalid = |xml: &str| PolicyRules::parse(xml).unwrap_err();
+
+        assert_eq!(invalid(""), InvalidPolicyFile::MissingRoot);
+        assert_eq!(invalid("<policy/>"), InvalidPolicyFile::MissingRoot);
+        assert_eq!(
+            invalid("<cross-domain-policy><allow-access-from domain=\"*\"/></cross-domain-policy>"),
+            InvalidPolicyFile::MissingAttribute {
+                element: "allow-access-from",
+                attribute: "to-ports"
+            }
+        );
+        assert_eq!(
+            invalid("<cross-domain-policy><unknown/></cross-domain-policy>"),
+            InvalidPolicyFile::UnsupportedElement("unknown".to_string())
+        );
+        assert_eq!(
+            invalid("<cross-domain-policy>"),
+            InvalidPolicyFile::Malformed("unclosed element")
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use super::PolicyFile;
+use log::{debug, warn};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::time::{delay_for, timeout};
+
+/// How long to wait after failing to accept a connection before trying again.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Limits applied to connections handled by a `PolicyServer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PolicyServerConfig {
+    /// Maximum time to wait for a connection to send a complete policy file
+    /// request before closing it.
+    pub request_timeout: Duration,
+
+    /// Maximum number of connections to handle at once. Connections accepted
+    /// while the limit is reached are closed immediately.
+    pub max_connections: usize,
+}
+
+impl Default for PolicyServerConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout: Duration::from_secs(5),
+            max_connections: 256,
+        }
+    }
+}
+
+/// A standalone server responding to flash policy file requests.
+///
+/// Flash players first request the policy file from port 843 before falling
+/// back to the port they're connecting to, so running this server alongside a
+/// game server avoids the delay of the fallback. Any connection that doesn't
+/// send a policy file request is closed.
+pub struct PolicyServer {
+    listener: TcpListener,
+    policy: Arc<PolicyFile>,
+    config: PolicyServerConfig,
+    active: Arc<AtomicUsize>,
+}
+
+/// Decrements the active connection count when a connection is closed.
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl PolicyServer {
+    /// The port flash players request policy files from.
+    pub const DEFAULT_PORT: u16 = 843;
+
+    /// Bind a new policy server to the given address.
+    pub async fn bind(
+        address: impl ToSocketAddrs,
+        policy: PolicyFile,
+        config: PolicyServerConfig,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(address).await?,
+            policy: Arc::new(policy),
+            config,
+            active: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Get the local address this server is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept and handle connections forever.
+    ///
+    /// Each connection is handled in a separate task. Errors accepting or
+    /// handling individual connections are logged, and don't stop the server.
+    pub async fn run(mut self) {
+        loop {
+            let (conn, address) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // errors like running out of file descriptors usually
+                    // clear up, so wait a moment rather than spinning
+                    warn!("Error accepting policy server connection: {}", e);
+                    delay_for(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+
+            if self.active.fetch_add(1, Ordering::SeqCst) >= self.config.max_connections {
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                debug!(
+                    "Connection limit reached, dropping connection from {}",
+                    address
+                );
+                continue;
+            }
+
+            let guard = ActiveGuard(self.active.clone());
+            let policy = self.policy.clone();
+            let request_timeout = self.config.request_timeout;
+            tokio::spawn(async move {
+                match Self::handle(conn, &policy, request_timeout).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {
+                        debug!("Policy request from {} timed out", address)
+                    }
+                    Err(e) => warn!("Error handling policy request from {}: {}", address, e),
+                }
+                drop(guard);
+            });
+        }
+    }
+
+    async fn handle(
+        conn: TcpStream,
+        policy: &PolicyFile,
+        request_timeout: Duration,
+    ) -> io::Result<()> {
+        if timeout(request_timeout, policy.handle_connection(conn))
+            .await??
+            .is_some()
+        {
+            debug!("Closing connection that didn't request a policy file");
+        }
+        Ok(())
+    }
+}
@@ -3,18 +3,29 @@
 //! This is adapted from the `rust-crypto` project, the original source is
 //! available [on GitHub](https://github.com/DaGenix/rust-crypto/blob/master/src/rc4.rs]
 
+/// The number of keystream bytes generated at a time by `Rc4::process`.
+const BLOCK_SIZE: usize = 64;
+
 /// The state of an RC4 cipher
+///
+/// Cloning an `Rc4` snapshots its current position in the keystream, so the
+/// clone can be used to process the same data again - for example, to replay
+/// captured traffic from a known point.
 #[derive(Clone)]
 pub struct Rc4 {
     i: u8,
     j: u8,
-    state: [u8; 256],
+    // each entry is a single byte, but storing them as u32 avoids the cost of
+    // byte-sized loads and stores, which is significantly faster on x86
+    state: [u32; 256],
 }
 
 impl Rc4 {
     /// Create a new RC4 state with the given key
     ///
-    /// The key must be between 1 and 256 bytes long, inclusive
+    /// # Panics
+    ///
+    /// Panics if the key is empty or longer than 256 bytes
     pub fn new(key: &[u8]) -> Self {
         assert!(!key.is_empty(), "key must not be empty");
         assert!(key.len() <= 256, "key may not be longer than 256 bytes");
@@ -23,17 +34,17 @@ impl Rc4 {
         let mut rc4 = Self {
             i: 0,
             j: 0,
-            state: [0u8; 256],
+            state: [0u32; 256],
         };
 
         for (i, x) in rc4.state.iter_mut().enumerate() {
-            *x = i as u8;
+            *x = i as u32;
         }
 
         let mut j: u8 = 0;
         for i in 0..256 {
             j = j
-                .wrapping_add(rc4.state[i])
+                .wrapping_add(rc4.state[i] as u8)
                 .wrapping_add(key[i % key.len()]);
             rc4.state.swap(i, j as usize);
         }
@@ -41,17 +52,60 @@ impl Rc4 {
         rc4
     }
 
-    fn next(&mut self) -> u8 {
-        self.i = self.i.wrapping_add(1);
-        self.j = self.j.wrapping_add(self.state[self.i as usize]);
-        self.state.swap(self.i as usize, self.j as usize);
-        self.state[(self.state[self.i as usize].wrapping_add(self.state[self.j as usize])) as usize]
+    /// Generate the next byte of the keystream
+    pub fn next_byte(&mut self) -> u8 {
+        let mut byte = [0u8];
+        self.fill_keystream(&mut byte);
+        byte[0]
+    }
+
+    /// Fill the given buffer with the next bytes of the keystream
+    fn fill_keystream(&mut self, keystream: &mut [u8]) {
+        // work on local copies so the indices can be kept in registers
+        let (mut i, mut j) = (self.i, self.j);
+        let state = &mut self.state;
+
+        for k in keystream.iter_mut() {
+            i = i.wrapping_add(1);
+            let si = state[i as usize];
+            j = j.wrapping_add(si as u8);
+            let sj = state[j as usize];
+            state[i as usize] = sj;
+            state[j as usize] = si;
+            *k = state[((si + sj) & 0xff) as usize] as u8;
+        }
+
+        self.i = i;
+        self.j = j;
+    }
+
+    /// Advance the keystream by `n` bytes without processing any data
+    ///
+    /// This is equivalent to processing `n` bytes and discarding the result,
+    /// and can be used to resynchronize with a stream when data was dropped.
+    /// Note that RC4 doesn't support random access, so this takes time
+    /// proportional to `n`.
+    pub fn skip(&mut self, mut n: usize) {
+        let mut keystream = [0u8; BLOCK_SIZE];
+        while n > 0 {
+            let len = n.min(BLOCK_SIZE);
+            self.fill_keystream(&mut keystream[..len]);
+            n -= len;
+        }
     }
 
     /// Process the given input with this RC4 state
+    ///
+    /// The keystream is generated in blocks, which are then combined with the
+    /// input separately, allowing the compiler to vectorize the XOR.
     pub fn process(&mut self, bytes: &mut [u8]) {
-        for n in bytes.iter_mut() {
-            *n ^= self.next();
+        let mut keystream = [0u8; BLOCK_SIZE];
+        for chunk in bytes.chunks_mut(BLOCK_SIZE) {
+            let keystream = &mut keystream[..chunk.len()];
+            self.fill_keystream(keystream);
+            for (n, k) in chunk.iter_mut().zip(keystream.iter()) {
+                *n ^= k;
+            }
         }
     }
 }
@@ -99,4 +153,45 @@ mod test {
             assert_eq!(bytes, t.output);
         }
     }
+
+    #[test]
+    fn test_process_matches_keystream() {
+        let mut bytes = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        let expected = {
+            let mut rc4 = Rc4::new(b"Key");
+            bytes
+                .iter()
+                .map(|b| b ^ rc4.next_byte())
+                .collect::<Vec<_>>()
+        };
+
+        Rc4::new(b"Key").process(&mut bytes);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_skip() {
+        let mut bytes = b"Attack at dawn".to_vec();
+        let mut skipped = Rc4::new(b"Secret");
+        skipped.skip(200);
+        skipped.process(&mut bytes[..]);
+
+        let mut expected = vec![0u8; 200];
+        expected.extend_from_slice(b"Attack at dawn");
+        Rc4::new(b"Secret").process(&mut expected[..]);
+        assert_eq!(bytes, &expected[200..]);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut rc4 = Rc4::new(b"Wiki");
+        rc4.skip(10);
+        let mut snapshot = rc4.clone();
+
+        let mut a = b"pedia".to_vec();
+        let mut b = b"pedia".to_vec();
+        rc4.process(&mut a[..]);
+        snapshot.process(&mut b[..]);
+        assert_eq!(a, b);
+    }
 }
@@ -0,0 +1,121 @@
+//! A stream wrapper for replaying bytes that were already read.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A stream which returns a prefix of already-read bytes before continuing to
+/// read from the underlying stream.
+///
+/// This allows the start of a stream to be inspected, such as when detecting
+/// policy file requests, without requiring support for peeking from the
+/// underlying transport. Writes are passed through unchanged.
+#[derive(Debug)]
+pub struct Rewind<T> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: T,
+}
+
+impl<T> Rewind<T> {
+    /// Wrap a stream, replaying the given prefix before any further data.
+    pub fn new(inner: T, prefix: Vec<u8>) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+
+    /// Get a reference to the underlying stream.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the underlying stream.
+    ///
+    /// Reading directly from the underlying stream will skip any remaining
+    /// prefix bytes.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Get the remaining prefix bytes which haven't been read yet.
+    pub fn remaining(&self) -> &[u8] {
+        &self.prefix[self.pos..]
+    }
+
+    /// Split this into the remaining prefix bytes and the underlying stream.
+    pub fn into_parts(mut self) -> (Vec<u8>, T) {
+        self.prefix.drain(..self.pos);
+        (self.prefix, self.inner)
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rewind<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let remaining = self.remaining();
+        if remaining.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rewind<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rewind;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_rewind() {
+        let mut stream = Rewind::new(&b"world"[..], b"hello ".to_vec());
+
+        let mut start = [0u8; 3];
+        stream.read_exact(&mut start).await.unwrap();
+        assert_eq!(&start, b"hel");
+        assert_eq!(stream.remaining(), b"lo ");
+
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"lo world");
+    }
+
+    #[tokio::test]
+    async fn test_into_parts() {
+        let mut stream = Rewind::new(&b"world"[..], b"hello ".to_vec());
+        let mut start = [0u8; 2];
+        stream.read_exact(&mut start).await.unwrap();
+
+        let (prefix, inner) = stream.into_parts();
+        assert_eq!(prefix, b"llo ");
+        assert_eq!(inner, b"world");
+    }
+}
@@ -0,0 +1,302 @@
+//! Connection statistics and hooks for observing traffic.
+//!
+//! Every `Encoder` and `Decoder` can have any number of `PacketObserver`s
+//! attached, which are notified of each packet passing through. The main
+//! observer provided is `ConnectionMetrics`, which counts traffic and measures
+//! latency, and can forward these events to a `StatsObserver` for export to
+//! external monitoring systems.
+
+use crate::codec::{Decoder, Encoder};
+use rotmg_packets::raw::RawPacket;
+use rotmg_packets::structured::packets::PacketType;
+use rotmg_packets::PacketMappings;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// The direction of a packet, relative to the local end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// A packet received by a `Decoder`.
+    Inbound,
+
+    /// A packet sent by an `Encoder`.
+    Outbound,
+}
+
+/// A hook notified of every packet passing through an `Encoder` or `Decoder`.
+///
+/// Observers are always given the decrypted packet, and are called in the
+/// order packets are sent or received. Since they're called inline, observers
+/// should avoid blocking for any significant amount of time.
+pub trait PacketObserver: Send + Sync {
+    /// Observe a single packet.
+    fn observe(&self, direction: Direction, packet: &RawPacket);
+}
+
+/// A hook notified of events recorded by `ConnectionMetrics`.
+///
+/// All methods do nothing by default, so implementations only need to handle
+/// the events they're interested in.
+pub trait StatsObserver: Send + Sync {
+    /// Called for every packet, with its type if it's known by the mappings.
+    fn on_packet(&self, _direction: Direction, _typ: Option<PacketType>, _size: usize) {}
+
+    /// Called when a packet fails to parse.
+    fn on_parse_failure(&self) {}
+
+    /// Called when the latency between a `Ping` and `Pong` packet is measured.
+    fn on_latency(&self, _latency: Duration) {}
+}
+
+/// Packet and byte counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketCounts {
+    /// The number of packets.
+    pub packets: u64,
+
+    /// The total size of the packets, including headers.
+    pub bytes: u64,
+}
+
+impl PacketCounts {
+    fn add(&mut self, size: usize) {
+        self.packets += 1;
+        self.bytes += size as u64;
+    }
+}
+
+/// A snapshot of the statistics for a single connection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionStats {
+    /// Totals for all packets received.
+    pub inbound: PacketCounts,
+
+    /// Totals for all packets sent.
+    pub outbound: PacketCounts,
+
+    /// Counts of received packets with known types.
+    pub inbound_by_type: HashMap<PacketType, PacketCounts>,
+
+    /// Counts of sent packets with known types.
+    pub outbound_by_type: HashMap<PacketType, PacketCounts>,
+
+    /// The number of packets that couldn't be parsed.
+    pub parse_failures: u64,
+
+    /// The most recently measured latency between a `Ping` and its `Pong`.
+    pub last_latency: Option<Duration>,
+
+    /// The average latency between `Ping` and `Pong` packets.
+    pub average_latency: Option<Duration>,
+}
+
+/// The maximum number of unanswered pings to keep track of.
+const MAX_PENDING_PINGS: usize = 16;
+
+struct MetricsState {
+    stats: ConnectionStats,
+    pending_pings: VecDeque<(u32, Instant)>,
+    total_latency: Duration,
+    latency_samples: u32,
+}
+
+struct MetricsInner {
+    mappings: PacketMappings,
+    ping_id: Option<u8>,
+    pong_id: Option<u8>,
+    state: Mutex<MetricsState>,
+    observer: Option<Arc<dyn StatsObserver>>,
+}
+
+/// A `PacketObserver` that collects statistics about a connection.
+///
+/// Metrics are shared between clones, so the same instance can be attached to
+/// both halves of a connection and then queried from elsewhere with
+/// `ConnectionMetrics::snapshot`.
+///
+/// Latency is measured as the time between a `Ping` packet passing through in
+/// one direction and the `Pong` with the same serial passing through in the
+/// other, so it's only meaningful on the side of the connection sending pings.
+#[derive(Clone)]
+pub struct ConnectionMetrics(Arc<MetricsInner>);
+
+impl ConnectionMetrics {
+    /// Create new metrics, using the given mappings to identify packets.
+    pub fn new(mappings: &PacketMappings) -> Self {
+        Self::create(mappings, None)
+    }
+
+    /// Create new metrics which forward events to the given observer.
+    pub fn with_observer(mappings: &PacketMappings, observer: Arc<dyn StatsObserver>) -> Self {
+        Self::create(mappings, Some(observer))
+    }
+
+    fn create(mappings: &PacketMappings, observer: Option<Arc<dyn StatsObserver>>) -> Self {
+        Self(Arc::new(MetricsInner {
+            mappings: mappings.clone(),
+            ping_id: mappings.to_game(PacketType::Ping),
+            pong_id: mappings.to_game(PacketType::Pong),
+            state: Mutex::new(MetricsState {
+                stats: ConnectionStats::default(),
+                pending_pings: VecDeque::new(),
+                total_latency: Duration::default(),
+                latency_samples: 0,
+            }),
+            observer,
+        }))
+    }
+
+    /// Attach these metrics to both halves of a connection.
+    pub fn attach<R, W>(&self, decoder: &mut Decoder<R>, encoder: &mut Encoder<W>)
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        decoder.add_observer(Arc::new(self.clone()));
+        encoder.add_observer(Arc::new(self.clone()));
+    }
+
+    /// Record that a packet from this connection couldn't be parsed.
+    pub fn record_parse_failure(&self) {
+        self.0.state.lock().unwrap().stats.parse_failures += 1;
+        if let Some(observer) = &self.0.observer {
+            observer.on_parse_failure();
+        }
+    }
+
+    /// Get a snapshot of the current statistics.
+    pub fn snapshot(&self) -> ConnectionStats {
+        self.0.state.lock().unwrap().stats.clone()
+    }
+}
+
+impl PacketObserver for ConnectionMetrics {
+    fn observe(&self, direction: Direction, packet: &RawPacket) {
+        let inner = &self.0;
+        let typ = inner.mappings.to_internal(packet.id());
+        let size = packet.bytes().len();
+        let serial = packet
+            .payload()
+            .get(..4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+
+        let mut latency = None;
+        {
+            let mut state = inner.state.lock().unwrap();
+            let stats = &mut state.stats;
+
+            let (total, by_type) = match direction {
+                Direction::Inbound => (&mut stats.inbound, &mut stats.inbound_by_type),
+                Direction::Outbound => (&mut stats.outbound, &mut stats.outbound_by_type),
+            };
+            total.add(size);
+            if let Some(typ) = typ {
+                by_type.entry(typ).or_default().add(size);
+            }
+
+            match serial {
+                Some(serial) if Some(packet.id()) == inner.ping_id => {
+                    if state.pending_pings.len() == MAX_PENDING_PINGS {
+                        state.pending_pings.pop_front();
+                    }
+                    state.pending_pings.push_back((serial, Instant::now()));
+                }
+                Some(serial) if Some(packet.id()) == inner.pong_id => {
+                    let pending = &mut state.pending_pings;
+                    if let Some(i) = pending.iter().position(|&(s, _)| s == serial) {
+                        let (_, sent) = pending.remove(i).unwrap();
+                        let elapsed = sent.elapsed();
+
+                        state.total_latency += elapsed;
+                        state.latency_samples += 1;
+                        state.stats.last_latency = Some(elapsed);
+                        state.stats.average_latency =
+                            Some(state.total_latency / state.latency_samples);
+                        latency = Some(elapsed);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(observer) = &inner.observer {
+            observer.on_packet(direction, typ, size);
+            if let Some(latency) = latency {
+                observer.on_latency(latency);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rc4::Rc4;
+    use rotmg_packets::structured::packets::{client::Pong, server::Ping, AnyPacket};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const KEY: &[u8] = b"key";
+
+    fn identity_mappings() -> PacketMappings {
+        PacketMappings::new(PacketType::VALUES.iter().map(|&t| (t, t as u8)))
+    }
+
+    #[derive(Default)]
+    struct CountingObserver {
+        packets: AtomicUsize,
+        latencies: AtomicUsize,
+    }
+
+    impl StatsObserver for CountingObserver {
+        fn on_packet(&self, _direction: Direction, _typ: Option<PacketType>, _size: usize) {
+            self.packets.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_latency(&self, _latency: Duration) {
+            self.latencies.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_metrics() {
+        let observer = Arc::new(CountingObserver::default());
+        let metrics = ConnectionMetrics::with_observer(&identity_mappings(), observer.clone());
+
+        // send pings to a buffer, then read pongs back from another buffer
+        let mut encoder = Encoder::new(vec![], Rc4::new(KEY));
+        encoder.add_observer(Arc::new(metrics.clone()));
+        for serial in 0..3 {
+            let ping = Box::new(Ping { serial }).into_raw().unwrap();
+            encoder.send(ping).await.unwrap();
+        }
+
+        let mut pongs = Encoder::new(vec![], Rc4::new(KEY));
+        for serial in 0..2 {
+            let pong = Box::new(Pong { serial, time: 0 }).into_raw().unwrap();
+            pongs.send(pong).await.unwrap();
+        }
+
+        let mut decoder = Decoder::new(&pongs.inner().unwrap()[..], Rc4::new(KEY));
+        decoder.add_observer(Arc::new(metrics.clone()));
+        while decoder.recv().await.unwrap().is_some() {}
+
+        metrics.record_parse_failure();
+
+        let stats = metrics.snapshot();
+        assert_eq!(stats.outbound.packets, 3);
+        assert_eq!(stats.outbound.bytes, 27);
+        assert_eq!(stats.inbound.packets, 2);
+        assert_eq!(stats.inbound.bytes, 26);
+        assert_eq!(stats.outbound_by_type[&PacketType::Ping].packets, 3);
+        assert_eq!(stats.inbound_by_type[&PacketType::Pong].packets, 2);
+        assert_eq!(stats.parse_failures, 1);
+        assert!(stats.last_latency.is_some());
+        assert!(stats.average_latency.is_some());
+
+        assert_eq!(observer.packets.load(Ordering::SeqCst), 5);
+        assert_eq!(observer.latencies.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,73 @@
+//! TLS transport for ROTMG connections, using rustls.
+//!
+//! Packets are sent exactly as they would be over plain TCP, inside a TLS
+//! session. The `rustls` crate is re-exported for building configurations.
+
+use crate::codec::{Decoder, Encoder};
+use crate::{configure_socket, create_ciphers, split_stream, ConnectionConfig};
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+pub use tokio_rustls::rustls;
+
+/// The codec halves for a client TLS connection.
+pub type ClientTlsCodec = (
+    Decoder<ReadHalf<client::TlsStream<TcpStream>>>,
+    Encoder<WriteHalf<client::TlsStream<TcpStream>>>,
+);
+
+/// The codec halves for a server TLS connection.
+pub type ServerTlsCodec = (
+    Decoder<ReadHalf<server::TlsStream<TcpStream>>>,
+    Encoder<WriteHalf<server::TlsStream<TcpStream>>>,
+);
+
+/// Open a new ROTMG connection over TLS using the given RC4 keys, as a client.
+///
+/// The server's certificate is verified against the given domain name using
+/// the given TLS config. The raw binary keys should be used, decoded from
+/// hexadecimal.
+pub async fn connect_tls(
+    address: impl ToSocketAddrs,
+    domain: &str,
+    tls_config: Arc<rustls::ClientConfig>,
+    keys: &[u8],
+    config: &ConnectionConfig,
+) -> io::Result<ClientTlsCodec> {
+    let domain = DNSNameRef::try_from_ascii_str(domain)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+    let conn = TcpStream::connect(address).await?;
+    configure_socket(&conn, config)?;
+    let stream = TlsConnector::from(tls_config).connect(domain, conn).await?;
+
+    let (tx_rc4, rx_rc4) = create_ciphers(keys);
+    Ok(split_stream(stream, rx_rc4, tx_rc4, config))
+}
+
+/// Accept an incoming ROTMG connection over TLS using the given RC4 keys, as a
+/// server.
+///
+/// The TLS handshake is performed on the given TCP stream using the given TLS
+/// config. The raw binary keys should be used, decoded from hexadecimal. The
+/// handshake timeout from the given config applies to the TLS handshake.
+pub async fn accept_tls(
+    conn: TcpStream,
+    tls_config: Arc<rustls::ServerConfig>,
+    keys: &[u8],
+    config: &ConnectionConfig,
+) -> io::Result<ServerTlsCodec> {
+    configure_socket(&conn, config)?;
+    let handshake = TlsAcceptor::from(tls_config).accept(conn);
+    let stream = match config.handshake_timeout {
+        Some(handshake_timeout) => tokio::time::timeout(handshake_timeout, handshake).await??,
+        None => handshake.await?,
+    };
+
+    let (rx_rc4, tx_rc4) = create_ciphers(keys);
+    Ok(split_stream(stream, rx_rc4, tx_rc4, config))
+}
@@ -0,0 +1,164 @@
+//! WebSocket transport for ROTMG connections.
+//!
+//! Packets are sent exactly as they would be over TCP, framed and encrypted,
+//! carried in binary WebSocket messages. Message boundaries aren't significant,
+//! so the stream of messages is treated as a single stream of bytes.
+
+use crate::codec::{Decoder, Encoder};
+use crate::{configure_socket, create_ciphers, split_stream, ConnectionConfig};
+use futures::{ready, Sink, Stream};
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{accept_async, client_async, WebSocketStream};
+
+/// Convert a WebSocket error into an IO error, unwrapping underlying IO errors
+fn ws_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::new(ErrorKind::Other, e),
+    }
+}
+
+/// An adapter exposing a WebSocket connection as a byte stream.
+///
+/// Each write is sent as a single binary message, and received binary
+/// messages are read back in order. Text messages are treated as an error,
+/// and control messages are handled transparently.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl<S> WsStream<S> {
+    /// Wrap an established WebSocket connection.
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buf: Vec::new(),
+            read_pos: 0,
+        }
+    }
+
+    /// Get a reference to the underlying WebSocket connection.
+    pub fn get_ref(&self) -> &WebSocketStream<S> {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.read_pos == self.read_buf.len() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    self.read_buf = data;
+                    self.read_pos = 0;
+                }
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "received text message on binary WebSocket stream",
+                    )))
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(0)),
+                Some(Ok(_)) => {}
+                Some(Err(WsError::ConnectionClosed)) => return Poll::Ready(Ok(0)),
+                Some(Err(e)) => return Poll::Ready(Err(ws_error(e))),
+            }
+        }
+
+        let remaining = &self.read_buf[self.read_pos..];
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.read_pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut inner = Pin::new(&mut self.inner);
+        ready!(inner.as_mut().poll_ready(cx)).map_err(ws_error)?;
+        inner
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(ws_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(ws_error)
+    }
+}
+
+/// The codec halves for a WebSocket connection over TCP.
+pub type WsCodec = (
+    Decoder<ReadHalf<WsStream<TcpStream>>>,
+    Encoder<WriteHalf<WsStream<TcpStream>>>,
+);
+
+/// Open a new ROTMG connection over a WebSocket using the given RC4 keys, as a
+/// client.
+///
+/// Only unencrypted `ws://` URLs are supported. The raw binary keys should be
+/// used, decoded from hexadecimal.
+pub async fn connect_ws(url: &str, keys: &[u8], config: &ConnectionConfig) -> io::Result<WsCodec> {
+    let request = url.into_client_request().map_err(ws_error)?;
+    let uri = request.uri();
+    if uri.scheme_str() != Some("ws") {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "only ws:// URLs are supported",
+        ));
+    }
+    let host = uri
+        .host()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "URL has no host"))?;
+    let port = uri.port_u16().unwrap_or(80);
+
+    let conn = TcpStream::connect((host, port)).await?;
+    configure_socket(&conn, config)?;
+    let (ws, _) = client_async(request, conn).await.map_err(ws_error)?;
+
+    let (tx_rc4, rx_rc4) = create_ciphers(keys);
+    Ok(split_stream(WsStream::new(ws), rx_rc4, tx_rc4, config))
+}
+
+/// Accept an incoming ROTMG connection over a WebSocket using the given RC4
+/// keys, as a server.
+///
+/// The WebSocket handshake is performed on the given TCP stream. The raw binary
+/// keys should be used, decoded from hexadecimal. The handshake timeout from
+/// the given config applies to the WebSocket handshake.
+pub async fn accept_ws(
+    conn: TcpStream,
+    keys: &[u8],
+    config: &ConnectionConfig,
+) -> io::Result<WsCodec> {
+    configure_socket(&conn, config)?;
+    let handshake = accept_async(conn);
+    let ws = match config.handshake_timeout {
+        Some(handshake_timeout) => tokio::time::timeout(handshake_timeout, handshake).await?,
+        None => handshake.await,
+    }
+    .map_err(ws_error)?;
+
+    let (rx_rc4, tx_rc4) = create_ciphers(keys);
+    Ok(split_stream(WsStream::new(ws), rx_rc4, tx_rc4, config))
+}
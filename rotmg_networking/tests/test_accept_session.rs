@@ -0,0 +1,134 @@
+use rotmg_networking::{accept_session, connect, ConnectionConfig, HandshakeError, PolicyFile};
+use rotmg_packets::structured::packets::client::{Hello, Pong};
+use rotmg_packets::structured::packets::{AnyPacket, PacketType};
+use rotmg_packets::PacketMappings;
+use std::io::ErrorKind;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const KEYS: &[u8] = b"abcd";
+
+/// Mappings where game IDs are equal to the internal packet types
+fn identity_mappings() -> PacketMappings {
+    PacketMappings::new(PacketType::VALUES.iter().map(|&t| (t, t as u8)))
+}
+
+#[tokio::test]
+async fn test_accept_session() {
+    let mut listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let hello = Hello {
+        build_version: "1.2.3".to_string(),
+        game_id: 42,
+        key: vec![1, 2, 3],
+        ..Hello::default()
+    };
+
+    let sent = hello.clone();
+    tokio::spawn(async move {
+        let (mut rx, mut tx) = connect(address, KEYS, &ConnectionConfig::default())
+            .await
+            .unwrap();
+        tx.send(Box::new(sent).into_raw().unwrap()).await.unwrap();
+        rx.recv().await.unwrap();
+    });
+
+    let (conn, _) = listener.accept().await.unwrap();
+    let config = ConnectionConfig {
+        handshake_timeout: Some(Duration::from_secs(5)),
+        ..ConnectionConfig::default()
+    };
+    let (received, _rx, _tx) = accept_session(
+        conn,
+        KEYS,
+        &PolicyFile::ALLOW_ALL,
+        &identity_mappings(),
+        &config,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(received, hello);
+}
+
+#[tokio::test]
+async fn test_accept_session_unexpected_packet() {
+    let mut listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut rx, mut tx) = connect(address, KEYS, &ConnectionConfig::default())
+            .await
+            .unwrap();
+        let pong = Pong::default();
+        tx.send(Box::new(pong).into_raw().unwrap()).await.unwrap();
+        rx.recv().await.unwrap();
+    });
+
+    let (conn, _) = listener.accept().await.unwrap();
+    let result = accept_session(
+        conn,
+        KEYS,
+        &PolicyFile::ALLOW_ALL,
+        &identity_mappings(),
+        &ConnectionConfig::default(),
+    )
+    .await;
+    let err = match result {
+        Ok(_) => panic!("expected handshake to fail"),
+        Err(e) => e,
+    };
+
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    match *err
+        .into_inner()
+        .unwrap()
+        .downcast::<HandshakeError>()
+        .unwrap()
+    {
+        HandshakeError::UnexpectedPacket { expected, received } => {
+            assert_eq!(expected, Some(PacketType::Hello as u8));
+            assert_eq!(received, PacketType::Pong as u8);
+        }
+        e => panic!("unexpected error: {}", e),
+    }
+}
+
+#[tokio::test]
+async fn test_accept_session_timeout() {
+    let mut listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // connect without ever sending a Hello packet
+    let client = tokio::spawn(async move {
+        let conn = connect(address, KEYS, &ConnectionConfig::default())
+            .await
+            .unwrap();
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+        drop(conn);
+    });
+
+    let (conn, _) = listener.accept().await.unwrap();
+    let config = ConnectionConfig {
+        handshake_timeout: Some(Duration::from_millis(100)),
+        ..ConnectionConfig::default()
+    };
+    let result = accept_session(
+        conn,
+        KEYS,
+        &PolicyFile::ALLOW_ALL,
+        &identity_mappings(),
+        &config,
+    )
+    .await;
+    let err = match result {
+        Ok(_) => panic!("expected handshake to fail"),
+        Err(e) => e,
+    };
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+    client.await.unwrap();
+}
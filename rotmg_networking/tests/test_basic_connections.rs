@@ -1,5 +1,5 @@
 use futures::try_join;
-use rotmg_networking::{accept_with_policy, connect, PolicyFile};
+use rotmg_networking::{accept_with_policy, connect, ConnectionConfig, PolicyFile};
 use rotmg_packets::raw::RawPacket;
 use std::net::{Ipv4Addr, Shutdown};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -20,9 +20,11 @@ async fn test_basic_connections() {
             let (conn, address) = listener.accept().await.unwrap();
             println!("Accepting connection from {}", address);
 
-            if let Some((mut rx, mut tx)) = accept_with_policy(conn, KEYS, &PolicyFile::ALLOW_ALL)
-                .await
-                .unwrap()
+            let config = ConnectionConfig::default();
+            if let Some((mut rx, mut tx)) =
+                accept_with_policy(conn, KEYS, &PolicyFile::ALLOW_ALL, &config)
+                    .await
+                    .unwrap()
             {
                 println!("ROTMG connection established with {}", address);
                 for i in 0..3 {
@@ -54,7 +56,9 @@ async fn test_basic_connections() {
     });
 
     let rotmg_response = tokio::spawn(async move {
-        let (mut rx, mut tx) = connect(address, KEYS).await.unwrap();
+        let (mut rx, mut tx) = connect(address, KEYS, &ConnectionConfig::default())
+            .await
+            .unwrap();
         println!(
             "Client connected to {}",
             rx.inner().as_ref().peer_addr().unwrap()
@@ -0,0 +1,101 @@
+use rotmg_networking::codec::{PacketRateLimitExceeded, PacketSizeLimitExceeded};
+use rotmg_networking::{accept, connect, ConnectionConfig};
+use rotmg_packets::raw::RawPacket;
+use std::io::ErrorKind;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+const DATA: &[u8] = &[0, 0, 0, 6, 5, 6];
+const KEYS: &[u8] = b"abcd";
+
+#[tokio::test]
+async fn test_packet_size_limit() {
+    let mut listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let mut conn = TcpStream::connect(address).await.unwrap();
+        conn.write_all(&[0, 0, 1, 0, 5]).await.unwrap();
+    });
+
+    let (conn, _) = listener.accept().await.unwrap();
+    let config = ConnectionConfig {
+        max_packet_size: 128,
+        ..ConnectionConfig::default()
+    };
+    let (mut rx, _tx) = accept(conn, KEYS, &config).unwrap();
+
+    let err = rx.recv().await.unwrap_err();
+    let inner = err.into_inner().unwrap();
+    assert_eq!(
+        *inner.downcast::<PacketSizeLimitExceeded>().unwrap(),
+        PacketSizeLimitExceeded {
+            limit: 128,
+            size: 256
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_idle_timeout() {
+    let mut listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // keep the client connection open without sending anything
+    let client = tokio::spawn(async move {
+        let conn = connect(address, KEYS, &ConnectionConfig::default())
+            .await
+            .unwrap();
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+        drop(conn);
+    });
+
+    let (conn, _) = listener.accept().await.unwrap();
+    let config = ConnectionConfig {
+        idle_timeout: Some(Duration::from_millis(100)),
+        ..ConnectionConfig::default()
+    };
+    let (mut rx, _tx) = accept(conn, KEYS, &config).unwrap();
+
+    let err = rx.recv().await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+    client.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_packet_rate_limit() {
+    let mut listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (_rx, mut tx) = connect(address, KEYS, &ConnectionConfig::default())
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            tx.send(RawPacket::from_slice(DATA).unwrap().to_owned())
+                .await
+                .unwrap();
+        }
+    });
+
+    let (conn, _) = listener.accept().await.unwrap();
+    let config = ConnectionConfig {
+        max_packets_per_second: Some(3),
+        ..ConnectionConfig::default()
+    };
+    let (mut rx, _tx) = accept(conn, KEYS, &config).unwrap();
+
+    for _ in 0..3 {
+        assert_eq!(rx.recv().await.unwrap().unwrap().bytes(), DATA);
+    }
+
+    let err = rx.recv().await.unwrap_err();
+    let inner = err.into_inner().unwrap();
+    assert_eq!(
+        *inner.downcast::<PacketRateLimitExceeded>().unwrap(),
+        PacketRateLimitExceeded { limit: 3 }
+    );
+}
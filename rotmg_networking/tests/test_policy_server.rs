@@ -0,0 +1,64 @@
+use rotmg_networking::policy::{PolicyServer, PolicyServerConfig};
+use rotmg_networking::PolicyFile;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn start_server(config: PolicyServerConfig) -> SocketAddr {
+    let server = PolicyServer::bind((Ipv4Addr::LOCALHOST, 0), PolicyFile::ALLOW_ALL, config)
+        .await
+        .unwrap();
+    let address = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+    address
+}
+
+/// Send a request, returning the response, or `None` if the connection was
+/// reset
+async fn send(address: SocketAddr, request: &[u8]) -> Option<Vec<u8>> {
+    let mut conn = TcpStream::connect(address).await.unwrap();
+    conn.write_all(request).await.unwrap();
+
+    let mut response = Vec::new();
+    match conn.read_to_end(&mut response).await {
+        Ok(_) => Some(response),
+        Err(e) if e.kind() == ErrorKind::ConnectionReset => None,
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+#[tokio::test]
+async fn test_policy_server() {
+    let address = start_server(PolicyServerConfig::default()).await;
+
+    let response = send(address, PolicyFile::REQUEST_MESSAGE).await.unwrap();
+    assert_eq!(response, PolicyFile::ALLOW_ALL.contents().as_bytes());
+
+    let response = send(address, b"GET / HTTP/1.1\r\n\r\n").await;
+    assert!(response.unwrap_or_default().is_empty());
+}
+
+#[tokio::test]
+async fn test_policy_server_limits() {
+    let address = start_server(PolicyServerConfig {
+        request_timeout: Duration::from_millis(200),
+        max_connections: 1,
+    })
+    .await;
+
+    // occupy the only connection slot without sending anything
+    let mut idle = TcpStream::connect(address).await.unwrap();
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    let response = send(address, PolicyFile::REQUEST_MESSAGE).await;
+    assert!(response.unwrap_or_default().is_empty());
+
+    // the idle connection should be closed once the timeout elapses
+    let mut buf = [0u8; 16];
+    assert_eq!(idle.read(&mut buf).await.unwrap(), 0);
+
+    let response = send(address, PolicyFile::REQUEST_MESSAGE).await.unwrap();
+    assert_eq!(response, PolicyFile::ALLOW_ALL.contents().as_bytes());
+}
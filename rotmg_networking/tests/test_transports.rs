@@ -0,0 +1,124 @@
+#![cfg(any(feature = "websocket", feature = "tls"))]
+
+use rotmg_networking::codec::{Decoder, Encoder};
+use rotmg_networking::ConnectionConfig;
+use rotmg_packets::raw::RawPacket;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const KEYS: &[u8] = b"abcd";
+const REQUEST: &[u8] = &[0, 0, 0, 8, 1, 1, 2, 3];
+const RESPONSE: &[u8] = &[0, 0, 0, 9, 2, 4, 5, 6, 7];
+
+/// Send a packet in each direction, and check they're received correctly
+async fn exchange<R1, W1, R2, W2>(
+    client: (Decoder<R1>, Encoder<W1>),
+    server: (Decoder<R2>, Encoder<W2>),
+) where
+    R1: AsyncRead + Unpin,
+    W1: AsyncWrite + Unpin,
+    R2: AsyncRead + Unpin,
+    W2: AsyncWrite + Unpin,
+{
+    let (mut client_rx, mut client_tx) = client;
+    let (mut server_rx, mut server_tx) = server;
+
+    let request = RawPacket::from_slice(REQUEST).unwrap().to_owned();
+    client_tx.send(request).await.unwrap();
+    let received = server_rx.recv().await.unwrap().unwrap();
+    assert_eq!(received.bytes(), REQUEST);
+
+    let response = RawPacket::from_slice(RESPONSE).unwrap().to_owned();
+    server_tx.send(response).await.unwrap();
+    let received = client_rx.recv().await.unwrap().unwrap();
+    assert_eq!(received.bytes(), RESPONSE);
+
+    // close the connection cleanly, as dropping it would be a protocol error
+    client_tx
+        .into_inner()
+        .ok()
+        .unwrap()
+        .shutdown()
+        .await
+        .unwrap();
+    assert!(server_rx.recv().await.unwrap().is_none());
+}
+
+async fn bind() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let address = listener.local_addr().unwrap();
+    (listener, address)
+}
+
+#[cfg(feature = "websocket")]
+#[tokio::test]
+async fn test_websocket() {
+    use rotmg_networking::{accept_ws, connect_ws};
+
+    let (mut listener, address) = bind().await;
+    let server = tokio::spawn(async move {
+        let (conn, _) = listener.accept().await.unwrap();
+        accept_ws(conn, KEYS, &ConnectionConfig::default()).await
+    });
+
+    let url = format!("ws://{}/", address);
+    let client = connect_ws(&url, KEYS, &ConnectionConfig::default())
+        .await
+        .unwrap();
+    let server = match server.await.unwrap() {
+        Ok(server) => server,
+        Err(e) => panic!("error accepting connection: {}", e),
+    };
+
+    exchange(client, server).await;
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_tls() {
+    use rotmg_networking::tls::rustls::{
+        Certificate, ClientConfig, NoClientAuth, PrivateKey, ServerConfig,
+    };
+    use rotmg_networking::{accept_tls, connect_tls};
+    use std::sync::Arc;
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_der = Certificate(cert.serialize_der().unwrap());
+    let key_der = PrivateKey(cert.serialize_private_key_der());
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config
+        .set_single_cert(vec![cert_der.clone()], key_der)
+        .unwrap();
+    let mut client_config = ClientConfig::new();
+    client_config.root_store.add(&cert_der).unwrap();
+
+    let (mut listener, address) = bind().await;
+    let server = tokio::spawn(async move {
+        let (conn, _) = listener.accept().await.unwrap();
+        accept_tls(
+            conn,
+            Arc::new(server_config),
+            KEYS,
+            &ConnectionConfig::default(),
+        )
+        .await
+    });
+
+    let client = connect_tls(
+        address,
+        "localhost",
+        Arc::new(client_config),
+        KEYS,
+        &ConnectionConfig::default(),
+    )
+    .await
+    .unwrap();
+    let server = match server.await.unwrap() {
+        Ok(server) => server,
+        Err(e) => panic!("error accepting connection: {}", e),
+    };
+
+    exchange(client, server).await;
+}
@@ -13,6 +13,7 @@ mod unit;
 mod vec;
 
 use crate::raw::RawPacket;
+use crate::structured::packets::PacketType;
 use std::fmt::Display;
 use std::marker::PhantomData;
 use std::string::FromUtf8Error;
@@ -47,6 +48,11 @@ pub enum PacketFormatError {
     /// mappings.
     #[error("No known mapping for packet ID {0}")]
     UnmappedID(u8),
+
+    /// Couldn't identify the ROTMG packet ID for a packet type due to
+    /// incomplete mappings.
+    #[error("No known mapping for packet type {0:?}")]
+    UnmappedType(PacketType),
 }
 
 impl PacketFormatError {
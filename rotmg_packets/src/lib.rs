@@ -7,6 +7,8 @@
 //!
 //! If desired, `serde` support can be enabled with the `serde` feature flag,
 //! allowing packets to be serialized and deserialized to arbitrary formats.
+//! Packets of unknown type can be serialized as a `dyn AnyPacket`, and
+//! deserialized using `PacketType::deserialize_packet`.
 
 #![warn(missing_docs)]
 
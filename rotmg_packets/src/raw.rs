@@ -109,6 +109,11 @@ impl RawPacket {
         self.0[4]
     }
 
+    /// Set the raw ID of this packet.
+    pub fn set_id(&mut self, id: u8) {
+        self.0[4] = id;
+    }
+
     /// Attempt to parse this raw packet into a structured packet using the
     /// given mappings.
     ///
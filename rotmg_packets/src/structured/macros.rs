@@ -146,7 +146,19 @@ macro_rules! define_packets {
 
                 // packet trait implementations
                 $(
-                    impl sealed::Sealed for $name {}
+                    impl sealed::Sealed for $name {
+                        fn as_any(&self) -> &dyn Any {
+                            self
+                        }
+
+                        fn as_any_mut(&mut self) -> &mut dyn Any {
+                            self
+                        }
+
+                        fn into_any(self: Box<Self>) -> Box<dyn Any> {
+                            self
+                        }
+                    }
 
                     impl StructuredPacket for $name {
                         const TYPE: PacketType = PacketType::$name;
@@ -216,6 +228,45 @@ macro_rules! define_packets {
                     )* )*
                 }
             }
+
+            /// Create a packet of this type with every field set to its
+            /// default value.
+            pub fn default_packet(self) -> Box<dyn AnyPacket> {
+                match self {
+                    $( $(
+                        PacketType::$name => Box::new($module::$name::default()),
+                    )* )*
+                }
+            }
+
+            /// Deserialize a structured packet of this type.
+            #[cfg(feature = "serde")]
+            pub fn deserialize_packet<'de, D: serde::Deserializer<'de>>(
+                self,
+                deserializer: D,
+            ) -> Result<Box<dyn AnyPacket>, D::Error> {
+                match self {
+                    $( $(
+                        PacketType::$name => {
+                            <$module::$name as serde::Deserialize>::deserialize(deserializer)
+                                .map(|p| -> Box<dyn AnyPacket> { Box::new(p) })
+                        },
+                    )* )*
+                }
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl serde::Serialize for dyn AnyPacket {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                match self.packet_type() {
+                    $( $(
+                        PacketType::$name => {
+                            self.downcast_ref::<$module::$name>().unwrap().serialize(serializer)
+                        },
+                    )* )*
+                }
+            }
         }
     };
 }
@@ -3,12 +3,21 @@
 
 use crate::adapters::{FromPacketBytes, PacketFormatError, ToPacketBytes};
 use crate::raw::RawPacket;
+use crate::PacketMappings;
 pub use definitions::{client, server, PacketType};
 use std::any::Any;
 use std::fmt::Debug;
 
 mod sealed {
-    pub trait Sealed {}
+    use std::any::Any;
+
+    /// Prevents the packet traits from being implemented outside this crate,
+    /// and provides the conversions used to downcast packets.
+    pub trait Sealed {
+        fn as_any(&self) -> &dyn Any;
+        fn as_any_mut(&mut self) -> &mut dyn Any;
+        fn into_any(self: Box<Self>) -> Box<dyn Any>;
+    }
 }
 
 /// A trait implemented by structured packet types.
@@ -21,12 +30,65 @@ where
 }
 
 /// A trait representing any structured packet type, supporting downcasting.
-pub trait AnyPacket: sealed::Sealed + Any + Debug {
+pub trait AnyPacket: sealed::Sealed + Any + Debug + Send {
     /// Get the type of this packet.
     fn packet_type(&self) -> PacketType;
 
     /// Convert this structured packet into a raw packet.
+    ///
+    /// The ID of the returned packet is the internal `PacketType`, rather than
+    /// a ROTMG packet ID. Use `into_raw_mapped` to produce a packet that can
+    /// be sent to the official client or server.
     fn into_raw(self: Box<Self>) -> Result<Box<RawPacket>, Box<PacketFormatError>>;
+
+    /// Convert this structured packet into a raw packet, using the ROTMG packet
+    /// ID from the given mappings.
+    fn into_raw_mapped(
+        self: Box<Self>,
+        mappings: &PacketMappings,
+    ) -> Result<Box<RawPacket>, Box<PacketFormatError>> {
+        let typ = self.packet_type();
+        let id = mappings
+            .to_game(typ)
+            .ok_or_else(|| Box::new(PacketFormatError::UnmappedType(typ)))?;
+
+        let mut packet = self.into_raw()?;
+        packet.set_id(id);
+        Ok(packet)
+    }
+}
+
+impl dyn AnyPacket {
+    /// Check whether this packet is of the given type.
+    pub fn is<T: StructuredPacket>(&self) -> bool {
+        self.packet_type() == T::TYPE
+    }
+
+    /// Get a reference to this packet as the given type, if it is of that
+    /// type.
+    pub fn downcast_ref<T: StructuredPacket>(&self) -> Option<&T> {
+        self.as_any().downcast_ref()
+    }
+
+    /// Get a mutable reference to this packet as the given type, if it is of
+    /// that type.
+    pub fn downcast_mut<T: StructuredPacket>(&mut self) -> Option<&mut T> {
+        self.as_any_mut().downcast_mut()
+    }
+
+    /// Convert this packet into the given type, returning the original packet
+    /// if it isn't of that type.
+    pub fn downcast<T: StructuredPacket>(self: Box<Self>) -> Result<Box<T>, Box<Self>> {
+        if self.as_any().is::<T>() {
+            // the type was just checked, so this can't fail
+            Ok(self
+                .into_any()
+                .downcast()
+                .unwrap_or_else(|_| unreachable!()))
+        } else {
+            Err(self)
+        }
+    }
 }
 
 mod definitions {
@@ -437,3 +499,34 @@ mod definitions {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::client::Pong;
+    use super::server::Ping;
+    use super::*;
+
+    #[test]
+    fn test_mapped_roundtrip() {
+        let mappings = PacketMappings::new(vec![(PacketType::Pong, 42)]);
+        let pong = Pong { serial: 1, time: 2 };
+
+        let raw = Box::new(pong.clone()).into_raw_mapped(&mappings).unwrap();
+        assert_eq!(raw.id(), 42);
+
+        let (parsed, remaining) = raw.parse(&mappings).unwrap();
+        assert!(remaining.is_empty());
+        assert!(parsed.downcast_ref::<Ping>().is_none());
+        assert_eq!(parsed.downcast_ref::<Pong>(), Some(&pong));
+        let parsed = parsed.downcast::<Ping>().unwrap_err();
+        assert_eq!(*parsed.downcast::<Pong>().unwrap(), pong);
+
+        let err = Box::new(Ping { serial: 1 })
+            .into_raw_mapped(&mappings)
+            .unwrap_err();
+        assert!(matches!(
+            *err,
+            PacketFormatError::UnmappedType(PacketType::Ping)
+        ));
+    }
+}
@@ -0,0 +1,242 @@
+//! Reading frames from pcap and pcapng files.
+
+use crate::PcapError;
+use log::warn;
+use std::convert::TryInto;
+use std::time::Duration;
+
+/// A single captured frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    /// The time the frame was captured, since the Unix epoch.
+    pub timestamp: Duration,
+
+    /// The link-layer header type of the interface the frame was captured on.
+    pub link_type: u32,
+
+    /// The captured data, which may be truncated if the snapshot length was
+    /// shorter than the frame.
+    pub data: &'a [u8],
+}
+
+/// A cursor over little or big endian data.
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], PcapError> {
+        if self.data.len() < n {
+            return Err(PcapError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, PcapError> {
+        let bytes = self.take(2)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&mut self) -> Result<u32, PcapError> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+}
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_OPTION_END: u16 = 0;
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+/// Read all frames from a pcap or pcapng file.
+pub fn read_frames(data: &[u8]) -> Result<Vec<Frame<'_>>, PcapError> {
+    if data.len() < 4 {
+        return Err(PcapError::UnknownFormat);
+    }
+
+    let magic = data[..4].try_into().unwrap();
+    if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+        return read_pcapng(data);
+    }
+
+    match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+        (PCAP_MAGIC_MICROS, _) => read_pcap(data, false, 1_000_000),
+        (PCAP_MAGIC_NANOS, _) => read_pcap(data, false, 1_000_000_000),
+        (_, PCAP_MAGIC_MICROS) => read_pcap(data, true, 1_000_000),
+        (_, PCAP_MAGIC_NANOS) => read_pcap(data, true, 1_000_000_000),
+        _ => Err(PcapError::UnknownFormat),
+    }
+}
+
+/// Convert a timestamp in units of `1 / units_per_sec` seconds to a duration
+fn timestamp(ts: u64, units_per_sec: u64) -> Duration {
+    let nanos = (ts % units_per_sec) as u128 * 1_000_000_000 / units_per_sec as u128;
+    Duration::new(ts / units_per_sec, nanos as u32)
+}
+
+fn read_pcap(
+    data: &[u8],
+    big_endian: bool,
+    units_per_sec: u64,
+) -> Result<Vec<Frame<'_>>, PcapError> {
+    let mut reader = Reader { data, big_endian };
+    reader.take(20)?;
+    let link_type = reader.u32()?;
+
+    let mut frames = Vec::new();
+    while !reader.data.is_empty() {
+        let record = (|| {
+            let secs = reader.u32()? as u64;
+            let frac = reader.u32()? as u64;
+            let len = reader.u32()? as usize;
+            reader.u32()?;
+            Ok(Frame {
+                timestamp: timestamp(secs * units_per_sec + frac, units_per_sec),
+                link_type,
+                data: reader.take(len)?,
+            })
+        })();
+
+        match record {
+            Ok(frame) => frames.push(frame),
+            Err(PcapError::Truncated) => {
+                // captures are often cut off if tcpdump is killed
+                warn!("Ignoring truncated record at end of capture");
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(frames)
+}
+
+/// An interface described in a pcapng file
+struct Interface {
+    link_type: u32,
+    units_per_sec: u64,
+}
+
+fn read_pcapng(data: &[u8]) -> Result<Vec<Frame<'_>>, PcapError> {
+    let mut interfaces = Vec::<Interface>::new();
+    let mut frames = Vec::new();
+    let mut big_endian = false;
+    let mut data = data;
+
+    while !data.is_empty() {
+        if data.len() < 12 {
+            warn!("Ignoring truncated block at end of capture");
+            break;
+        }
+
+        // the byte order of each section is given in its header
+        let typ = u32::from_le_bytes(data[..4].try_into().unwrap());
+        if typ == PCAPNG_SECTION_HEADER {
+            let magic = data[8..12].try_into().unwrap();
+            big_endian = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                (PCAPNG_BYTE_ORDER_MAGIC, _) => false,
+                (_, PCAPNG_BYTE_ORDER_MAGIC) => true,
+                _ => return Err(PcapError::InvalidBlock("invalid byte order magic")),
+            };
+            interfaces.clear();
+        }
+
+        let mut reader = Reader { data, big_endian };
+        let typ = reader.u32()?;
+        let len = reader.u32()? as usize;
+        if len < 12 || len % 4 != 0 {
+            return Err(PcapError::InvalidBlock("invalid block length"));
+        }
+        if len > data.len() {
+            warn!("Ignoring truncated block at end of capture");
+            break;
+        }
+
+        let mut body = Reader {
+            data: &data[8..len - 4],
+            big_endian,
+        };
+        data = &data[len..];
+
+        match typ {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let link_type = body.u16()? as u32;
+                body.take(6)?;
+                let mut units_per_sec = 1_000_000;
+
+                while body.data.len() >= 4 {
+                    let code = body.u16()?;
+                    let len = body.u16()? as usize;
+                    let value = body.take(len)?;
+                    body.take((4 - len % 4) % 4)?;
+
+                    match code {
+                        PCAPNG_OPTION_END => break,
+                        PCAPNG_OPTION_TSRESOL if len == 1 => {
+                            let resolution = value[0];
+                            units_per_sec = if resolution & 0x80 == 0 {
+                                10u64.checked_pow(resolution as u32)
+                            } else {
+                                1u64.checked_shl((resolution & 0x7f) as u32)
+                            }
+                            .ok_or(PcapError::InvalidBlock("invalid timestamp resolution"))?;
+                        }
+                        _ => {}
+                    }
+                }
+
+                interfaces.push(Interface {
+                    link_type,
+                    units_per_sec,
+                });
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                let interface = interfaces
+                    .get(body.u32()? as usize)
+                    .ok_or(PcapError::InvalidBlock("unknown interface"))?;
+                let high = body.u32()? as u64;
+                let low = body.u32()? as u64;
+                let len = body.u32()? as usize;
+                body.u32()?;
+
+                frames.push(Frame {
+                    timestamp: timestamp(high << 32 | low, interface.units_per_sec),
+                    link_type: interface.link_type,
+                    data: body.take(len)?,
+                });
+            }
+            PCAPNG_SIMPLE_PACKET => {
+                let interface = interfaces
+                    .first()
+                    .ok_or(PcapError::InvalidBlock("unknown interface"))?;
+                let len = (body.u32()? as usize).min(body.data.len());
+
+                // simple packets have no timestamp, so use the previous one
+                frames.push(Frame {
+                    timestamp: frames.last().map(|f| f.timestamp).unwrap_or_default(),
+                    link_type: interface.link_type,
+                    data: body.take(len)?,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(frames)
+}
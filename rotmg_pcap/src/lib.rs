@@ -0,0 +1,266 @@
+//! Decoding ROTMG sessions from pcap and pcapng captures.
+//!
+//! This allows traffic captured with tools such as tcpdump or Wireshark to be
+//! inspected without going through the proxy. TCP streams on the game port are
+//! reassembled, policy file exchanges are skipped, and each direction is
+//! decrypted using the RC4 keys from the `Parameters`.
+//!
+//! Since RC4 is a stream cipher, a session can only be decrypted if the capture
+//! includes the start of the connection. Connections which were already open
+//! when the capture started are ignored.
+
+pub mod file;
+pub mod net;
+pub mod reassembly;
+
+use crate::file::read_frames;
+use crate::net::{parse_segment, ACK, SYN};
+use crate::reassembly::Stream;
+use log::{debug, warn};
+use rotmg_networking::rc4::Rc4;
+use rotmg_networking::{ConnectionConfig, PolicyFile};
+use rotmg_packets::adapters::PacketFormatError;
+use rotmg_packets::raw::RawPacket;
+use rotmg_packets::structured::packets::AnyPacket;
+use rotmg_packets::{PacketMappings, Parameters};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An error reading a capture.
+#[derive(Debug, thiserror::Error)]
+pub enum PcapError {
+    /// An error reading the capture file.
+    #[error("Error reading capture: {0}")]
+    Io(#[from] io::Error),
+
+    /// The file isn't a pcap or pcapng capture.
+    #[error("Unrecognized capture format")]
+    UnknownFormat,
+
+    /// The capture ended in the middle of a header.
+    #[error("Capture is truncated")]
+    Truncated,
+
+    /// A pcapng block was invalid.
+    #[error("Invalid pcapng block: {0}")]
+    InvalidBlock(&'static str),
+
+    /// The RC4 keys in the parameters weren't valid hexadecimal.
+    #[error("Invalid RC4 keys: {0}")]
+    InvalidKeys(#[from] hex::FromHexError),
+
+    /// The RC4 keys didn't contain two keys of 1 to 256 bytes each.
+    #[error("Invalid RC4 key length of {0} bytes")]
+    InvalidKeyLength(usize),
+}
+
+/// Which end of a connection sent a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sender {
+    /// The packet was sent by the client to the server.
+    Client,
+
+    /// The packet was sent by the server to the client.
+    Server,
+}
+
+/// A decrypted packet from a session.
+#[derive(Debug, Clone)]
+pub struct SessionPacket {
+    /// The time the last byte of the packet was captured.
+    pub time: SystemTime,
+
+    /// Which end of the connection sent the packet.
+    pub sender: Sender,
+
+    /// The decrypted packet.
+    pub packet: Box<RawPacket>,
+}
+
+/// A single ROTMG connection found in a capture.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// The client's address.
+    pub client: SocketAddr,
+
+    /// The server's address.
+    pub server: SocketAddr,
+
+    /// All complete packets sent in both directions, in the order they were
+    /// captured.
+    pub packets: Vec<SessionPacket>,
+}
+
+impl Session {
+    /// Parse each packet in this session using the given mappings.
+    #[allow(clippy::type_complexity)]
+    pub fn parsed<'a>(
+        &'a self,
+        mappings: &'a PacketMappings,
+    ) -> impl Iterator<
+        Item = (
+            &'a SessionPacket,
+            Result<Box<dyn AnyPacket>, Box<PacketFormatError>>,
+        ),
+    > + 'a {
+        self.packets
+            .iter()
+            .map(move |p| (p, p.packet.parse(mappings).map(|(packet, _)| packet)))
+    }
+}
+
+/// A TCP connection to the game port
+#[derive(Default)]
+struct Connection {
+    to_server: Stream,
+    to_client: Stream,
+}
+
+/// Read all ROTMG sessions from a capture file, using the port and RC4 keys
+/// from the given parameters.
+pub fn read_sessions_file(
+    path: impl AsRef<Path>,
+    params: &Parameters,
+) -> Result<Vec<Session>, PcapError> {
+    read_sessions(&std::fs::read(path)?, params)
+}
+
+/// Read all ROTMG sessions from a capture, using the port and RC4 keys from the
+/// given parameters.
+pub fn read_sessions(capture: &[u8], params: &Parameters) -> Result<Vec<Session>, PcapError> {
+    let keys = hex::decode(&params.rc4)?;
+    decode_sessions(capture, &keys, params.basic.port)
+}
+
+/// Read all ROTMG sessions on the given port from a capture, using the given
+/// raw RC4 keys.
+pub fn decode_sessions(capture: &[u8], keys: &[u8], port: u16) -> Result<Vec<Session>, PcapError> {
+    // the keys for each direction are the two halves of the given keys
+    if keys.len() < 2 || keys.len() > 512 {
+        return Err(PcapError::InvalidKeyLength(keys.len()));
+    }
+
+    let mut connections = Vec::<((SocketAddr, SocketAddr), Connection)>::new();
+    let mut open = HashMap::<(SocketAddr, SocketAddr), usize>::new();
+
+    for frame in read_frames(capture)? {
+        let segment = match parse_segment(frame.link_type, frame.data) {
+            Some(segment) => segment,
+            None => continue,
+        };
+
+        let key = if segment.dst.port() == port {
+            (segment.src, segment.dst)
+        } else if segment.src.port() == port {
+            (segment.dst, segment.src)
+        } else {
+            continue;
+        };
+
+        // a SYN from the client always starts a new connection, since ports
+        // can be reused
+        if segment.flags & (SYN | ACK) == SYN && segment.src == key.0 {
+            open.insert(key, connections.len());
+            connections.push((key, Connection::default()));
+        }
+
+        let conn = match open.get(&key) {
+            Some(&i) => &mut connections[i].1,
+            None => continue,
+        };
+        let stream = if segment.src == key.0 {
+            &mut conn.to_server
+        } else {
+            &mut conn.to_client
+        };
+
+        if segment.flags & SYN != 0 {
+            stream.syn(segment.seq);
+        } else {
+            stream.add_segment(segment.seq, segment.payload, frame.timestamp);
+        }
+    }
+
+    let mut sessions = Vec::new();
+    for ((client, server), conn) in connections {
+        if conn
+            .to_server
+            .data()
+            .starts_with(PolicyFile::REQUEST_MESSAGE)
+        {
+            debug!("Skipping policy file request from {}", client);
+            continue;
+        }
+        if !conn.to_client.is_started() {
+            debug!("Skipping connection from {} with no SYN-ACK", client);
+            continue;
+        }
+
+        let (key0, key1) = keys.split_at(keys.len() / 2);
+        let mut packets = Vec::new();
+        decode_stream(
+            &conn.to_server,
+            Rc4::new(key0),
+            Sender::Client,
+            &mut packets,
+        );
+        decode_stream(
+            &conn.to_client,
+            Rc4::new(key1),
+            Sender::Server,
+            &mut packets,
+        );
+        packets.sort_by_key(|p| p.time);
+
+        sessions.push(Session {
+            client,
+            server,
+            packets,
+        });
+    }
+
+    Ok(sessions)
+}
+
+/// Split one direction of a connection into packets and decrypt them
+fn decode_stream(stream: &Stream, mut cipher: Rc4, sender: Sender, out: &mut Vec<SessionPacket>) {
+    let data = stream.data();
+    let mut pos = 0;
+
+    while data.len() - pos >= 4 {
+        let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap());
+        if !(5..=ConnectionConfig::DEFAULT_MAX_PACKET_SIZE).contains(&len) {
+            warn!("Invalid packet length {} from {:?}, stopping", len, sender);
+            return;
+        }
+
+        let len = len as usize;
+        if data.len() - pos < len {
+            break;
+        }
+
+        let bytes = data[pos..pos + len].to_vec().into_boxed_slice();
+        let mut packet = RawPacket::from_box(bytes).unwrap();
+        cipher.process(packet.payload_mut());
+
+        let time = stream.time_at(pos + len - 1).unwrap_or_default();
+        out.push(SessionPacket {
+            time: UNIX_EPOCH + time,
+            sender,
+            packet,
+        });
+        pos += len;
+    }
+
+    if pos < data.len() || stream.pending_len() > 0 {
+        debug!(
+            "Ignoring {} bytes of incomplete data from {:?}",
+            data.len() - pos + stream.pending_len(),
+            sender
+        );
+    }
+}
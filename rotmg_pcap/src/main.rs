@@ -0,0 +1,78 @@
+use anyhow::Context;
+use env_logger::Env;
+use rotmg_packets::Parameters;
+use rotmg_pcap::{read_sessions_file, Sender};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use structopt::StructOpt;
+
+/// Decode ROTMG sessions from a pcap or pcapng capture.
+#[derive(StructOpt)]
+struct Opts {
+    /// The capture file to read.
+    capture: PathBuf,
+
+    /// The client parameters to decode packets with, as saved by the proxy in
+    /// its data directory.
+    #[structopt(long)]
+    params: PathBuf,
+
+    /// Look for sessions on the given port instead of the one in the
+    /// parameters.
+    #[structopt(short, long)]
+    port: Option<u16>,
+
+    /// Print raw packet bytes instead of parsing packets.
+    #[structopt(long)]
+    raw: bool,
+}
+
+/// Load parameters from either a bare `Parameters` file, or the data file saved
+/// by the proxy
+fn load_params(path: &Path) -> anyhow::Result<Parameters> {
+    let mut json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(path).context("reading parameters")?)
+            .context("parsing parameters")?;
+    if let Some(params) = json.get_mut("params") {
+        json = params.take();
+    }
+    serde_json::from_value(json).context("parsing parameters")
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init_from_env(Env::new().default_filter_or("rotmg_pcap=INFO"));
+    let opts = Opts::from_args();
+
+    let mut params = load_params(&opts.params)?;
+    if let Some(port) = opts.port {
+        params.basic.port = port;
+    }
+
+    let sessions = read_sessions_file(&opts.capture, &params).context("reading capture")?;
+    for session in &sessions {
+        println!("Session from {} to {}", session.client, session.server);
+
+        let start = session.packets.first().map_or(UNIX_EPOCH, |p| p.time);
+        for (packet, parsed) in session.parsed(&params.packets) {
+            let time = packet.time.duration_since(start).unwrap_or_default();
+            let sender = match packet.sender {
+                Sender::Client => "C->S",
+                Sender::Server => "S->C",
+            };
+
+            match parsed {
+                Ok(parsed) if !opts.raw => {
+                    println!("[{:>9.3}] {} {:?}", time.as_secs_f64(), sender, parsed)
+                }
+                _ => println!(
+                    "[{:>9.3}] {} {:?}",
+                    time.as_secs_f64(),
+                    sender,
+                    packet.packet
+                ),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! Parsing TCP segments from captured frames.
+
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// BSD loopback encapsulation.
+const LINKTYPE_NULL: u32 = 0;
+/// Ethernet.
+const LINKTYPE_ETHERNET: u32 = 1;
+/// Raw IPv4 or IPv6.
+const LINKTYPE_RAW: u32 = 101;
+/// Linux "cooked" capture, used when capturing on all interfaces.
+const LINKTYPE_LINUX_SLL: u32 = 113;
+/// Linux "cooked" capture, version 2.
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const IP_PROTOCOL_TCP: u8 = 6;
+
+/// The TCP FIN flag.
+pub const FIN: u8 = 0x01;
+/// The TCP SYN flag.
+pub const SYN: u8 = 0x02;
+/// The TCP RST flag.
+pub const RST: u8 = 0x04;
+/// The TCP ACK flag.
+pub const ACK: u8 = 0x10;
+
+/// A TCP segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment<'a> {
+    /// The sender's address.
+    pub src: SocketAddr,
+
+    /// The receiver's address.
+    pub dst: SocketAddr,
+
+    /// The sequence number of the first byte of the payload.
+    pub seq: u32,
+
+    /// The TCP flags.
+    pub flags: u8,
+
+    /// The segment payload.
+    pub payload: &'a [u8],
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+/// Parse the TCP segment contained in a frame with the given link type.
+///
+/// Returns `None` if the frame doesn't contain a TCP segment, if the link type
+/// isn't supported, or if the segment is a fragment or is truncated.
+pub fn parse_segment(link_type: u32, data: &[u8]) -> Option<Segment<'_>> {
+    let (ethertype, ip) = match link_type {
+        LINKTYPE_NULL => {
+            // the address family is in host byte order, which isn't specified
+            let family = u32::from_le_bytes(data.get(..4)?.try_into().unwrap());
+            let family = if family > 0xffff {
+                family.swap_bytes()
+            } else {
+                family
+            };
+            match family {
+                2 => (ETHERTYPE_IPV4, &data[4..]),
+                24 | 28 | 30 => (ETHERTYPE_IPV6, &data[4..]),
+                _ => return None,
+            }
+        }
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ethertype = u16_at(data, offset)?;
+            while ethertype == ETHERTYPE_VLAN {
+                offset += 4;
+                ethertype = u16_at(data, offset)?;
+            }
+            (ethertype, data.get(offset + 2..)?)
+        }
+        LINKTYPE_RAW => match data.first()? >> 4 {
+            4 => (ETHERTYPE_IPV4, data),
+            6 => (ETHERTYPE_IPV6, data),
+            _ => return None,
+        },
+        LINKTYPE_LINUX_SLL => (u16_at(data, 14)?, data.get(16..)?),
+        LINKTYPE_LINUX_SLL2 => (u16_at(data, 0)?, data.get(20..)?),
+        _ => return None,
+    };
+
+    let (src, dst, tcp) = match ethertype {
+        ETHERTYPE_IPV4 => {
+            let header_len = ((ip.first()? & 0x0f) as usize) * 4;
+            let total_len = u16_at(ip, 2)? as usize;
+            let fragment = u16_at(ip, 6)?;
+            // ignore fragments, which are very rare for TCP
+            if fragment & 0x3fff != 0 || *ip.get(9)? != IP_PROTOCOL_TCP {
+                return None;
+            }
+
+            let src: [u8; 4] = ip.get(12..16)?.try_into().unwrap();
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().unwrap();
+            (
+                IpAddr::from(Ipv4Addr::from(src)),
+                IpAddr::from(Ipv4Addr::from(dst)),
+                // trim any link-layer padding
+                ip.get(header_len..total_len)?,
+            )
+        }
+        ETHERTYPE_IPV6 => {
+            // extension headers aren't supported
+            if *ip.get(6)? != IP_PROTOCOL_TCP {
+                return None;
+            }
+
+            let payload_len = u16_at(ip, 4)? as usize;
+            let src: [u8; 16] = ip.get(8..24)?.try_into().unwrap();
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().unwrap();
+            (
+                IpAddr::from(Ipv6Addr::from(src)),
+                IpAddr::from(Ipv6Addr::from(dst)),
+                ip.get(40..40 + payload_len)?,
+            )
+        }
+        _ => return None,
+    };
+
+    let header_len = ((tcp.get(12)? >> 4) as usize) * 4;
+    Some(Segment {
+        src: SocketAddr::new(src, u16_at(tcp, 0)?),
+        dst: SocketAddr::new(dst, u16_at(tcp, 2)?),
+        seq: u32::from_be_bytes(tcp.get(4..8)?.try_into().unwrap()),
+        flags: *tcp.get(13)?,
+        payload: tcp.get(header_len..)?,
+    })
+}
@@ -0,0 +1,133 @@
+//! Reassembly of TCP streams from individual segments.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// One direction of a TCP connection, reassembled from segments.
+#[derive(Debug, Default)]
+pub struct Stream {
+    /// The sequence number of the first byte of data, once the SYN is seen.
+    base: Option<u32>,
+
+    /// The contiguous data received so far.
+    data: Vec<u8>,
+
+    /// The end offset of each chunk of data, and the time it became available.
+    times: Vec<(usize, Duration)>,
+
+    /// Segments received after a gap in the data, by offset.
+    pending: BTreeMap<usize, Vec<u8>>,
+}
+
+impl Stream {
+    /// Handle a SYN segment, which marks the start of the stream.
+    pub fn syn(&mut self, seq: u32) {
+        self.base = Some(seq.wrapping_add(1));
+    }
+
+    /// Check whether the start of this stream has been seen.
+    pub fn is_started(&self) -> bool {
+        self.base.is_some()
+    }
+
+    /// Add a segment to the stream.
+    ///
+    /// Segments can be added in any order, and retransmitted data is ignored.
+    /// Segments are ignored until the SYN is seen, since the offset of the data
+    /// in the stream isn't known.
+    pub fn add_segment(&mut self, seq: u32, payload: &[u8], time: Duration) {
+        let base = match self.base {
+            Some(base) => base,
+            None => return,
+        };
+
+        // sequence numbers wrap around, so anything in the upper half of the
+        // sequence space relative to the start is before the start
+        let mut payload = payload;
+        let mut offset = seq.wrapping_sub(base) as usize;
+        if offset >= 1 << 31 {
+            let skip = base.wrapping_sub(seq) as usize;
+            if skip >= payload.len() {
+                return;
+            }
+            payload = &payload[skip..];
+            offset = 0;
+        }
+        if payload.is_empty() {
+            return;
+        }
+
+        let existing = self.pending.get(&offset).map_or(0, |p| p.len());
+        if payload.len() > existing {
+            self.pending.insert(offset, payload.to_vec());
+        }
+
+        // data after a gap only becomes available once the gap is filled, so
+        // it's all timestamped with the time of this segment
+        while let Some((&offset, _)) = self.pending.iter().next() {
+            if offset > self.data.len() {
+                break;
+            }
+
+            let payload = self.pending.remove(&offset).unwrap();
+            if offset + payload.len() > self.data.len() {
+                self.data
+                    .extend_from_slice(&payload[self.data.len() - offset..]);
+                self.times.push((self.data.len(), time));
+            }
+        }
+    }
+
+    /// Get the contiguous data received so far.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Get the number of bytes received after a gap in the data, which can't
+    /// be used until the missing data is received.
+    pub fn pending_len(&self) -> usize {
+        self.pending.values().map(Vec::len).sum()
+    }
+
+    /// Get the time at which the given byte of the stream became available.
+    pub fn time_at(&self, offset: usize) -> Option<Duration> {
+        // find the first chunk ending after the given offset
+        let i = match self.times.binary_search_by(|&(end, _)| end.cmp(&offset)) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        self.times.get(i).map(|&(_, time)| time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stream;
+    use std::time::Duration;
+
+    #[test]
+    fn test_reassembly() {
+        let mut stream = Stream::default();
+        let t = Duration::from_secs;
+
+        // data before the SYN is ignored
+        stream.add_segment(0, b"ignored", t(0));
+        stream.syn(u32::MAX - 2);
+        stream.add_segment(u32::MAX - 1, b"abc", t(1));
+
+        // out of order, wrapping around, with a retransmission
+        stream.add_segment(4, b"ghi", t(2));
+        assert_eq!(stream.pending_len(), 3);
+        stream.add_segment(1, b"def", t(3));
+        stream.add_segment(u32::MAX - 1, b"abcd", t(4));
+        stream.add_segment(1, b"def", t(5));
+
+        assert_eq!(stream.data(), b"abcdefghi");
+        assert_eq!(stream.pending_len(), 0);
+        assert_eq!(stream.time_at(0), Some(t(1)));
+        assert_eq!(stream.time_at(5), Some(t(3)));
+        assert_eq!(stream.time_at(6), Some(t(3)));
+        assert_eq!(stream.time_at(8), Some(t(3)));
+        assert_eq!(stream.time_at(9), None);
+    }
+}
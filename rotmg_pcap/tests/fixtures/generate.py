@@ -0,0 +1,183 @@
+#!/usr/bin/env python3
+"""Generate the fixture captures used by the rotmg_pcap tests.
+
+Both files contain the same traffic: a policy file exchange and a short game
+session on port 2050, along with unrelated traffic which should be ignored.
+The game session includes packets split across segments, multiple packets in
+one segment, out of order segments and retransmissions.
+
+Packets use ID 8 for Ping and 9 for Pong, and are encrypted using the RC4 keys
+0102030405060708090a.
+"""
+
+import struct
+from pathlib import Path
+
+KEYS = bytes.fromhex("0102030405060708090a")
+CLIENT = (bytes([10, 0, 0, 1]), bytes([2, 0, 0, 0, 0, 1]))
+SERVER = (bytes([10, 0, 0, 2]), bytes([2, 0, 0, 0, 0, 2]))
+PORT = 2050
+START = 1590000000.0
+
+POLICY_REQUEST = b"<policy-file-request/>\0"
+POLICY_RESPONSE = b'<cross-domain-policy><allow-access-from domain="*" to-ports="*" /></cross-domain-policy>'
+
+
+class Rc4:
+    def __init__(self, key):
+        self.s = list(range(256))
+        j = 0
+        for i in range(256):
+            j = (j + self.s[i] + key[i % len(key)]) % 256
+            self.s[i], self.s[j] = self.s[j], self.s[i]
+        self.i = self.j = 0
+
+    def process(self, data):
+        out = bytearray()
+        for b in data:
+            self.i = (self.i + 1) % 256
+            self.j = (self.j + self.s[self.i]) % 256
+            self.s[self.i], self.s[self.j] = self.s[self.j], self.s[self.i]
+            out.append(b ^ self.s[(self.s[self.i] + self.s[self.j]) % 256])
+        return bytes(out)
+
+
+def checksum(data):
+    if len(data) % 2:
+        data += b"\0"
+    total = sum(struct.unpack("!%dH" % (len(data) // 2), data))
+    while total >> 16:
+        total = (total & 0xFFFF) + (total >> 16)
+    return ~total & 0xFFFF
+
+
+def frame(src, dst, sport, dport, seq, ack, flags, payload=b"", proto=6):
+    if proto == 6:
+        seq, ack = seq & 0xFFFFFFFF, ack & 0xFFFFFFFF
+        l4 = struct.pack("!HHIIBBHHH", sport, dport, seq, ack, 5 << 4, flags, 65535, 0, 0)
+    else:
+        l4 = struct.pack("!HHHH", sport, dport, 8 + len(payload), 0)
+    l4 += payload
+    ip = struct.pack("!BBHHHBBH4s4s", 0x45, 0, 20 + len(l4), 0, 0x4000, 64, proto, 0, src[0], dst[0])
+    ip = ip[:10] + struct.pack("!H", checksum(ip)) + ip[12:]
+    return dst[1] + src[1] + b"\x08\x00" + ip + l4
+
+
+SYN, FIN, PSH, ACK = 0x02, 0x01, 0x08, 0x10
+
+
+class Connection:
+    def __init__(self, frames, sport, client_isn, server_isn, dport=PORT):
+        self.frames = frames
+        self.sport = sport
+        self.dport = dport
+        self.seq = {"client": client_isn, "server": server_isn}
+
+    def send(self, t, sender, payload=b"", flags=PSH | ACK, seq=None, advance=True):
+        src, dst = (CLIENT, SERVER) if sender == "client" else (SERVER, CLIENT)
+        sport, dport = (self.sport, self.dport) if sender == "client" else (self.dport, self.sport)
+        other = "server" if sender == "client" else "client"
+        start = self.seq[sender] if seq is None else seq
+        self.frames.append((t, frame(src, dst, sport, dport, start, self.seq[other], flags, payload)))
+        if advance:
+            self.seq[sender] = start + len(payload) + (1 if flags & (SYN | FIN) else 0)
+        return start
+
+    def handshake(self, t):
+        self.send(t, "client", flags=SYN)
+        self.send(t + 0.001, "server", flags=SYN | ACK)
+        self.send(t + 0.002, "client", flags=ACK)
+
+    def close(self, t):
+        self.send(t, "client", flags=FIN | ACK)
+        self.send(t + 0.001, "server", flags=FIN | ACK)
+        self.send(t + 0.002, "client", flags=ACK)
+
+
+def packet(id, payload):
+    return struct.pack("!IB", 5 + len(payload), id) + payload
+
+
+def generate():
+    frames = []
+
+    # unrelated traffic, which should be ignored
+    frames.append((START, frame(CLIENT, SERVER, 40000, 53, 0, 0, 0, b"dns?", proto=17)))
+    web = Connection(frames, 40001, 100, 200, dport=80)
+    web.handshake(START + 0.01)
+    web.send(START + 0.02, "client", b"GET / HTTP/1.1\r\n\r\n")
+
+    # policy file exchange
+    policy = Connection(frames, 50000, 1000, 2000)
+    policy.handshake(START + 0.1)
+    policy.send(START + 0.11, "client", POLICY_REQUEST)
+    policy.send(START + 0.12, "server", POLICY_RESPONSE)
+    policy.close(START + 0.13)
+
+    # game session, with sequence numbers that wrap around
+    game = Connection(frames, 50001, 0xFFFFFFF0, 3000)
+    game.handshake(START + 1.0)
+    client_rc4, server_rc4 = Rc4(KEYS[:5]), Rc4(KEYS[5:])
+
+    def encrypt(rc4, id, payload):
+        raw = packet(id, payload)
+        return raw[:5] + rc4.process(raw[5:])
+
+    ping1 = encrypt(server_rc4, 8, struct.pack("!I", 1))
+    pong1 = encrypt(client_rc4, 9, struct.pack("!II", 1, 100))
+    ping2 = encrypt(server_rc4, 8, struct.pack("!I", 2))
+    ping3 = encrypt(server_rc4, 8, struct.pack("!I", 3))
+    pong2 = encrypt(client_rc4, 9, struct.pack("!II", 2, 200))
+
+    game.send(START + 1.1, "server", ping1)
+    game.send(START + 1.2, "client", pong1)
+
+    # ping 2 split across segments, with the second also containing ping 3
+    game.send(START + 1.3, "server", ping2[:3])
+    game.send(START + 1.31, "server", ping2[3:] + ping3)
+
+    # pong 2 arrives out of order, and the first half is retransmitted
+    first = game.seq["client"]
+    game.send(START + 1.41, "client", pong2[6:], seq=first + 6, advance=False)
+    game.send(START + 1.42, "client", pong2[:6], seq=first, advance=False)
+    game.send(START + 1.43, "client", pong2[:6], seq=first)
+    game.seq["client"] = first + len(pong2)
+
+    game.close(START + 1.5)
+
+    frames.sort(key=lambda f: f[0])
+    return frames
+
+
+def write_pcap(path, frames):
+    out = struct.pack("<IHHiIII", 0xA1B2C3D4, 2, 4, 0, 0, 65535, 1)
+    for t, data in frames:
+        micros = round(t * 1e6)
+        out += struct.pack("<IIII", micros // 10**6, micros % 10**6, len(data), len(data))
+        out += data
+    path.write_bytes(out)
+
+
+def block(typ, body):
+    body += b"\0" * (-len(body) % 4)
+    length = 12 + len(body)
+    return struct.pack("<II", typ, length) + body + struct.pack("<I", length)
+
+
+def write_pcapng(path, frames):
+    out = block(0x0A0D0D0A, struct.pack("<IHHq", 0x1A2B3C4D, 1, 0, -1))
+    # interface with nanosecond timestamps (if_tsresol = 9)
+    options = struct.pack("<HHB", 9, 1, 9) + b"\0" * 3 + struct.pack("<HH", 0, 0)
+    out += block(1, struct.pack("<HHI", 1, 0, 65535) + options)
+    for t, data in frames:
+        nanos = round(t * 1e6) * 1000
+        body = struct.pack("<IIIII", 0, nanos >> 32, nanos & 0xFFFFFFFF, len(data), len(data))
+        out += block(6, body + data)
+    path.write_bytes(out)
+
+
+if __name__ == "__main__":
+    here = Path(__file__).parent
+    frames = generate()
+    write_pcap(here / "session.pcap", frames)
+    write_pcapng(here / "session.pcapng", frames)
@@ -0,0 +1,103 @@
+use rotmg_packets::structured::packets::client::Pong;
+use rotmg_packets::structured::packets::server::Ping;
+use rotmg_packets::structured::packets::PacketType;
+use rotmg_packets::{BasicParameters, PacketMappings, Parameters};
+use rotmg_pcap::{read_sessions, PcapError, Sender, Session};
+use std::time::{Duration, UNIX_EPOCH};
+
+// see fixtures/generate.py for the contents of these captures
+const PCAP: &[u8] = include_bytes!("fixtures/session.pcap");
+const PCAPNG: &[u8] = include_bytes!("fixtures/session.pcapng");
+
+fn params() -> Parameters {
+    Parameters {
+        packets: PacketMappings::new(vec![(PacketType::Ping, 8), (PacketType::Pong, 9)]),
+        rc4: "0102030405060708090a".to_string(),
+        basic: BasicParameters {
+            version: "1.0".to_string(),
+            port: 2050,
+            tutorial_game_id: -1,
+            nexus_game_id: -2,
+            random_game_id: -3,
+        },
+    }
+}
+
+fn check_session(sessions: &[Session]) {
+    // the policy file exchange should be skipped
+    assert_eq!(sessions.len(), 1);
+    let session = &sessions[0];
+    assert_eq!(session.client, "10.0.0.1:50001".parse().unwrap());
+    assert_eq!(session.server, "10.0.0.2:2050".parse().unwrap());
+
+    // packets are compared by their debug representation, since they can't
+    // be downcast from trait objects
+    let mappings = params().packets;
+    let packets = session
+        .parsed(&mappings)
+        .map(|(p, parsed)| (p.sender, format!("{:?}", parsed.unwrap())))
+        .collect::<Vec<_>>();
+    let expected = vec![
+        (Sender::Server, format!("{:?}", Ping { serial: 1 })),
+        (
+            Sender::Client,
+            format!(
+                "{:?}",
+                Pong {
+                    serial: 1,
+                    time: 100
+                }
+            ),
+        ),
+        (Sender::Server, format!("{:?}", Ping { serial: 2 })),
+        (Sender::Server, format!("{:?}", Ping { serial: 3 })),
+        (
+            Sender::Client,
+            format!(
+                "{:?}",
+                Pong {
+                    serial: 2,
+                    time: 200
+                }
+            ),
+        ),
+    ];
+    assert_eq!(packets, expected);
+
+    // packets are timestamped with when they were completely received
+    let time = |i: usize| {
+        session.packets[i]
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    };
+    let start = Duration::from_secs(1_590_000_000).as_millis();
+    assert_eq!(time(0) - start, 1100);
+    assert_eq!(time(2) - start, 1310);
+    assert_eq!(time(4) - start, 1420);
+}
+
+#[test]
+fn test_pcap() {
+    check_session(&read_sessions(PCAP, &params()).unwrap());
+}
+
+#[test]
+fn test_pcapng() {
+    check_session(&read_sessions(PCAPNG, &params()).unwrap());
+}
+
+#[test]
+fn test_invalid_capture() {
+    assert!(read_sessions(b"not a capture", &params()).is_err());
+
+    let no_keys = Parameters {
+        rc4: String::new(),
+        ..params()
+    };
+    assert!(matches!(
+        read_sessions(PCAP, &no_keys),
+        Err(PcapError::InvalidKeyLength(0))
+    ));
+}
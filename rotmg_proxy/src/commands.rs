@@ -0,0 +1,216 @@
+//! Chat commands which players can use to control the proxy
+//!
+//! Commands are sent as chat messages starting with the command prefix, e.g.
+//! `/proxy server USWest`. They're handled by the proxy and never forwarded to
+//! the server.
+
+use crate::plugin::{parse_packet_type, Session};
+use crate::proxy::Proxy;
+use anyhow::{bail, Context};
+use rotmg_networking::stats::ConnectionMetrics;
+use rotmg_packets::structured::packets::server::Text;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+/// Everything a command can use to handle a message
+pub struct CommandContext<'a> {
+    pub proxy: &'a Proxy,
+    pub session: &'a mut Session,
+    pub metrics: &'a ConnectionMetrics,
+
+    /// The address the client reached the proxy on
+    pub local_addr: SocketAddr,
+}
+
+/// A function handling a command, given its arguments, which returns a message
+/// for the player
+type Handler = Box<dyn Fn(&mut CommandContext, &[&str]) -> anyhow::Result<String> + Send + Sync>;
+
+struct Command {
+    usage: String,
+    handler: Handler,
+}
+
+/// The commands players can use, by name
+pub struct Commands {
+    prefix: String,
+    commands: BTreeMap<String, Command>,
+}
+
+impl Commands {
+    /// Create a set of commands triggered by the given prefix, with the
+    /// built-in commands registered
+    pub fn new(prefix: impl Into<String>) -> Self {
+        let mut commands = Self {
+            prefix: prefix.into(),
+            commands: BTreeMap::new(),
+        };
+        commands.register("help", "help", help);
+        commands.register("server", "server <name or abbreviation>", server);
+        commands.register("plugins", "plugins", plugins);
+        commands.register("log", "log [off | <packet types>]", log);
+        commands.register("stats", "stats", stats);
+        commands
+    }
+
+    /// Register a command, replacing any existing command with the same name
+    ///
+    /// The usage should start with the name of the command, and is shown by
+    /// the `help` command.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        usage: impl Into<String>,
+        handler: impl Fn(&mut CommandContext, &[&str]) -> anyhow::Result<String> + Send + Sync + 'static,
+    ) {
+        let command = Command {
+            usage: usage.into(),
+            handler: Box::new(handler),
+        };
+        self.commands.insert(name.into(), command);
+    }
+
+    /// Handle a chat message if it's a command, returning whether it was
+    ///
+    /// The reply to the command is queued to be sent to the client.
+    pub fn handle(&self, ctx: &mut CommandContext, message: &str) -> bool {
+        let (name, args) = match parse_command(&self.prefix, message) {
+            Some(command) => command,
+            None => return false,
+        };
+        let result = match self.commands.get(name) {
+            Some(command) => (command.handler)(ctx, &args),
+            None => Err(anyhow::anyhow!(
+                "Unknown command {}, try {} help",
+                name,
+                self.prefix
+            )),
+        };
+
+        let reply = match result {
+            Ok(message) => reply("", message),
+            Err(e) => reply("*Error*", format!("{:#}", e)),
+        };
+        ctx.session.send_to_client(reply);
+        true
+    }
+}
+
+/// Split a chat message into a command name and arguments, if it starts with
+/// the prefix
+///
+/// A message that's just the prefix runs the `help` command.
+fn parse_command<'a>(prefix: &str, message: &'a str) -> Option<(&'a str, Vec<&'a str>)> {
+    if !message.starts_with(prefix) {
+        return None;
+    }
+    let rest = &message[prefix.len()..];
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+
+    let mut words = rest.split_whitespace();
+    let name = words.next().unwrap_or("help");
+    Some((name, words.collect()))
+}
+
+/// Create a chat message to show the player, from the given sender
+pub fn reply(name: &str, text: String) -> Text {
+    Text {
+        name: name.to_string(),
+        // no stars shows the message without a rank icon
+        num_stars: u32::MAX,
+        text,
+        ..Text::default()
+    }
+}
+
+fn help(ctx: &mut CommandContext, _args: &[&str]) -> anyhow::Result<String> {
+    let commands = ctx.proxy.commands();
+    let usages = commands
+        .commands
+        .values()
+        .map(|c| format!("{} {}", commands.prefix, c.usage))
+        .collect::<Vec<_>>();
+    Ok(format!("Commands: {}", usages.join(", ")))
+}
+
+fn server(ctx: &mut CommandContext, args: &[&str]) -> anyhow::Result<String> {
+    let name = match args {
+        [name] => name,
+        _ => bail!("Usage: server <name>"),
+    };
+    let server = ctx
+        .proxy
+        .servers()
+        .get(name)
+        .with_context(|| format!("Unknown server {}", name))?;
+
+    ctx.proxy.switch_server(ctx.session, server, ctx.local_addr);
+    Ok(format!("Switching to {}", server.name))
+}
+
+fn plugins(ctx: &mut CommandContext, _args: &[&str]) -> anyhow::Result<String> {
+    let names = ctx.proxy.plugins().names().collect::<Vec<_>>();
+    if names.is_empty() {
+        Ok("No plugins are loaded".to_string())
+    } else {
+        Ok(format!("Plugins: {}", names.join(", ")))
+    }
+}
+
+fn log(ctx: &mut CommandContext, args: &[&str]) -> anyhow::Result<String> {
+    let logger = ctx.proxy.logger();
+    match args {
+        [] => {}
+        ["off"] => logger.set_types(Vec::new()),
+        types => {
+            let types = types
+                .iter()
+                .map(|t| parse_packet_type(t))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(anyhow::Error::msg)?;
+            logger.set_types(types);
+        }
+    }
+
+    let mut types = logger.types().iter().map(|t| t.name()).collect::<Vec<_>>();
+    if types.is_empty() {
+        return Ok("Not logging any packets".to_string());
+    }
+    types.sort();
+    Ok(format!("Logging packets: {}", types.join(", ")))
+}
+
+fn stats(ctx: &mut CommandContext, _args: &[&str]) -> anyhow::Result<String> {
+    let stats = ctx.metrics.snapshot();
+    // the metrics are attached to the client's connection, so inbound packets
+    // came from the client
+    let mut message = format!(
+        "{} packets ({} bytes) from client, {} packets ({} bytes) to client",
+        stats.inbound.packets, stats.inbound.bytes, stats.outbound.packets, stats.outbound.bytes
+    );
+    if let Some(latency) = stats.average_latency {
+        message += &format!(", average latency {}ms", latency.as_millis());
+    }
+    if stats.parse_failures > 0 {
+        message += &format!(", {} packets failed to parse", stats.parse_failures);
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_command;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command("/proxy", "/proxy server  USWest"),
+            Some(("server", vec!["USWest"]))
+        );
+        assert_eq!(parse_command("/proxy", "/proxy"), Some(("help", vec![])));
+        assert_eq!(parse_command("/proxy", "/proxyserver"), None);
+        assert_eq!(parse_command("/proxy", "hello /proxy"), None);
+    }
+}
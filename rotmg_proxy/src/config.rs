@@ -0,0 +1,349 @@
+//! The proxy configuration file
+//!
+//! Every setting has a default, so the file is optional and may leave out any
+//! section. Command line flags override settings from the file.
+
+use crate::servers::ServerList;
+use crate::Opts;
+use anyhow::{bail, Context};
+use log::warn;
+use rotmg_networking::PolicyFile;
+use rotmg_packets::structured::packets::PacketType;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tokio::fs::read_to_string;
+
+/// The proxy settings, loaded from a TOML file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: ListenConfig,
+    pub listeners: Vec<ListenerConfig>,
+    pub servers: ServersConfig,
+    pub policy: PolicyConfig,
+    pub plugins: PluginsConfig,
+    pub logging: LoggingConfig,
+    pub capture: CaptureConfig,
+    pub commands: CommandsConfig,
+    pub shutdown: ShutdownConfig,
+}
+
+/// Where the proxy listens for clients
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    /// The IP address to listen on
+    pub ip: IpAddr,
+
+    /// The port to listen on, instead of the one extracted from the client
+    pub port: Option<u16>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            ip: Ipv4Addr::LOCALHOST.into(),
+            port: None,
+        }
+    }
+}
+
+/// An extra address to listen for clients on, which can connect them to a
+/// specific server
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+
+    /// The server to connect clients to, by name or abbreviation, instead of
+    /// the one they would usually connect to
+    pub server: Option<String>,
+}
+
+/// Which servers clients are connected to
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServersConfig {
+    /// The default server, by name or abbreviation
+    pub default: String,
+
+    /// The default server for clients from specific IP addresses
+    pub clients: HashMap<IpAddr, String>,
+}
+
+impl Default for ServersConfig {
+    fn default() -> Self {
+        Self {
+            default: "USEast".to_string(),
+            clients: HashMap::new(),
+        }
+    }
+}
+
+/// How flash policy file requests are handled
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// The policy file to respond with, instead of the default policy
+    pub file: Option<PathBuf>,
+
+    /// Whether to run a standalone policy file server
+    pub server: bool,
+
+    /// The port to run the policy file server on
+    pub server_port: u16,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            server: false,
+            server_port: 843,
+        }
+    }
+}
+
+/// Settings for the built-in plugins, by plugin name
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PluginsConfig {
+    pub scripts: ScriptsConfig,
+    pub packet_logger: PacketLoggerConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptsConfig {
+    pub enabled: bool,
+
+    /// The directory to load scripts from, instead of `scripts` in the data
+    /// dir
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for ScriptsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PacketLoggerConfig {
+    /// The types of packets to log
+    pub packets: Vec<PacketType>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// An `env_logger` filter, e.g. `rotmg_proxy=debug`, which is overridden
+    /// by the `RUST_LOG` environment variable
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// The directory to record each session to a capture file in
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandsConfig {
+    /// The prefix of chat messages which are handled as proxy commands
+    pub prefix: String,
+}
+
+impl Default for CommandsConfig {
+    fn default() -> Self {
+        Self {
+            prefix: "/proxy".to_string(),
+        }
+    }
+}
+
+/// How the proxy shuts down when it's asked to stop
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// How long to wait for players to leave before closing their sessions,
+    /// in seconds
+    pub timeout: u64,
+
+    /// The chat message shown to players when the proxy starts shutting down
+    pub message: String,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 30,
+            message: "The proxy is shutting down, please finish up and log out".to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Get the path of the config file in the data dir, used when no other path
+    /// is given
+    pub fn default_path(data_dir: &Path) -> PathBuf {
+        data_dir.join("config.toml")
+    }
+
+    /// Parse a config file, resolving relative paths in it against the given
+    /// directory
+    pub fn parse(s: &str, base: &Path) -> anyhow::Result<Self> {
+        let mut config: Config = toml::from_str(s)?;
+
+        let paths = vec![
+            &mut config.policy.file,
+            &mut config.plugins.scripts.dir,
+            &mut config.capture.dir,
+        ];
+        for path in paths.into_iter().flatten() {
+            *path = base.join(&*path);
+        }
+        Ok(config)
+    }
+
+    /// Load a config file, using the defaults if it doesn't exist and isn't
+    /// required
+    pub async fn load(path: &Path, required: bool) -> anyhow::Result<Self> {
+        let s = match read_to_string(path).await {
+            Ok(s) => s,
+            Err(e) if e.kind() == ErrorKind::NotFound && !required => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading config from {}", path.display()))
+            }
+        };
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&s, base).with_context(|| format!("parsing config from {}", path.display()))
+    }
+
+    /// Override settings with any given on the command line
+    pub fn apply_opts(&mut self, opts: &Opts) {
+        if let Some(ip) = opts.ip {
+            self.listen.ip = ip;
+        }
+        if opts.port.is_some() {
+            self.listen.port = opts.port;
+        }
+        if let Some(server) = &opts.default_server {
+            self.servers.default = server.clone();
+        }
+        self.listeners.extend(opts.listeners.iter().cloned());
+        self.servers
+            .clients
+            .extend(opts.client_servers.iter().cloned());
+
+        if opts.policy_file.is_some() {
+            self.policy.file = opts.policy_file.clone();
+        }
+        if opts.policy_server {
+            self.policy.server = true;
+        }
+        if let Some(port) = opts.policy_server_port {
+            self.policy.server_port = port;
+        }
+
+        if opts.capture_dir.is_some() {
+            self.capture.dir = opts.capture_dir.clone();
+        }
+        if !opts.log_packets.is_empty() {
+            self.plugins.packet_logger.packets = opts.log_packets.clone();
+        }
+        if let Some(prefix) = &opts.command_prefix {
+            self.commands.prefix = prefix.clone();
+        }
+        if opts.no_scripts {
+            self.plugins.scripts.enabled = false;
+        }
+        if let Some(timeout) = opts.shutdown_timeout {
+            self.shutdown.timeout = timeout;
+        }
+    }
+
+    /// Check the parts of the config which can only be validated against other
+    /// files, like the policy file and the cached server list
+    pub async fn check(&self, data_dir: &Path) -> anyhow::Result<()> {
+        if let Some(path) = &self.policy.file {
+            let policy = read_to_string(path)
+                .await
+                .with_context(|| format!("reading policy file {}", path.display()))?;
+            PolicyFile::new(policy)
+                .parse()
+                .with_context(|| format!("validating policy file {}", path.display()))?;
+        }
+
+        let servers = match ServerList::fetch(data_dir, true).await {
+            Ok(servers) => ServerList::new(servers, &self.servers.default)?,
+            Err(_) => {
+                warn!("Server names weren't checked, since there's no cached server list");
+                return Ok(());
+            }
+        };
+        for (ip, name) in &self.servers.clients {
+            if servers.get(name).is_none() {
+                bail!("unknown server {} for {}", name, ip);
+            }
+        }
+        for listener in &self.listeners {
+            match &listener.server {
+                Some(name) if servers.get(name).is_none() => {
+                    bail!("unknown server {} for listener {}", name, listener.address)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use rotmg_packets::structured::packets::PacketType;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_config() {
+        let config =
+            Config::parse(include_str!("../config.example.toml"), Path::new("/data")).unwrap();
+        assert_eq!(config.listen.ip.to_string(), "0.0.0.0");
+        assert_eq!(config.servers.default, "USWest");
+        assert_eq!(config.servers.clients.len(), 1);
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[0].address.port(), 2051);
+        assert_eq!(config.listeners[0].server.as_deref(), Some("USEast"));
+        assert_eq!(config.listeners[1].server, None);
+        assert_eq!(
+            config.plugins.packet_logger.packets,
+            vec![PacketType::Hello, PacketType::Failure]
+        );
+        assert_eq!(config.capture.dir.unwrap(), Path::new("/data/captures"));
+        assert_eq!(config.commands.prefix, "/proxy");
+        assert_eq!(config.shutdown.timeout, 60);
+
+        let config = Config::parse("", Path::new("/data")).unwrap();
+        assert_eq!(config.listen.ip.to_string(), "127.0.0.1");
+        assert!(config.plugins.scripts.enabled);
+        assert_eq!(config.policy.server_port, 843);
+
+        assert!(Config::parse("[listen]\nipp = \"0.0.0.0\"", Path::new("")).is_err());
+        assert!(Config::parse(
+            "[plugins.packet-logger]\npackets = [\"Nope\"]",
+            Path::new("")
+        )
+        .is_err());
+    }
+}
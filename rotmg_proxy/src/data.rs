@@ -1,50 +1,117 @@
-use anyhow::Context;
-use log::info;
+use anyhow::{bail, Context};
+use log::{info, warn};
 use reqwest::Response;
 use rotmg_extractor::ParsedClient;
 use rotmg_packets::Parameters;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use tokio::fs::{metadata, read_to_string, write};
+use std::path::{Path, PathBuf};
+use tokio::fs::{metadata, read, read_to_string, write};
+
+/// The URL of the official flash ROTMG client
+const OFFICIAL_CLIENT_URL: &str = "https://realmofthemadgodhrd.appspot.com/client";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ClientData {
     /// The URL of the client this data was extracted from
     pub client_url: String,
 
+    /// The URL the client was requested from, which may have redirected to
+    /// `client_url`, or `None` for a local client
+    #[serde(default)]
+    pub requested_url: Option<String>,
+
     /// The extracted data
     pub params: Parameters,
 }
 
-async fn get_client() -> anyhow::Result<Response> {
-    reqwest::get("https://realmofthemadgodhrd.appspot.com/client")
-        .await
-        .context("downloading flash ROTMG client")
-}
+/// Where to get the flash ROTMG client from
+#[derive(Debug, Clone)]
+pub enum ClientSource {
+    /// Download the client from the given URL
+    Url(String),
 
-/// Download the latest ROTMG client and extract parameters from it, saving them
-/// to the given file
-async fn extract_params(to: &Path, response: Option<Response>) -> anyhow::Result<ClientData> {
-    info!("Downloading ROTMG client...");
+    /// Read the client from a local file
+    File(PathBuf),
+}
 
-    let response = match response {
-        Some(r) => r,
-        None => get_client().await?,
-    };
+impl ClientSource {
+    /// Choose the client source from the command line options, using the
+    /// official client if neither is given
+    pub fn new(url: Option<String>, file: Option<PathBuf>) -> Self {
+        match (url, file) {
+            (_, Some(file)) => ClientSource::File(file),
+            (Some(url), None) => ClientSource::Url(url),
+            (None, None) => ClientSource::Url(OFFICIAL_CLIENT_URL.to_string()),
+        }
+    }
+}
 
-    let client_url = response.url().to_string();
-    let client = response
-        .bytes()
+async fn get_client(url: &str) -> anyhow::Result<Response> {
+    reqwest::get(url)
         .await
-        .context("receiving flash ROTMG client")?;
+        .and_then(|r| r.error_for_status())
+        .with_context(|| format!("downloading flash ROTMG client from {}", url))
+}
 
+/// Extract parameters from the raw bytes of a client
+fn extract_client(
+    client_url: String,
+    requested_url: Option<String>,
+    client: &[u8],
+) -> anyhow::Result<ClientData> {
     info!("Extracting ROTMG client data...");
-    let params = ParsedClient::new(&client)
+    let params = ParsedClient::new(client)
         .context("parsing client")?
         .extract_all()
         .context("extracting client parameters")?;
 
-    let data = ClientData { client_url, params };
+    Ok(ClientData {
+        client_url,
+        requested_url,
+        params,
+    })
+}
+
+/// Get a ROTMG client and extract parameters from it
+///
+/// If the client is being downloaded, an existing response for it can be
+/// given.
+pub async fn extract_params(
+    source: &ClientSource,
+    response: Option<Response>,
+) -> anyhow::Result<ClientData> {
+    match source {
+        ClientSource::Url(url) => {
+            info!("Downloading ROTMG client...");
+            let response = match response {
+                Some(r) => r,
+                None => get_client(url).await?,
+            };
+
+            let client_url = response.url().to_string();
+            let client = response
+                .bytes()
+                .await
+                .context("receiving flash ROTMG client")?;
+            extract_client(client_url, Some(url.clone()), &client)
+        }
+        ClientSource::File(path) => {
+            let client = read(path)
+                .await
+                .with_context(|| format!("reading flash client from {}", path.display()))?;
+            let path = path.canonicalize().unwrap_or_else(|_| path.clone());
+            extract_client(format!("file://{}", path.display()), None, &client)
+        }
+    }
+}
+
+/// Extract parameters from a client, saving them to the given file
+async fn update_params(
+    to: &Path,
+    source: &ClientSource,
+    response: Option<Response>,
+) -> anyhow::Result<ClientData> {
+    let data = extract_params(source, response).await?;
     write(to, serde_json::to_string_pretty(&data)?)
         .await
         .context("saving client params")?;
@@ -52,20 +119,88 @@ async fn extract_params(to: &Path, response: Option<Response>) -> anyhow::Result
     Ok(data)
 }
 
+/// Read client data previously saved by `update_params`
+async fn read_client_data(file: &Path) -> anyhow::Result<ClientData> {
+    let data = read_to_string(file)
+        .await
+        .with_context(|| format!("reading client data from {}", file.display()))?;
+    serde_json::from_str(&data)
+        .with_context(|| format!("parsing client data from {}", file.display()))
+}
+
+/// Check that cached client data was downloaded from the given URL
+fn check_source(data: &ClientData, url: &str) -> anyhow::Result<()> {
+    match &data.requested_url {
+        Some(requested) if requested == url => Ok(()),
+        Some(requested) => bail!(
+            "cached client parameters are for the client at {}, not {}, run the proxy online once or use --params",
+            requested,
+            url
+        ),
+        None if data.client_url.starts_with("file://") => bail!(
+            "cached client parameters are for the local client {}, not {}, run the proxy online once or use --params",
+            data.client_url,
+            url
+        ),
+        None => {
+            warn!(
+                "Cached client data doesn't record where it was downloaded from, so it may not be for {}",
+                url
+            );
+            Ok(())
+        }
+    }
+}
+
 /// Get the client parameters, downloading and extracting data if necessary.
-pub async fn get_params(data_dir: &Path) -> anyhow::Result<Parameters> {
+///
+/// Clients read from local files are always extracted again, even in offline
+/// mode, since they can't be checked for changes, and their parameters aren't
+/// cached. Otherwise, offline mode only uses cached parameters, which must have
+/// been downloaded from the same URL.
+pub async fn get_params(
+    data_dir: &Path,
+    source: &ClientSource,
+    offline: bool,
+) -> anyhow::Result<Parameters> {
     let file = data_dir.join("parameters.json");
+    let cached = metadata(&file).await.is_ok();
+
+    // a local client can be extracted without going online
+    let url = match source {
+        ClientSource::Url(url) => url,
+        ClientSource::File(_) => return Ok(extract_params(source, None).await?.params),
+    };
+
+    if offline {
+        anyhow::ensure!(
+            cached,
+            "no cached client parameters at {}, run the proxy online once or use --params",
+            file.display()
+        );
+        let old = read_client_data(&file).await?;
+        check_source(&old, url)?;
+        info!("Loaded cached client data");
+        return Ok(old.params);
+    }
 
-    if metadata(&file).await.is_ok() {
+    if cached {
         // there's some existing data - load it and check if it's up to date
-        let old = serde_json::from_str::<ClientData>(
-            &read_to_string(&file)
-                .await
-                .context("reading cached client params")?,
-        )
-        .context("parsing cached client params")?;
+        let old = read_client_data(&file)
+            .await
+            .context("loading cached client params")?;
 
-        let res = get_client().await?;
+        let res = match get_client(url).await {
+            Ok(res) => res,
+            Err(e) if check_source(&old, url).is_ok() => {
+                warn!(
+                    "Using cached client data, which may be out of date: {:#}",
+                    e
+                );
+                return Ok(old.params);
+            }
+            Err(e) => return Err(e),
+        };
 
         if res.url().as_str() == old.client_url {
             // use cached data
@@ -74,10 +209,35 @@ pub async fn get_params(data_dir: &Path) -> anyhow::Result<Parameters> {
         } else {
             // update data
             info!("Client data out of date");
-            extract_params(&file, Some(res)).await.map(|p| p.params)
+            update_params(&file, source, Some(res))
+                .await
+                .map(|p| p.params)
         }
     } else {
         // download and extract new data
-        extract_params(&file, None).await.map(|p| p.params)
+        update_params(&file, source, None).await.map(|p| p.params)
+    }
+}
+
+/// Load client parameters from a local file, which can either be client data
+/// saved by the proxy, bare parameters, or a flash client to extract them from
+pub async fn load_params(file: &Path) -> anyhow::Result<Parameters> {
+    if file.extension() == Some("swf".as_ref()) {
+        let source = ClientSource::File(file.to_path_buf());
+        return Ok(extract_params(&source, None).await?.params);
     }
+
+    let data = read_to_string(file)
+        .await
+        .with_context(|| format!("reading client data from {}", file.display()))?;
+    let mut json: serde_json::Value = serde_json::from_str(&data)
+        .with_context(|| format!("parsing client data from {}", file.display()))?;
+    if let Some(params) = json.get_mut("params") {
+        json = params.take();
+    }
+    let params = serde_json::from_value(json)
+        .with_context(|| format!("parsing client data from {}", file.display()))?;
+
+    info!("Loaded client data from {}", file.display());
+    Ok(params)
 }
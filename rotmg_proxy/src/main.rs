@@ -1,44 +1,254 @@
+mod commands;
+mod config;
 mod data;
+mod plugin;
 mod proxy;
+mod reconnect;
 mod servers;
+mod tools;
 
+use crate::config::{Config, ListenerConfig};
+use crate::data::{get_params, load_params, ClientSource};
+use crate::plugin::{parse_packet_type, ScriptPlugin};
 use crate::proxy::Proxy;
 use crate::servers::ServerList;
 use anyhow::ensure;
 use anyhow::Context;
 use env_logger::Env;
 use log::{debug, info};
+use rotmg_packets::structured::packets::PacketType;
 use rotmg_packets::Parameters;
-use std::net::IpAddr;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use tokio::try_join;
 
+/// A proxy and toolkit for ROTMG.
+#[derive(StructOpt)]
+#[allow(clippy::large_enum_variant)]
+enum Cli {
+    /// Run the proxy.
+    Run(Opts),
+
+    /// Extract client data from the flash client and save it as JSON.
+    Extract {
+        #[structopt(flatten)]
+        client: ClientOpts,
+
+        /// The file to write the client data to, instead of standard output.
+        #[structopt(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// List the ROTMG servers, along with the abbreviations which can be used
+    /// in place of their names.
+    Servers {
+        /// Only use the cached server list.
+        #[structopt(long)]
+        offline: bool,
+    },
+
+    /// Decode a packet using the current packet mappings.
+    Decode {
+        #[structopt(flatten)]
+        data: DataOpts,
+
+        /// The bytes of the packet as hex, including its header.
+        packet: String,
+    },
+
+    /// Print the packets in a capture recorded by the proxy, or replay them
+    /// over the network.
+    Replay {
+        /// The capture file to read.
+        capture: PathBuf,
+
+        /// Act as the client, connecting to the given address and sending the
+        /// packets the client sent.
+        #[structopt(long, conflicts_with = "listen")]
+        connect: Option<SocketAddr>,
+
+        /// Act as the server, waiting for a client to connect to the given
+        /// address and sending it the packets the server sent.
+        #[structopt(long)]
+        listen: Option<SocketAddr>,
+    },
+
+    /// Work with packet mappings.
+    Mappings(MappingsCommand),
+
+    /// Work with the config file.
+    Config(ConfigCommand),
+}
+
+#[derive(StructOpt)]
+enum MappingsCommand {
+    /// Compare the client data in two files, such as the `parameters.json`
+    /// from before and after a game update.
+    Diff {
+        /// The old client data, either as JSON or a flash client.
+        old: PathBuf,
+
+        /// The new client data, either as JSON or a flash client.
+        new: PathBuf,
+    },
+}
+
+#[derive(StructOpt)]
+enum ConfigCommand {
+    /// Check that a config file is valid, including the files and servers it
+    /// refers to.
+    Check {
+        /// The config file to check, instead of `config.toml` in the data dir.
+        config: Option<PathBuf>,
+    },
+}
+
 #[derive(StructOpt)]
 pub struct Opts {
+    #[structopt(flatten)]
+    data: DataOpts,
+
+    /// Load settings from the given config file instead of `config.toml` in
+    /// the data dir. Flags given on the command line override the file.
+    #[structopt(short, long)]
+    config: Option<PathBuf>,
+
     /// Use the given port for listening instead of using the one extracted from
     /// the ROTMG client.
     #[structopt(short, long)]
     port: Option<u16>,
 
-    /// Listen on the given IP address.
-    #[structopt(short, long, default_value = "127.0.0.1")]
-    ip: IpAddr,
+    /// Listen on the given IP address, 127.0.0.1 by default.
+    #[structopt(short, long)]
+    ip: Option<IpAddr>,
+
+    /// Listen on another address as well as the main one, optionally
+    /// connecting clients to a specific server, e.g.
+    /// `--listen 127.0.0.1:2051=USWest`.
+    #[structopt(long = "listen", parse(try_from_str = parse_listener))]
+    listeners: Vec<ListenerConfig>,
+
+    /// The default server to connect to, by name or abbreviation, USEast by
+    /// default.
+    #[structopt(short = "s", long)]
+    default_server: Option<String>,
 
-    /// The default server to connect to.
-    #[structopt(short = "s", long, default_value = "USEast")]
-    default_server: String,
+    /// Connect clients from the given IP address to a different server by
+    /// default, e.g. `--server 192.168.1.5=USWest`.
+    #[structopt(long = "server", parse(try_from_str = parse_client_server))]
+    client_servers: Vec<(IpAddr, String)>,
 
     /// Respond to flash policy file requests with the given policy file instead
-    /// of the default "allow-all" policy.
+    /// of the default policy, which only allows access to the proxy port.
     #[structopt(long)]
     policy_file: Option<PathBuf>,
+
+    /// Run a standalone policy file server alongside the proxy, which flash
+    /// clients check before falling back to the proxy port.
+    #[structopt(long)]
+    policy_server: bool,
+
+    /// The port to run the policy file server on, 843 by default.
+    #[structopt(long)]
+    policy_server_port: Option<u16>,
+
+    /// Record each session to a capture file in the given directory.
+    #[structopt(long)]
+    capture_dir: Option<PathBuf>,
+
+    /// Log every packet of the given types passing through the proxy, e.g.
+    /// `--log-packets Hello Text`. This can be changed while running with the
+    /// `log` command.
+    #[structopt(long, parse(try_from_str = parse_packet_type))]
+    log_packets: Vec<PacketType>,
+
+    /// The prefix of chat messages which are handled as proxy commands, /proxy
+    /// by default.
+    #[structopt(long)]
+    command_prefix: Option<String>,
+
+    /// Don't run the scripts in the `scripts` directory of the data dir.
+    #[structopt(long)]
+    no_scripts: bool,
+
+    /// How long to wait for players to leave when shutting down before closing
+    /// their sessions, in seconds, 30 by default.
+    #[structopt(long)]
+    shutdown_timeout: Option<u64>,
+}
+
+#[derive(StructOpt)]
+pub struct DataOpts {
+    /// Don't connect to the ROTMG website, and only use cached client data and
+    /// server lists.
+    #[structopt(long)]
+    offline: bool,
+
+    /// Load client data from the given file instead of the official client.
+    /// This can either be a `parameters.json` file saved by the proxy, or a
+    /// flash client to extract data from.
+    #[structopt(long)]
+    params: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    client: ClientOpts,
+}
+
+impl DataOpts {
+    /// Load the client parameters, using the cache in the data dir if possible
+    pub async fn load_params(&self, data_dir: &Path) -> anyhow::Result<Parameters> {
+        match &self.params {
+            Some(file) => load_params(file).await,
+            None => get_params(data_dir, &self.client.source(), self.offline).await,
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct ClientOpts {
+    /// Extract client data from the given flash client instead of downloading
+    /// the official client.
+    #[structopt(long, conflicts_with = "client-url")]
+    client_swf: Option<PathBuf>,
+
+    /// Download the flash client to extract client data from the given URL
+    /// instead of the official one.
+    #[structopt(long)]
+    client_url: Option<String>,
 }
 
-fn init_logging() {
-    env_logger::init_from_env(
-        Env::new().default_filter_or(concat!(env!("CARGO_PKG_NAME"), "=INFO")),
-    )
+impl ClientOpts {
+    /// Get where to get the flash client from
+    fn source(&self) -> ClientSource {
+        ClientSource::new(self.client_url.clone(), self.client_swf.clone())
+    }
+}
+
+/// Parse a client server override of the form `<ip>=<server>`
+fn parse_client_server(s: &str) -> anyhow::Result<(IpAddr, String)> {
+    let mut parts = s.splitn(2, '=');
+    let ip = parts.next().unwrap().parse().context("parsing client IP")?;
+    let server = parts.next().context("expected <ip>=<server>")?;
+    Ok((ip, server.to_string()))
+}
+
+/// Parse an extra listener of the form `<address>[=<server>]`
+fn parse_listener(s: &str) -> anyhow::Result<ListenerConfig> {
+    let mut parts = s.splitn(2, '=');
+    let address = parts
+        .next()
+        .unwrap()
+        .parse()
+        .context("parsing listen address")?;
+    let server = parts.next().map(|s| s.to_string());
+    Ok(ListenerConfig { address, server })
+}
+
+/// Set up logging, with the given filter used unless `RUST_LOG` is set
+fn init_logging(filter: Option<&str>) {
+    let default = concat!(env!("CARGO_PKG_NAME"), "=INFO");
+    env_logger::init_from_env(Env::new().default_filter_or(filter.unwrap_or(default)))
 }
 
 /// Create the data directory and return the path
@@ -54,13 +264,60 @@ async fn init_data_dir() -> anyhow::Result<PathBuf> {
     Ok(dir)
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    init_logging();
-    let opts: Opts = StructOpt::from_args();
+/// Run the proxy until it fails
+async fn run(opts: Opts) -> anyhow::Result<()> {
     let dir = init_data_dir().await?;
+    let mut config = match &opts.config {
+        Some(path) => Config::load(path, true).await?,
+        None => Config::load(&Config::default_path(&dir), false).await?,
+    };
+    config.apply_opts(&opts);
+    init_logging(config.logging.filter.as_deref());
+
+    let scripts = &config.plugins.scripts;
+    let scripts = if scripts.enabled {
+        let scripts_dir = scripts.dir.clone().unwrap_or_else(|| dir.join("scripts"));
+        Some(ScriptPlugin::new(scripts_dir)?)
+    } else {
+        None
+    };
+
+    let mut proxy = Proxy::init(dir, config, &opts.data).await?;
+    if let Some(scripts) = scripts {
+        proxy.register_plugin(scripts);
+    }
+    proxy.start().await
+}
 
-    Proxy::init(dir, opts).await?.start().await?;
+/// Run a subcommand other than `run`
+async fn run_tool(command: Cli) -> anyhow::Result<()> {
+    init_logging(None);
 
-    Ok(())
+    match command {
+        Cli::Run(_) => unreachable!(),
+        Cli::Extract { client, output } => tools::extract(&client.source(), output).await,
+        Cli::Servers { offline } => tools::servers(&init_data_dir().await?, offline).await,
+        Cli::Decode { data, packet } => {
+            let params = data.load_params(&init_data_dir().await?).await?;
+            tools::decode(&params, &packet)
+        }
+        Cli::Replay {
+            capture,
+            connect,
+            listen,
+        } => tools::replay(&capture, connect, listen).await,
+        Cli::Mappings(MappingsCommand::Diff { old, new }) => tools::diff_mappings(&old, &new).await,
+        Cli::Config(ConfigCommand::Check { config }) => {
+            tools::check_config(&init_data_dir().await?, config).await
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    match Cli::from_args() {
+        // logging is set up once the config has been loaded
+        Cli::Run(opts) => run(opts).await,
+        command => run_tool(command).await,
+    }
 }
@@ -0,0 +1,64 @@
+use crate::plugin::{Action, ProxyPlugin, Session};
+use log::info;
+use rotmg_packets::structured::packets::{AnyPacket, PacketType};
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+/// An example plugin which logs every packet of the given types
+pub struct PacketLogger {
+    types: RwLock<HashSet<PacketType>>,
+}
+
+impl PacketLogger {
+    pub fn new(types: impl IntoIterator<Item = PacketType>) -> Self {
+        Self {
+            types: RwLock::new(types.into_iter().collect()),
+        }
+    }
+
+    /// The types of packets being logged
+    pub fn types(&self) -> Vec<PacketType> {
+        self.types.read().unwrap().iter().copied().collect()
+    }
+
+    /// Change the types of packets being logged, or stop logging by passing
+    /// none
+    pub fn set_types(&self, types: impl IntoIterator<Item = PacketType>) {
+        *self.types.write().unwrap() = types.into_iter().collect();
+    }
+
+    fn log(&self, session: &Session, direction: &str, packet: &dyn AnyPacket) {
+        if self.wants_packet(packet.packet_type()) {
+            info!("[{}] {} {:?}", session.client_addr(), direction, packet);
+        }
+    }
+}
+
+impl ProxyPlugin for PacketLogger {
+    fn name(&self) -> &str {
+        "packet-logger"
+    }
+
+    fn wants_packet(&self, typ: PacketType) -> bool {
+        self.types.read().unwrap().contains(&typ)
+    }
+
+    fn on_client_packet(&self, session: &mut Session, packet: &dyn AnyPacket) -> Action {
+        self.log(session, "C->S", packet);
+        Action::Pass
+    }
+
+    fn on_server_packet(&self, session: &mut Session, packet: &dyn AnyPacket) -> Action {
+        self.log(session, "S->C", packet);
+        Action::Pass
+    }
+}
+
+/// Parse a packet type from its name, e.g. `Hello`
+pub fn parse_packet_type(name: &str) -> Result<PacketType, String> {
+    PacketType::VALUES
+        .iter()
+        .copied()
+        .find(|t| t.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("unknown packet type {}", name))
+}
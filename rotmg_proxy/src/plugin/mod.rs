@@ -0,0 +1,407 @@
+//! Plugins which can inspect and modify the packets passing through the proxy
+
+mod logger;
+mod script;
+
+pub use self::logger::{parse_packet_type, PacketLogger};
+pub use self::script::ScriptPlugin;
+
+use anyhow::Context;
+use log::debug;
+use rotmg_networking::stats::ConnectionMetrics;
+use rotmg_packets::raw::RawPacket;
+use rotmg_packets::structured::packets::{AnyPacket, PacketType};
+use rotmg_packets::PacketMappings;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// What to do with a packet after a plugin has seen it
+#[derive(Debug)]
+pub enum Action {
+    /// Pass the packet on unchanged
+    Pass,
+
+    /// Pass on the given packet instead
+    Replace(Box<dyn AnyPacket>),
+
+    /// Drop the packet, so later plugins and the other end never see it
+    Drop,
+}
+
+/// A client session passing through the proxy, as seen by plugins
+#[derive(Debug)]
+pub struct Session {
+    id: u64,
+    client_addr: SocketAddr,
+    to_client: Vec<Box<dyn AnyPacket>>,
+    to_server: Vec<Box<dyn AnyPacket>>,
+}
+
+impl Session {
+    pub fn new(id: u64, client_addr: SocketAddr) -> Self {
+        Self {
+            id,
+            client_addr,
+            to_client: Vec::new(),
+            to_server: Vec::new(),
+        }
+    }
+
+    /// The ID of this session, unique for the lifetime of the proxy
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The address of the client
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client_addr
+    }
+
+    /// Queue a packet to be sent to the client
+    ///
+    /// Queued packets are sent after the packet currently being handled.
+    pub fn send_to_client(&mut self, packet: impl AnyPacket) {
+        self.to_client.push(Box::new(packet));
+    }
+
+    /// Queue a boxed packet to be sent to the client
+    pub fn send_boxed_to_client(&mut self, packet: Box<dyn AnyPacket>) {
+        self.to_client.push(packet);
+    }
+
+    /// Queue a boxed packet to be sent to the server
+    pub fn send_boxed_to_server(&mut self, packet: Box<dyn AnyPacket>) {
+        self.to_server.push(packet);
+    }
+
+    /// Take the packets queued for the client
+    pub fn take_to_client(&mut self) -> Vec<Box<dyn AnyPacket>> {
+        std::mem::take(&mut self.to_client)
+    }
+
+    /// Take the packets queued for the server
+    pub fn take_to_server(&mut self) -> Vec<Box<dyn AnyPacket>> {
+        std::mem::take(&mut self.to_server)
+    }
+}
+
+/// A plugin which hooks into the proxy's sessions
+///
+/// A single instance of each plugin is shared between all sessions, so any
+/// state needs interior mutability. Every hook has a default implementation
+/// which does nothing, so plugins only need to implement the ones they use.
+pub trait ProxyPlugin: Send + Sync {
+    /// The name of the plugin, used in logs
+    fn name(&self) -> &str;
+
+    /// Whether the plugin wants to see packets of the given type
+    ///
+    /// Packets which no plugin wants are forwarded without being parsed, so
+    /// plugins should only want the types they handle.
+    fn wants_packet(&self, _typ: PacketType) -> bool {
+        true
+    }
+
+    /// Called once the session is connected to the server, after the client's
+    /// `Hello` packet has been forwarded
+    fn on_connect(&self, _session: &mut Session) {}
+
+    /// Called for each packet sent by the client, except the `Hello` packet
+    fn on_client_packet(&self, _session: &mut Session, _packet: &dyn AnyPacket) -> Action {
+        Action::Pass
+    }
+
+    /// Called for each packet sent by the server
+    fn on_server_packet(&self, _session: &mut Session, _packet: &dyn AnyPacket) -> Action {
+        Action::Pass
+    }
+
+    /// Called when either side closes the connection
+    fn on_disconnect(&self, _session: &Session) {}
+}
+
+impl<T: ProxyPlugin + ?Sized> ProxyPlugin for Arc<T> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn wants_packet(&self, typ: PacketType) -> bool {
+        (**self).wants_packet(typ)
+    }
+
+    fn on_connect(&self, session: &mut Session) {
+        (**self).on_connect(session)
+    }
+
+    fn on_client_packet(&self, session: &mut Session, packet: &dyn AnyPacket) -> Action {
+        (**self).on_client_packet(session, packet)
+    }
+
+    fn on_server_packet(&self, session: &mut Session, packet: &dyn AnyPacket) -> Action {
+        (**self).on_server_packet(session, packet)
+    }
+
+    fn on_disconnect(&self, session: &Session) {
+        (**self).on_disconnect(session)
+    }
+}
+
+/// The result of running a raw packet through the plugins
+#[derive(Debug)]
+pub enum Filtered {
+    /// The original packet should be forwarded
+    Original,
+
+    /// The given packet should be forwarded instead
+    Replaced(Box<RawPacket>),
+
+    /// Nothing should be forwarded
+    Dropped,
+}
+
+/// The plugins registered with the proxy, in the order they're run
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Box<dyn ProxyPlugin>>,
+}
+
+impl Plugins {
+    /// Add a plugin, which runs after all those already registered
+    pub fn register(&mut self, plugin: impl ProxyPlugin + 'static) {
+        debug!("Registered plugin {}", plugin.name());
+        self.plugins.push(Box::new(plugin));
+    }
+
+    /// The names of the registered plugins, in order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.plugins.iter().map(|p| p.name())
+    }
+
+    pub fn connect(&self, session: &mut Session) {
+        for plugin in &self.plugins {
+            plugin.on_connect(session);
+        }
+    }
+
+    pub fn disconnect(&self, session: &Session) {
+        for plugin in &self.plugins {
+            plugin.on_disconnect(session);
+        }
+    }
+
+    /// Run a packet from the client through the plugins
+    pub fn client_packet(
+        &self,
+        session: &mut Session,
+        packet: &RawPacket,
+        mappings: &PacketMappings,
+        metrics: &ConnectionMetrics,
+    ) -> anyhow::Result<Filtered> {
+        self.filter(
+            session,
+            packet,
+            mappings,
+            metrics,
+            |plugin, session, packet| plugin.on_client_packet(session, packet),
+        )
+    }
+
+    /// Run a packet from the server through the plugins
+    pub fn server_packet(
+        &self,
+        session: &mut Session,
+        packet: &RawPacket,
+        mappings: &PacketMappings,
+        metrics: &ConnectionMetrics,
+    ) -> anyhow::Result<Filtered> {
+        self.filter(
+            session,
+            packet,
+            mappings,
+            metrics,
+            |plugin, session, packet| plugin.on_server_packet(session, packet),
+        )
+    }
+
+    fn filter(
+        &self,
+        session: &mut Session,
+        raw: &RawPacket,
+        mappings: &PacketMappings,
+        metrics: &ConnectionMetrics,
+        hook: impl Fn(&dyn ProxyPlugin, &mut Session, &dyn AnyPacket) -> Action,
+    ) -> anyhow::Result<Filtered> {
+        let wanted = match mappings.to_internal(raw.id()) {
+            Some(typ) => self.plugins.iter().any(|p| p.wants_packet(typ)),
+            None => false,
+        };
+        if !wanted {
+            return Ok(Filtered::Original);
+        }
+
+        // packets we can't parse are passed through untouched, since the
+        // packet definitions may just be out of date
+        let mut packet = match raw.parse(mappings) {
+            Ok((packet, _)) => packet,
+            Err(e) => {
+                debug!("Not running plugins on unparsable packet: {}", e);
+                metrics.record_parse_failure();
+                return Ok(Filtered::Original);
+            }
+        };
+
+        let mut replaced = false;
+        for plugin in &self.plugins {
+            match hook(plugin.as_ref(), session, packet.as_ref()) {
+                Action::Pass => {}
+                Action::Replace(new) => {
+                    packet = new;
+                    replaced = true;
+                }
+                Action::Drop => {
+                    debug!(
+                        "Plugin {} dropped {:?} packet",
+                        plugin.name(),
+                        packet.packet_type()
+                    );
+                    return Ok(Filtered::Dropped);
+                }
+            }
+        }
+
+        if !replaced {
+            return Ok(Filtered::Original);
+        }
+        let raw = packet
+            .into_raw_mapped(mappings)
+            .context("encoding packet from plugin")?;
+        Ok(Filtered::Replaced(raw))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Filtered, Plugins, ProxyPlugin, Session};
+    use rotmg_networking::stats::ConnectionMetrics;
+    use rotmg_packets::raw::RawPacket;
+    use rotmg_packets::structured::packets::client::PlayerText;
+    use rotmg_packets::structured::packets::server::Notification;
+    use rotmg_packets::structured::packets::{AnyPacket, PacketType};
+    use rotmg_packets::PacketMappings;
+
+    /// Shouts everything the player says, and drops whispers
+    struct Shout;
+
+    impl ProxyPlugin for Shout {
+        fn name(&self) -> &str {
+            "shout"
+        }
+
+        fn on_client_packet(&self, session: &mut Session, packet: &dyn AnyPacket) -> Action {
+            let text = match packet.downcast_ref::<PlayerText>() {
+                Some(text) => &text.text,
+                None => return Action::Pass,
+            };
+            if text.starts_with("/tell") {
+                session.send_to_client(Notification::default());
+                return Action::Drop;
+            }
+            Action::Replace(Box::new(PlayerText {
+                text: text.to_uppercase(),
+            }))
+        }
+    }
+
+    /// Checks that it sees the packets from earlier plugins
+    struct Check;
+
+    impl ProxyPlugin for Check {
+        fn name(&self) -> &str {
+            "check"
+        }
+
+        fn on_client_packet(&self, _session: &mut Session, packet: &dyn AnyPacket) -> Action {
+            let text = packet.downcast_ref::<PlayerText>().unwrap();
+            assert_eq!(text.text, "HELLO");
+            Action::Pass
+        }
+    }
+
+    /// Doesn't want any packets, so it's never called
+    struct Idle;
+
+    impl ProxyPlugin for Idle {
+        fn name(&self) -> &str {
+            "idle"
+        }
+
+        fn wants_packet(&self, _typ: PacketType) -> bool {
+            false
+        }
+
+        fn on_server_packet(&self, _session: &mut Session, _packet: &dyn AnyPacket) -> Action {
+            panic!("idle plugin was given a packet")
+        }
+    }
+
+    #[test]
+    fn test_plugins() {
+        let mappings = PacketMappings::new(PacketType::VALUES.iter().map(|&t| (t, t as u8)));
+        let mut plugins = Plugins::default();
+        plugins.register(Shout);
+        plugins.register(Check);
+        assert_eq!(plugins.names().collect::<Vec<_>>(), vec!["shout", "check"]);
+        let metrics = ConnectionMetrics::new(&mappings);
+
+        let mut session = Session::new(0, ([127, 0, 0, 1], 1234).into());
+        let text = |text: &str| {
+            let packet: Box<dyn AnyPacket> = Box::new(PlayerText {
+                text: text.to_string(),
+            });
+            packet.into_raw_mapped(&mappings).unwrap()
+        };
+
+        match plugins
+            .client_packet(&mut session, &text("hello"), &mappings, &metrics)
+            .unwrap()
+        {
+            Filtered::Replaced(packet) => assert_eq!(packet, text("HELLO")),
+            filtered => panic!("unexpected result: {:?}", filtered),
+        }
+        assert!(session.take_to_client().is_empty());
+
+        let filtered = plugins
+            .client_packet(&mut session, &text("/tell someone"), &mappings, &metrics)
+            .unwrap();
+        assert!(matches!(filtered, Filtered::Dropped));
+        let injected = session.take_to_client();
+        assert_eq!(injected.len(), 1);
+        assert!(injected[0].is::<Notification>());
+
+        // packets which can't be parsed are forwarded, and counted as failures
+        let invalid = RawPacket::from_slice(&[0, 0, 0, 6, PacketType::PlayerText as u8, 1])
+            .unwrap()
+            .to_owned();
+        let filtered = plugins
+            .client_packet(&mut session, &invalid, &mappings, &metrics)
+            .unwrap();
+        assert!(matches!(filtered, Filtered::Original));
+        assert_eq!(metrics.snapshot().parse_failures, 1);
+    }
+
+    #[test]
+    fn test_unwanted_packets() {
+        let mappings = PacketMappings::new(PacketType::VALUES.iter().map(|&t| (t, t as u8)));
+        let mut plugins = Plugins::default();
+        plugins.register(Idle);
+        let metrics = ConnectionMetrics::new(&mappings);
+
+        let mut session = Session::new(0, ([127, 0, 0, 1], 1234).into());
+        let packet: Box<dyn AnyPacket> = Box::new(Notification::default());
+        let packet = packet.into_raw_mapped(&mappings).unwrap();
+        let filtered = plugins
+            .server_packet(&mut session, &packet, &mappings, &metrics)
+            .unwrap();
+        assert!(matches!(filtered, Filtered::Original));
+    }
+}
@@ -0,0 +1,461 @@
+//! Plugins written as Rhai scripts, loaded from a directory
+//!
+//! Each `.rhai` file in the directory is a separate script, which can define
+//! any of these functions:
+//!
+//! ```rhai
+//! fn on_connect(session) {}
+//! fn on_client_packet(session, packet) {}
+//! fn on_server_packet(session, packet) {}
+//! fn on_disconnect(session) {}
+//! ```
+//!
+//! The session is a map with the session `id` and the `client` address.
+//! Packets are maps of their fields, with the packet's name under `type`. A
+//! packet hook can return `false` to drop the packet, or a packet map to
+//! replace it. Anything else passes the packet on unchanged.
+//!
+//! Scripts can send new packets using `send_to_client(type, fields)` and
+//! `send_to_server(type, fields)`, where any fields left out are given default
+//! values.
+//!
+//! Scripts are reloaded when they change, so they can be edited while the proxy
+//! is running.
+
+use crate::plugin::{parse_packet_type, Action, ProxyPlugin, Session};
+use anyhow::Context;
+use log::{debug, info, warn};
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Map, NativeCallContext, Scope, AST};
+use rotmg_packets::structured::packets::{AnyPacket, PacketType};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime};
+use tokio::task::spawn_blocking;
+use tokio::time::delay_for;
+
+/// How often to check the scripts directory for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+// scripts run on the same threads as sessions, so these limits stop a script
+// stuck in a loop or recursing forever from stalling other sessions
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_ARRAY_SIZE: usize = 10_000;
+const MAX_MAP_SIZE: usize = 10_000;
+
+/// Packets sent by scripts during a call, and whether each is for the client
+type Outbox = Arc<Mutex<Vec<(bool, Box<dyn AnyPacket>)>>>;
+
+/// The loaded scripts, which are swapped out as a whole when they're reloaded
+type LoadedScripts = Arc<Mutex<Vec<Arc<Script>>>>;
+
+struct Script {
+    path: PathBuf,
+    modified: SystemTime,
+    ast: AST,
+}
+
+impl Script {
+    fn defines(&self, name: &str, params: usize) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|f| f.name == name && f.params.len() == params)
+    }
+}
+
+/// A plugin which runs the Rhai scripts in a directory
+pub struct ScriptPlugin {
+    engine: Arc<Engine>,
+    scripts: LoadedScripts,
+}
+
+impl ScriptPlugin {
+    /// Create a plugin running the scripts in the given directory, creating it
+    /// if it doesn't exist
+    ///
+    /// The scripts are reloaded by a background task while the plugin exists,
+    /// so this must be called from within a Tokio runtime.
+    pub fn new(dir: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir).context("creating scripts dir")?;
+
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_string_size(MAX_STRING_SIZE)
+            .set_max_array_size(MAX_ARRAY_SIZE)
+            .set_max_map_size(MAX_MAP_SIZE);
+        engine.on_print(|s| info!("{}", s));
+        engine.on_debug(|s, source, pos| debug!("{} @ {:?}: {}", source.unwrap_or(""), pos, s));
+        for &(name, to_client) in &[("send_to_client", true), ("send_to_server", false)] {
+            engine.register_fn(
+                name,
+                move |context: NativeCallContext,
+                      typ: &str,
+                      fields: Map|
+                      -> Result<(), Box<EvalAltResult>> {
+                    let packet = to_packet(typ, Dynamic::from(fields))?;
+                    // each call is tagged with the outbox of the session it's for
+                    let outbox = context
+                        .tag()
+                        .and_then(|tag| tag.clone().try_cast::<Outbox>())
+                        .ok_or("packets can't be sent from here")?;
+                    outbox.lock().unwrap().push((to_client, packet));
+                    Ok(())
+                },
+            );
+        }
+        let engine = Arc::new(engine);
+
+        info!("Loading scripts from {}", dir.display());
+        let loaded = reload_scripts(&dir, &engine, &[]).unwrap_or_default();
+        let scripts = Arc::new(Mutex::new(loaded));
+        tokio::spawn(watch_scripts(dir, engine.clone(), Arc::downgrade(&scripts)));
+
+        Ok(Self { engine, scripts })
+    }
+
+    /// Call a function in every script that defines it, passing the session
+    /// followed by the given arguments
+    ///
+    /// After each call, `handle` is given the result and can update the
+    /// arguments passed to the next script, or return false to stop early.
+    fn call(
+        &self,
+        session: &mut Session,
+        name: &str,
+        args: &mut [Dynamic],
+        mut handle: impl FnMut(Dynamic, &mut [Dynamic]) -> bool,
+    ) {
+        // scripts run without holding the lock, so they can be reloaded and
+        // run by other sessions at the same time
+        let scripts = self.scripts.lock().unwrap().clone();
+        let outbox = Outbox::default();
+
+        let session_map = session_to_dynamic(session);
+        for script in &scripts {
+            if !script.defines(name, args.len() + 1) {
+                continue;
+            }
+
+            let mut call_args = vec![session_map.clone()];
+            call_args.extend(args.iter().cloned());
+            let options = CallFnOptions::new()
+                .eval_ast(false)
+                .with_tag(outbox.clone());
+            let result = self.engine.call_fn_with_options::<Dynamic>(
+                options,
+                &mut Scope::new(),
+                &script.ast,
+                name,
+                call_args,
+            );
+
+            for (to_client, packet) in outbox.lock().unwrap().drain(..) {
+                if to_client {
+                    session.send_boxed_to_client(packet);
+                } else {
+                    session.send_boxed_to_server(packet);
+                }
+            }
+
+            match result {
+                Ok(value) => {
+                    if !handle(value, args) {
+                        return;
+                    }
+                }
+                // including hitting a limit, which stops the script
+                Err(e) => warn!("Error in script {}: {}", script.path.display(), e),
+            }
+        }
+    }
+
+    fn on_packet(&self, session: &mut Session, name: &str, packet: &dyn AnyPacket) -> Action {
+        // converting the packet is only worth it if a script will see it
+        let handled = self
+            .scripts
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|script| script.defines(name, 2));
+        if !handled {
+            return Action::Pass;
+        }
+
+        let value = match packet_to_dynamic(packet) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Failed to convert packet for scripts: {}", e);
+                return Action::Pass;
+            }
+        };
+
+        let mut args = [value];
+        let mut replaced = false;
+        let mut dropped = false;
+        self.call(session, name, &mut args, |result, args| {
+            if result.as_bool() == Ok(false) {
+                dropped = true;
+            } else if result.is_map() {
+                args[0] = result;
+                replaced = true;
+            }
+            !dropped
+        });
+
+        if dropped {
+            return Action::Drop;
+        }
+        if !replaced {
+            return Action::Pass;
+        }
+
+        let [value] = args;
+        let typ = value
+            .read_lock::<Map>()
+            .and_then(|m| m.get("type").cloned())
+            .and_then(|t| t.into_string().ok())
+            .unwrap_or_else(|| packet.packet_type().name().to_string());
+        match to_packet(&typ, value) {
+            Ok(packet) => Action::Replace(packet),
+            Err(e) => {
+                warn!("Script returned an invalid packet: {}", e);
+                Action::Pass
+            }
+        }
+    }
+}
+
+impl ProxyPlugin for ScriptPlugin {
+    fn name(&self) -> &str {
+        "scripts"
+    }
+
+    /// Scripts can handle any type of packet, but packets only need to be
+    /// parsed if a script handles packets at all
+    fn wants_packet(&self, _typ: PacketType) -> bool {
+        self.scripts.lock().unwrap().iter().any(|script| {
+            script.defines("on_client_packet", 2) || script.defines("on_server_packet", 2)
+        })
+    }
+
+    fn on_connect(&self, session: &mut Session) {
+        self.call(session, "on_connect", &mut [], |_, _| true);
+    }
+
+    fn on_client_packet(&self, session: &mut Session, packet: &dyn AnyPacket) -> Action {
+        self.on_packet(session, "on_client_packet", packet)
+    }
+
+    fn on_server_packet(&self, session: &mut Session, packet: &dyn AnyPacket) -> Action {
+        self.on_packet(session, "on_server_packet", packet)
+    }
+
+    fn on_disconnect(&self, session: &Session) {
+        // scripts can't send packets once the session has ended
+        let mut session = Session::new(session.id(), session.client_addr());
+        self.call(&mut session, "on_disconnect", &mut [], |_, _| true);
+    }
+}
+
+/// Reload the scripts in a directory whenever any are added, changed, or
+/// removed, until the plugin is dropped
+async fn watch_scripts(dir: PathBuf, engine: Arc<Engine>, scripts: Weak<Mutex<Vec<Arc<Script>>>>) {
+    loop {
+        delay_for(RELOAD_INTERVAL).await;
+        let scripts = match scripts.upgrade() {
+            Some(scripts) => scripts,
+            None => return,
+        };
+
+        // listing and compiling scripts blocks, so it's kept off the runtime
+        let current = scripts.lock().unwrap().clone();
+        let (dir, engine) = (dir.clone(), engine.clone());
+        let reloaded = spawn_blocking(move || reload_scripts(&dir, &engine, &current)).await;
+        match reloaded {
+            Ok(Some(loaded)) => *scripts.lock().unwrap() = loaded,
+            Ok(None) => {}
+            Err(e) => warn!("Failed to reload scripts: {}", e),
+        }
+    }
+}
+
+/// Load the scripts in a directory, reusing the currently loaded scripts which
+/// haven't changed
+///
+/// Returns `None` if the directory couldn't be listed, in which case the
+/// current scripts should be kept.
+fn reload_scripts(
+    dir: &Path,
+    engine: &Engine,
+    current: &[Arc<Script>],
+) -> Option<Vec<Arc<Script>>> {
+    let found = match list_scripts(dir) {
+        Ok(found) => found,
+        Err(e) => {
+            warn!("Failed to list scripts: {}", e);
+            return None;
+        }
+    };
+
+    let mut loaded = Vec::with_capacity(found.len());
+    for (path, modified) in found {
+        let existing = current.iter().find(|s| s.path == path);
+        if let Some(script) = existing {
+            if script.modified == modified {
+                loaded.push(script.clone());
+                continue;
+            }
+        }
+
+        match engine.compile_file(path.clone()) {
+            Ok(ast) => {
+                info!("Loaded script {}", path.display());
+                loaded.push(Arc::new(Script {
+                    path,
+                    modified,
+                    ast,
+                }));
+            }
+            Err(e) => warn!("Failed to load script {}: {}", path.display(), e),
+        }
+    }
+
+    for script in current {
+        if !loaded.iter().any(|s| s.path == script.path) {
+            info!("Unloaded script {}", script.path.display());
+        }
+    }
+    Some(loaded)
+}
+
+/// List the scripts in a directory, along with when they were last modified
+fn list_scripts(dir: &Path) -> std::io::Result<Vec<(PathBuf, SystemTime)>> {
+    let mut scripts = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() == Some("rhai".as_ref()) {
+            let modified = std::fs::metadata(&path)?.modified()?;
+            scripts.push((path, modified));
+        }
+    }
+
+    // run scripts in a predictable order
+    scripts.sort();
+    Ok(scripts)
+}
+
+fn session_to_dynamic(session: &Session) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("id".into(), Dynamic::from(session.id() as i64));
+    map.insert("client".into(), session.client_addr().to_string().into());
+    map.into()
+}
+
+fn packet_to_dynamic(packet: &dyn AnyPacket) -> Result<Dynamic, Box<EvalAltResult>> {
+    let mut map = to_dynamic(packet)?.cast::<Map>();
+    map.insert("type".into(), packet.packet_type().name().into());
+    Ok(map.into())
+}
+
+fn to_packet(typ: &str, fields: Dynamic) -> Result<Box<dyn AnyPacket>, Box<EvalAltResult>> {
+    let typ: PacketType = parse_packet_type(typ)?;
+    let mut fields = fields.cast::<Map>();
+    fields.remove("type");
+
+    // fields left out by the script keep their default values
+    let mut json = serde_json::to_value(&*typ.default_packet())
+        .map_err(|e| format!("encoding default {:?} packet: {}", typ, e))?;
+    merge_json(&mut json, from_dynamic(&fields.into())?);
+    typ.deserialize_packet(json)
+        .map_err(|e| format!("invalid {:?} packet: {}", typ, e).into())
+}
+
+/// Overwrite the fields of a JSON value with those of another, merging nested
+/// objects rather than replacing them
+fn merge_json(base: &mut serde_json::Value, other: serde_json::Value) {
+    match (base, other) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScriptPlugin;
+    use crate::plugin::{Action, ProxyPlugin, Session};
+    use rotmg_packets::structured::packets::client::PlayerText;
+    use rotmg_packets::structured::packets::server::Notification;
+    use rotmg_packets::structured::packets::PacketType;
+
+    const SCRIPT: &str = r#"
+        fn on_client_packet(session, packet) {
+            if packet.type != "PlayerText" {
+                return;
+            }
+            if packet.text == "/secret" {
+                send_to_client("Notification", #{ message: "shh", color: 3 });
+                return false;
+            }
+            packet.text += " from " + session.client;
+            packet
+        }
+    "#;
+
+    const LOOP_SCRIPT: &str = r#"
+        fn on_server_packet(session, packet) {
+            loop {}
+        }
+    "#;
+
+    fn text(text: &str) -> PlayerText {
+        PlayerText {
+            text: text.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_script_plugin() {
+        let dir = std::env::temp_dir().join(format!("rotmg_proxy-scripts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("test.rhai"), SCRIPT).unwrap();
+        std::fs::write(dir.join("loop.rhai"), LOOP_SCRIPT).unwrap();
+        let plugin = ScriptPlugin::new(dir.clone()).unwrap();
+        assert!(plugin.wants_packet(PacketType::PlayerText));
+
+        let mut session = Session::new(0, ([127, 0, 0, 1], 1234).into());
+        match plugin.on_client_packet(&mut session, &text("hello")) {
+            Action::Replace(packet) => {
+                let packet = packet.downcast::<PlayerText>().unwrap();
+                assert_eq!(packet.text, "hello from 127.0.0.1:1234");
+            }
+            action => panic!("unexpected action: {:?}", action),
+        }
+
+        let action = plugin.on_client_packet(&mut session, &text("/secret"));
+        assert!(matches!(action, Action::Drop));
+        let sent = session.take_to_client();
+        assert_eq!(sent.len(), 1);
+        let notification = sent[0].downcast_ref::<Notification>().unwrap();
+        assert_eq!(notification.message, "shh");
+        assert_eq!(notification.color, 3);
+
+        // the loop is stopped by the operation limit
+        let action = plugin.on_server_packet(&mut session, &Notification::default());
+        assert!(matches!(action, Action::Pass));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
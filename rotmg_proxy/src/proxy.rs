@@ -1,119 +1,990 @@
-use crate::data::get_params;
-use crate::servers::ServerList;
-use crate::Opts;
-use anyhow::Context;
-use log::{debug, error, info};
-use rotmg_networking::{accept_with_policy, connect, PolicyFile};
+use crate::commands::{reply, CommandContext, Commands};
+use crate::config::Config;
+use crate::plugin::{Filtered, PacketLogger, Plugins, ProxyPlugin, Session};
+use crate::reconnect::{ReconnectStore, ReconnectTarget};
+use crate::servers::{Server, ServerList};
+use crate::DataOpts;
+use anyhow::{bail, Context};
+use futures::future::join_all;
+use log::{debug, error, info, warn};
+use rotmg_networking::capture::CaptureWriter;
+use rotmg_networking::codec::Encoder;
+use rotmg_networking::policy::{PolicyServer, PolicyServerConfig, SiteControl};
+use rotmg_networking::stats::ConnectionMetrics;
+use rotmg_networking::{accept_with_policy, connect, ConnectionConfig, PolicyFile};
 use rotmg_packets::raw::RawPacket;
+use rotmg_packets::structured::packets::client::{Hello, PlayerText};
+use rotmg_packets::structured::packets::server::Reconnect;
+use rotmg_packets::structured::packets::{AnyPacket, PacketType};
 use rotmg_packets::Parameters;
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::fs::read_to_string;
+use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{delay_for, timeout};
 use tokio::{select, try_join};
 
+/// How long to wait after failing to accept a client before trying again
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
 pub struct Proxy {
     data_dir: PathBuf,
-    opts: Opts,
+    config: Config,
     params: Parameters,
     servers: ServerList,
     policy_file: PolicyFile,
     keys: Vec<u8>,
+    connection: ConnectionConfig,
+    reconnects: ReconnectStore,
+    plugins: Plugins,
+    logger: Arc<PacketLogger>,
+    commands: Commands,
+    next_session: AtomicU64,
+
+    /// The number of connections currently being handled
+    active_sessions: AtomicUsize,
+
+    /// The number of connections which ended with an error
+    failed_sessions: AtomicU64,
+
+    /// Tells sessions how far the proxy has got in shutting down
+    shutdown: watch::Sender<ShutdownStage>,
+    shutdown_rx: watch::Receiver<ShutdownStage>,
+
+    /// The host of the server each client has chosen, by client IP
+    selected: Mutex<HashMap<IpAddr, String>>,
+
+    /// The addresses to accept clients on, taken when the proxy starts
+    listeners: Vec<Listener>,
+
+    /// The captures of the sessions being recorded, by client address, so
+    /// they can be written out if their sessions don't close on shutdown
+    captures: Mutex<HashMap<SocketAddr, CaptureWriter<BufWriter<File>>>>,
+}
+
+/// How far the proxy has got in shutting down
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ShutdownStage {
+    /// Sessions are running normally
+    Running,
+
+    /// Players have been told the proxy is stopping, and sessions continue
+    /// until they leave
+    Draining,
+
+    /// The shutdown deadline has passed, and remaining sessions should close
+    Closing,
+}
+
+/// Wait for the shutdown stage to move on from the given one
+async fn next_stage(
+    rx: &mut watch::Receiver<ShutdownStage>,
+    current: ShutdownStage,
+) -> ShutdownStage {
+    loop {
+        let stage = *rx.borrow();
+        if stage != current {
+            return stage;
+        }
+        if rx.recv().await.is_none() {
+            return ShutdownStage::Closing;
+        }
+    }
+}
+
+/// Wait for a signal asking the proxy to stop
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// An address the proxy accepts clients on
+struct Listener {
+    address: SocketAddr,
+
+    /// The server all clients of this listener are connected to, if it has one
+    server: Option<Server>,
+}
+
+/// The real server a client session is connected to
+struct Route {
+    host: String,
+    port: u16,
 }
 
 impl Proxy {
-    async fn load_policy_file(opts: &Opts) -> anyhow::Result<PolicyFile> {
-        match &opts.policy_file {
-            None => Ok(PolicyFile::ALLOW_ALL),
-            Some(path) => Ok(PolicyFile::new(read_to_string(path).await?)),
+    /// Load the policy file specified by the config, or generate one that
+    /// only allows access to the given ports
+    async fn load_policy_file(config: &Config, ports: &[u16]) -> anyhow::Result<PolicyFile> {
+        let policy = match &config.policy.file {
+            None => ports
+                .iter()
+                .fold(
+                    PolicyFile::builder().site_control(SiteControl::MasterOnly),
+                    |builder, &port| builder.allow_domain("*", port),
+                )
+                .build(),
+            Some(path) => PolicyFile::new(read_to_string(path).await?),
+        };
+
+        let rules = policy.parse().context("validating policy file")?;
+        for rule in &rules.allow {
+            debug!(
+                "Policy file allows {} to access ports {}",
+                rule.domain, rule.ports
+            );
+        }
+        for &port in ports {
+            if !rules.permits("localhost", port) {
+                warn!("Policy file doesn't allow access to port {}", port);
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Get the configuration used for both client and server connections
+    fn connection_config() -> ConnectionConfig {
+        ConnectionConfig {
+            idle_timeout: Some(Duration::from_secs(60)),
+            policy_timeout: Some(Duration::from_secs(10)),
+            nodelay: true,
+            ..ConnectionConfig::default()
         }
     }
 
     /// Perform proxy initialization tasks like loading data
-    pub async fn init(data_dir: PathBuf, opts: Opts) -> anyhow::Result<Self> {
-        let (params, servers, policy_file) = try_join!(
-            get_params(&data_dir),
-            ServerList::load(&opts.default_server),
-            Self::load_policy_file(&opts)
+    pub async fn init(data_dir: PathBuf, config: Config, data: &DataOpts) -> anyhow::Result<Self> {
+        let (params, servers) = try_join!(
+            data.load_params(&data_dir),
+            ServerList::load(&data_dir, &config.servers.default, data.offline)
         )?;
+        Self::new(data_dir, config, params, servers).await
+    }
+
+    /// Create a proxy using already loaded parameters and servers
+    async fn new(
+        data_dir: PathBuf,
+        config: Config,
+        params: Parameters,
+        servers: ServerList,
+    ) -> anyhow::Result<Self> {
+        let port = config.listen.port.unwrap_or(params.basic.port);
+        let mut listeners = vec![Listener {
+            address: SocketAddr::new(config.listen.ip, port),
+            server: None,
+        }];
+        for listener in &config.listeners {
+            let server = match &listener.server {
+                Some(name) => {
+                    let server = servers.get(name).with_context(|| {
+                        format!("unknown server {} for listener {}", name, listener.address)
+                    })?;
+                    Some(server.clone())
+                }
+                None => None,
+            };
+            listeners.push(Listener {
+                address: listener.address,
+                server,
+            });
+        }
+
+        let ports = listeners
+            .iter()
+            .map(|l| l.address.port())
+            .collect::<Vec<_>>();
+        let policy_file = Self::load_policy_file(&config, &ports).await?;
+
+        if let Some(dir) = &config.capture.dir {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .context("creating capture dir")?;
+        }
 
         let keys = hex::decode(&params.rc4).context("parsing RC4 keys")?;
 
+        let mut selected = HashMap::new();
+        for (ip, name) in &config.servers.clients {
+            let server = servers
+                .get(name)
+                .with_context(|| format!("unknown server {} for {}", name, ip))?;
+            selected.insert(*ip, server.host.clone());
+        }
+
+        let logger = Arc::new(PacketLogger::new(
+            config.plugins.packet_logger.packets.iter().copied(),
+        ));
+        let mut plugins = Plugins::default();
+        plugins.register(logger.clone());
+        let commands = Commands::new(config.commands.prefix.clone());
+        let (shutdown, shutdown_rx) = watch::channel(ShutdownStage::Running);
+
         Ok(Self {
             data_dir,
-            opts,
+            config,
             params,
             servers,
             policy_file,
             keys,
+            connection: Self::connection_config(),
+            reconnects: ReconnectStore::default(),
+            plugins,
+            logger,
+            commands,
+            next_session: AtomicU64::new(0),
+            active_sessions: AtomicUsize::new(0),
+            failed_sessions: AtomicU64::new(0),
+            shutdown,
+            shutdown_rx,
+            selected: Mutex::new(selected),
+            listeners,
+            captures: Mutex::new(HashMap::new()),
         })
     }
 
-    /// Run the proxy server
-    pub async fn start(self) -> anyhow::Result<()> {
-        let addr = (
-            self.opts.ip,
-            self.opts.port.unwrap_or(self.params.basic.port),
-        );
+    /// Register a plugin to run on every session, after those already
+    /// registered
+    pub fn register_plugin(&mut self, plugin: impl ProxyPlugin + 'static) {
+        self.plugins.register(plugin);
+    }
 
-        let mut listener = TcpListener::bind(addr).await.context("binding address")?;
-        info!("Proxy server started on {}", listener.local_addr().unwrap());
+    pub fn servers(&self) -> &ServerList {
+        &self.servers
+    }
+
+    pub fn plugins(&self) -> &Plugins {
+        &self.plugins
+    }
+
+    pub fn logger(&self) -> &PacketLogger {
+        &self.logger
+    }
+
+    pub fn commands(&self) -> &Commands {
+        &self.commands
+    }
+
+    /// Run the proxy server until it's asked to stop, then shut down
+    /// gracefully
+    pub async fn start(mut self) -> anyhow::Result<()> {
+        let mut listeners = Vec::new();
+        for Listener { address, server } in std::mem::take(&mut self.listeners) {
+            let listener = TcpListener::bind(address)
+                .await
+                .with_context(|| format!("binding address {}", address))?;
+            let local_addr = listener.local_addr().unwrap();
+            match &server {
+                Some(server) => info!("Proxy server for {} started on {}", server.name, local_addr),
+                None => info!("Proxy server started on {}", local_addr),
+            }
+            listeners.push((listener, server));
+        }
+
+        if self.config.policy.server {
+            self.start_policy_server().await?;
+        }
 
+        // each session holds a sender, so the receiver finishes once they've
+        // all ended
+        let (done_tx, done_rx) = mpsc::channel(1);
         let proxy = Arc::new(self);
+        let accepting = listeners.into_iter().map(|(listener, server)| {
+            proxy
+                .clone()
+                .accept_clients(listener, server, done_tx.clone())
+        });
+        let accepting = join_all(accepting);
+        drop(done_tx);
+
+        // the listeners keep accepting until the proxy is asked to stop, and
+        // it always shuts down gracefully, even if waiting for that failed
+        let result = select! {
+            _ = accepting => Ok(()),
+            result = shutdown_signal() => result.context("waiting for shutdown signal"),
+        };
+        proxy.shutdown(done_rx).await;
+        result
+    }
+
+    /// Tell players the proxy is stopping, and wait for their sessions to end
+    /// before the shutdown deadline, after which they're closed
+    async fn shutdown(&self, mut done: mpsc::Receiver<()>) {
+        let active = self.active_sessions.load(Ordering::SeqCst);
+        info!("Shutting down, no longer accepting clients");
+        if active > 0 {
+            info!(
+                "Waiting up to {}s for {} sessions to end, press Ctrl-C again to stop now",
+                self.config.shutdown.timeout, active
+            );
+            let _ = self.shutdown.broadcast(ShutdownStage::Draining);
+
+            let deadline = Duration::from_secs(self.config.shutdown.timeout);
+            select! {
+                _ = done.recv() => {}
+                _ = delay_for(deadline) => self.close_sessions(&mut done).await,
+                _ = shutdown_signal() => self.close_sessions(&mut done).await,
+            }
+        }
+
+        let served = self.next_session.load(Ordering::SeqCst);
+        let failed = self.failed_sessions.load(Ordering::SeqCst);
+        info!(
+            "Proxy stopped after serving {} sessions ({} ended with errors)",
+            served, failed
+        );
+    }
+
+    /// Close the remaining sessions, and give them a moment to finish up
+    async fn close_sessions(&self, done: &mut mpsc::Receiver<()>) {
+        let active = self.active_sessions.load(Ordering::SeqCst);
+        warn!("Closing {} remaining sessions", active);
+        let _ = self.shutdown.broadcast(ShutdownStage::Closing);
+
+        if timeout(Duration::from_secs(5), done.recv()).await.is_err() {
+            warn!("Some sessions didn't close in time");
+            self.flush_captures();
+        }
+    }
+
+    /// Write out the captures of sessions which are still running
+    fn flush_captures(&self) {
+        for (addr, capture) in self.captures.lock().unwrap().iter() {
+            if let Err(e) = capture.flush() {
+                error!("Error writing capture of session from {}: {}", addr, e);
+            }
+        }
+    }
+
+    /// Accept clients on a listener, connecting them to the listener's server
+    /// if it has one
+    ///
+    /// Each session holds a clone of the given sender until it ends. Errors
+    /// accepting clients are logged, and don't stop the listener.
+    async fn accept_clients(
+        self: Arc<Self>,
+        mut listener: TcpListener,
+        server: Option<Server>,
+        done: mpsc::Sender<()>,
+    ) {
         loop {
-            let (conn, addr) = listener.accept().await.context("accepting client")?;
+            let (conn, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // errors like running out of file descriptors usually
+                    // clear up, so wait a moment rather than spinning
+                    error!("Error accepting client: {}", e);
+                    delay_for(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
             debug!("Spawning task to handle connection from {}", addr);
 
-            let proxy = proxy.clone();
+            let proxy = self.clone();
+            let server = server.clone();
+            let done = done.clone();
             tokio::spawn(async move {
-                match proxy.handle_connection(conn, addr).await {
+                proxy.active_sessions.fetch_add(1, Ordering::SeqCst);
+                match proxy.clone().handle_connection(conn, addr, server).await {
                     Ok(()) => (),
-                    Err(e) => error!("Unexpected client error: {}", e),
+                    Err(e) => {
+                        proxy.failed_sessions.fetch_add(1, Ordering::SeqCst);
+                        error!("Unexpected client error: {}", e);
+                    }
                 };
+                proxy.active_sessions.fetch_sub(1, Ordering::SeqCst);
+                drop(done);
             });
         }
     }
 
+    /// Start the standalone policy file server in the background
+    async fn start_policy_server(&self) -> anyhow::Result<()> {
+        let addr = (self.config.listen.ip, self.config.policy.server_port);
+        let server = PolicyServer::bind(
+            addr,
+            self.policy_file.clone(),
+            PolicyServerConfig::default(),
+        )
+        .await
+        .context("binding policy server address")?;
+        info!("Policy server started on {}", server.local_addr().unwrap());
+
+        tokio::spawn(server.run());
+
+        Ok(())
+    }
+
     async fn handle_connection(
         self: Arc<Self>,
         conn: TcpStream,
         addr: SocketAddr,
+        server: Option<Server>,
     ) -> anyhow::Result<()> {
-        let (mut client_rx, mut client_tx) =
-            match accept_with_policy(conn, &self.keys, &self.policy_file).await? {
-                Some(c) => c,
-                None => {
-                    info!("Responded to policy file request from {}", addr);
+        // reconnects are redirected to the address the client reached us on
+        let local_addr = conn.local_addr()?;
+
+        // sessions which haven't started yet are closed as soon as the proxy
+        // starts shutting down
+        let mut shutdown = self.shutdown_rx.clone();
+        let accepted = select! {
+            accepted = accept_with_policy(conn, &self.keys, &self.policy_file, &self.connection) => accepted?,
+            _ = next_stage(&mut shutdown, ShutdownStage::Running) => {
+                info!("Closing connection from {} for shutdown", addr);
+                return Ok(());
+            }
+        };
+        let (mut client_rx, mut client_tx) = match accepted {
+            Some(c) => c,
+            None => {
+                info!("Responded to policy file request from {}", addr);
+                return Ok(());
+            }
+        };
+
+        info!("Accepted game connection from {}", addr);
+
+        let metrics = ConnectionMetrics::new(&self.params.packets);
+        metrics.attach(&mut client_rx, &mut client_tx);
+
+        let capture = match &self.config.capture.dir {
+            Some(dir) => {
+                let capture = self.start_capture(dir, addr)?;
+                capture.attach(&mut client_rx, &mut client_tx);
+                self.captures.lock().unwrap().insert(addr, capture.clone());
+                Some(capture)
+            }
+            None => None,
+        };
+
+        // the session is torn down however it ends, so plugins are told about
+        // the disconnect and the capture is complete even after an error
+        let mut session = None;
+        let result = async {
+            // the Hello packet decides which server to connect to
+            let hello = select! {
+                hello = client_rx.recv() => match hello? {
+                    Some(packet) => packet.to_owned(),
+                    None => return Ok(()),
+                },
+                _ = next_stage(&mut shutdown, ShutdownStage::Running) => {
+                    info!("Closing connection from {} for shutdown", addr);
                     return Ok(());
                 }
             };
+            let (hello, route) = self.route_hello(hello, addr.ip(), server.as_ref())?;
 
-        info!("Accepted game connection from {}", addr);
+            let name = self
+                .servers
+                .get_by_host(&route.host)
+                .map_or("unknown server", |s| s.name.as_str());
+            info!(
+                "Connecting {} to {} ({}:{})",
+                addr, name, route.host, route.port
+            );
+            let connecting = connect(
+                (route.host.as_str(), route.port),
+                &self.keys,
+                &self.connection,
+            );
+            let (mut server_rx, mut server_tx) = select! {
+                connected = connecting => connected.context("connecting to real server")?,
+                _ = next_stage(&mut shutdown, ShutdownStage::Running) => {
+                    info!("Closing connection from {} for shutdown", addr);
+                    return Ok(());
+                }
+            };
+            server_tx.send(hello).await?;
 
-        let (mut server_rx, mut server_tx) = connect(
-            (self.servers.get_default(), self.params.basic.port),
-            &self.keys,
-        )
-        .await
-        .context("connecting to real server")?;
+            let id = self.next_session.fetch_add(1, Ordering::Relaxed);
+            let session = session.get_or_insert_with(|| Session::new(id, addr));
+            self.plugins.connect(session);
+            self.send_injected(session, &mut client_tx, &mut server_tx)
+                .await?;
 
-        loop {
-            select! {
-                Some(r) = client_rx.maybe_recv() => {
-                    let raw_packet: &mut RawPacket = r?;
-                    server_tx.send(raw_packet).await?;
-                },
-                Some(r) = server_rx.maybe_recv() => {
-                    let raw_packet = r?;
-                    client_tx.send(raw_packet).await?;
-                },
-                else => break,
+            // the session lasts until both sides close, or the proxy shuts down
+            let mut stage = ShutdownStage::Running;
+            let (mut client_open, mut server_open) = (true, true);
+            while client_open || server_open {
+                select! {
+                    r = client_rx.maybe_recv(), if client_open => {
+                        let raw_packet: &mut RawPacket = match r {
+                            Some(r) => r?,
+                            None => {
+                                // the server is told the client left, so it
+                                // can close its side too
+                                client_open = false;
+                                server_tx.shutdown().await?;
+                                continue;
+                            }
+                        };
+                        let mut ctx = CommandContext {
+                            proxy: &self,
+                            session,
+                            metrics: &metrics,
+                            local_addr,
+                        };
+                        if self.handle_command(&mut ctx, raw_packet) {
+                            // commands aren't forwarded to the server
+                        } else {
+                            match self.plugins.client_packet(session, raw_packet, &self.params.packets, &metrics)? {
+                                Filtered::Original => server_tx.send(raw_packet).await?,
+                                Filtered::Replaced(packet) => server_tx.send(packet).await?,
+                                Filtered::Dropped => {}
+                            }
+                        }
+                    },
+                    r = server_rx.maybe_recv(), if server_open => {
+                        let raw_packet = match r {
+                            Some(r) => r?,
+                            None => {
+                                server_open = false;
+                                client_tx.shutdown().await?;
+                                continue;
+                            }
+                        };
+                        match self.plugins.server_packet(session, raw_packet, &self.params.packets, &metrics)? {
+                            Filtered::Original => {
+                                self.forward_to_client(&mut client_tx, raw_packet, &route, addr.ip(), local_addr).await?
+                            }
+                            Filtered::Replaced(mut packet) => {
+                                self.forward_to_client(&mut client_tx, &mut packet, &route, addr.ip(), local_addr).await?
+                            }
+                            Filtered::Dropped => {}
+                        }
+                    },
+                    new_stage = next_stage(&mut shutdown, stage) => {
+                        stage = new_stage;
+                        if stage == ShutdownStage::Closing {
+                            info!("Closing connection from {} for shutdown", addr);
+                            break;
+                        }
+                        session.send_to_client(reply("", self.config.shutdown.message.clone()));
+                    },
+                }
+                self.send_injected(session, &mut client_tx, &mut server_tx)
+                    .await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+
+        if let Some(session) = &session {
+            self.plugins.disconnect(session);
+        }
+
+        let stats = metrics.snapshot();
+        info!(
+            "Connection from {} closed ({} packets/{} bytes received, {} packets/{} bytes sent)",
+            addr,
+            stats.inbound.packets,
+            stats.inbound.bytes,
+            stats.outbound.packets,
+            stats.outbound.bytes
+        );
+
+        // an error from the session takes priority over one writing the capture
+        let flushed = match capture {
+            Some(capture) => {
+                self.captures.lock().unwrap().remove(&addr);
+                capture.flush().context("writing capture")
             }
+            None => Ok(()),
+        };
+        result.and(flushed)
+    }
+
+    /// Handle a packet from the client if it's a chat command, returning
+    /// whether it was
+    fn handle_command(&self, ctx: &mut CommandContext, packet: &RawPacket) -> bool {
+        if self.params.packets.to_game(PacketType::PlayerText) != Some(packet.id()) {
+            return false;
         }
+        let text = match packet.parse(&self.params.packets) {
+            Ok((text, _)) => text.downcast::<PlayerText>().unwrap().text,
+            Err(e) => {
+                debug!("Failed to parse PlayerText: {}", e);
+                return false;
+            }
+        };
 
-        info!("Connection from {} closed gracefully", addr);
+        let handled = self.commands.handle(ctx, &text);
+        if handled {
+            info!(
+                "Handled command from {}: {}",
+                ctx.session.client_addr(),
+                text
+            );
+        }
+        handled
+    }
 
+    /// Send a packet from the server to the client, intercepting reconnects
+    async fn forward_to_client(
+        &self,
+        client_tx: &mut Encoder<OwnedWriteHalf>,
+        packet: &mut RawPacket,
+        route: &Route,
+        client_ip: IpAddr,
+        local_addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        if self.params.packets.to_game(PacketType::Reconnect) == Some(packet.id()) {
+            let packet = self.intercept_reconnect(packet, route, client_ip, local_addr)?;
+            client_tx.send(packet).await?;
+        } else {
+            client_tx.send(packet).await?;
+        }
         Ok(())
     }
+
+    /// Move a session to another server, by telling the client to reconnect
+    /// to the proxy with a key that routes it to the server's nexus
+    ///
+    /// The server is also used for the client's future sessions.
+    pub fn switch_server(&self, session: &mut Session, server: &Server, local_addr: SocketAddr) {
+        info!("Switching {} to {}", session.client_addr(), server.name);
+        self.selected
+            .lock()
+            .unwrap()
+            .insert(session.client_addr().ip(), server.host.clone());
+
+        let token = self.reconnects.insert(
+            session.client_addr().ip(),
+            ReconnectTarget {
+                host: server.host.clone(),
+                port: self.params.basic.port,
+                key_time: 0,
+                key: Vec::new(),
+            },
+        );
+        session.send_to_client(Reconnect {
+            name: "Nexus".to_string(),
+            host: local_addr.ip().to_string(),
+            port: local_addr.port().into(),
+            game_id: self.params.basic.nexus_game_id as u32,
+            key_time: 0,
+            key: token,
+            ..Reconnect::default()
+        });
+    }
+
+    /// Send the packets plugins have queued for either side of a session
+    async fn send_injected(
+        &self,
+        session: &mut Session,
+        client_tx: &mut Encoder<OwnedWriteHalf>,
+        server_tx: &mut Encoder<OwnedWriteHalf>,
+    ) -> anyhow::Result<()> {
+        for packet in session.take_to_client() {
+            let packet = packet
+                .into_raw_mapped(&self.params.packets)
+                .context("encoding packet for client")?;
+            client_tx.send(packet).await?;
+        }
+        for packet in session.take_to_server() {
+            let packet = packet
+                .into_raw_mapped(&self.params.packets)
+                .context("encoding packet for server")?;
+            server_tx.send(packet).await?;
+        }
+        Ok(())
+    }
+
+    /// Decide which server to connect a session to based on its first packet,
+    /// which should be a `Hello`
+    ///
+    /// If the `Hello` carries a key from an intercepted reconnect, the session
+    /// is routed to the real reconnect target, and the real key is restored.
+    /// Otherwise, the session goes to the server of the listener the client
+    /// connected to, then the server chosen by the client, or the default
+    /// server if neither is set.
+    fn route_hello(
+        &self,
+        packet: Box<RawPacket>,
+        client_ip: IpAddr,
+        listener_server: Option<&Server>,
+    ) -> anyhow::Result<(Box<RawPacket>, Route)> {
+        let selected = self.selected.lock().unwrap().get(&client_ip).cloned();
+        let host = match (listener_server, selected) {
+            (Some(server), _) => server.host.clone(),
+            (None, Some(host)) => host,
+            (None, None) => self.servers.get_default().host.clone(),
+        };
+        let default = Route {
+            host,
+            port: self.params.basic.port,
+        };
+        if self.params.packets.to_game(PacketType::Hello) != Some(packet.id()) {
+            warn!("First packet from client wasn't a Hello packet");
+            return Ok((packet, default));
+        }
+
+        let (hello, _) = packet
+            .parse(&self.params.packets)
+            .context("parsing Hello")?;
+        let mut hello = hello.downcast::<Hello>().unwrap();
+        let target = match self.reconnects.take(&hello.key, client_ip) {
+            Some(target) => target,
+            None => return Ok((packet, default)),
+        };
+
+        debug!(
+            "Following reconnect to game {} on {}:{}",
+            hello.game_id, target.host, target.port
+        );
+        hello.key = target.key;
+        hello.key_time = target.key_time;
+        let packet = hello
+            .into_raw_mapped(&self.params.packets)
+            .context("encoding Hello")?;
+        let route = Route {
+            host: target.host,
+            port: target.port,
+        };
+        Ok((packet, route))
+    }
+
+    /// Redirect a `Reconnect` packet to the proxy, storing the real target so
+    /// that the client's next session can be routed to it
+    fn intercept_reconnect(
+        &self,
+        packet: &RawPacket,
+        route: &Route,
+        client_ip: IpAddr,
+        local_addr: SocketAddr,
+    ) -> anyhow::Result<Box<RawPacket>> {
+        let (reconnect, _) = packet
+            .parse(&self.params.packets)
+            .context("parsing Reconnect")?;
+        let mut reconnect = reconnect.downcast::<Reconnect>().unwrap();
+
+        // an empty host or a port of -1 means the current server
+        let host = if reconnect.host.is_empty() {
+            route.host.clone()
+        } else {
+            reconnect.host.clone()
+        };
+        let port = match reconnect.port {
+            u32::MAX => route.port,
+            port if port <= u16::MAX as u32 => port as u16,
+            port => bail!("invalid reconnect port {}", port),
+        };
+        info!(
+            "Intercepted reconnect to {} ({}:{})",
+            reconnect.name, host, port
+        );
+
+        let token = self.reconnects.insert(
+            client_ip,
+            ReconnectTarget {
+                host,
+                port,
+                key_time: reconnect.key_time,
+                key: std::mem::take(&mut reconnect.key),
+            },
+        );
+        reconnect.host = local_addr.ip().to_string();
+        reconnect.port = local_addr.port().into();
+        reconnect.key = token;
+
+        reconnect
+            .into_raw_mapped(&self.params.packets)
+            .context("encoding Reconnect")
+    }
+
+    /// Create a new capture file for a session in the given directory
+    fn start_capture(
+        &self,
+        dir: &Path,
+        addr: SocketAddr,
+    ) -> anyhow::Result<CaptureWriter<BufWriter<File>>> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = dir.join(format!("{}-{}-{}.rotcap", started, addr.ip(), addr.port()));
+
+        info!("Recording session from {} to {}", addr, path.display());
+        CaptureWriter::create(&path, &self.params).context("creating capture file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Proxy;
+    use crate::config::Config;
+    use crate::plugin::{Action, ProxyPlugin, Session};
+    use crate::servers::{Server, ServerList};
+    use rotmg_mock_server::{MockServer, MockServerConfig, Scenario};
+    use rotmg_networking::{connect, ConnectionConfig};
+    use rotmg_packets::structured::packets::client::{Hello, PlayerText};
+    use rotmg_packets::structured::packets::server::Reconnect;
+    use rotmg_packets::structured::packets::{AnyPacket, PacketType};
+    use rotmg_packets::{BasicParameters, PacketMappings, Parameters};
+    use std::env::temp_dir;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    fn params(port: u16) -> Parameters {
+        // offset the IDs so that using internal IDs by mistake would be caught
+        Parameters {
+            packets: PacketMappings::new(
+                PacketType::VALUES
+                    .iter()
+                    .map(|&t| (t, (t as u8).wrapping_add(100))),
+            ),
+            rc4: "0102030405060708090a".to_string(),
+            basic: BasicParameters {
+                version: "1.0".to_string(),
+                port,
+                tutorial_game_id: -1,
+                nexus_game_id: -2,
+                random_game_id: -3,
+            },
+        }
+    }
+
+    fn text(text: &str) -> PlayerText {
+        PlayerText {
+            text: text.to_string(),
+        }
+    }
+
+    /// Drops the player saying "drop", and says "injected" instead
+    struct Replace;
+
+    impl ProxyPlugin for Replace {
+        fn name(&self) -> &str {
+            "replace"
+        }
+
+        fn on_client_packet(&self, session: &mut Session, packet: &dyn AnyPacket) -> Action {
+            match packet.downcast_ref::<PlayerText>() {
+                Some(t) if t.text == "drop" => {
+                    session.send_boxed_to_server(Box::new(text("injected")));
+                    Action::Drop
+                }
+                _ => Action::Pass,
+            }
+        }
+    }
+
+    /// Connect to the proxy with the given key, say "drop" then "kept", and
+    /// return the `Reconnect` the server responds with
+    async fn run_client(proxy: SocketAddr, key: Vec<u8>) -> Reconnect {
+        let params = params(0);
+        let keys = hex::decode(&params.rc4).unwrap();
+        let (mut rx, mut tx) = connect(proxy, &keys, &ConnectionConfig::default())
+            .await
+            .unwrap();
+
+        let send = |packet: Box<dyn AnyPacket>| packet.into_raw_mapped(&params.packets).unwrap();
+        let hello = Hello {
+            build_version: params.basic.version.clone(),
+            key,
+            ..Hello::default()
+        };
+        tx.send(send(Box::new(hello))).await.unwrap();
+        tx.send(send(Box::new(text("drop")))).await.unwrap();
+        tx.send(send(Box::new(text("kept")))).await.unwrap();
+
+        while let Some(packet) = rx.recv().await.unwrap() {
+            let (packet, _) = packet.parse(&params.packets).unwrap();
+            if let Ok(reconnect) = packet.downcast::<Reconnect>() {
+                return *reconnect;
+            }
+        }
+        panic!("proxy closed the connection before the reconnect");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_session() {
+        // the server sends the client back to itself, as it does when
+        // changing to a map on the same server
+        let scenario = Scenario::new()
+            .expect_with(|t: &PlayerText| t.text == "injected")
+            .expect_with(|t: &PlayerText| t.text == "kept")
+            .send(Reconnect {
+                name: "Vault".to_string(),
+                host: String::new(),
+                port: u32::MAX,
+                key: vec![1, 2, 3],
+                ..Reconnect::default()
+            })
+            .expect_disconnect();
+        let mut server = MockServer::bind(
+            (Ipv4Addr::LOCALHOST, 0),
+            &params(0),
+            scenario,
+            MockServerConfig::default(),
+        )
+        .await
+        .unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let servers = ServerList::new(
+            vec![Server {
+                name: "Mock".to_string(),
+                host: server_addr.ip().to_string(),
+            }],
+            "Mock",
+        )
+        .unwrap();
+        let mut proxy = Proxy::new(
+            temp_dir(),
+            Config::default(),
+            params(server_addr.port()),
+            servers,
+        )
+        .await
+        .unwrap();
+        proxy.register_plugin(Replace);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let (done, _) = mpsc::channel(1);
+        tokio::spawn(Arc::new(proxy).accept_clients(listener, None, done));
+
+        let client = tokio::spawn(async move {
+            let reconnect = run_client(proxy_addr, Vec::new()).await;
+            // the reconnect is redirected to the proxy, with the real key
+            // swapped for a token
+            assert_eq!(reconnect.host, proxy_addr.ip().to_string());
+            assert_eq!(reconnect.port, proxy_addr.port() as u32);
+            assert_ne!(reconnect.key, vec![1, 2, 3]);
+            run_client(proxy_addr, reconnect.key).await;
+        });
+
+        let first = server.run_session().await.unwrap();
+        assert!(first.hello.key.is_empty());
+        let texts = first
+            .received_of::<PlayerText>()
+            .map(|t| t.text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, vec!["injected", "kept"]);
+
+        // following the reconnect reaches the same server with the real key
+        let second = server.run_session().await.unwrap();
+        assert_eq!(second.hello.key, vec![1, 2, 3]);
+        client.await.unwrap();
+    }
 }
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Prefix of the keys given to clients in place of real reconnect keys
+const TOKEN_PREFIX: &[u8] = b"rotmg_proxy:";
+
+/// The number of random bytes in each token
+const TOKEN_LEN: usize = 16;
+
+/// How long a reconnect target is kept after the server sends it
+const TARGET_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// The real destination of a `Reconnect` packet, which the client is given a
+/// token for instead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectTarget {
+    /// The host of the real server
+    pub host: String,
+
+    /// The port of the real server
+    pub port: u16,
+
+    /// The key time to send in the `Hello` packet
+    pub key_time: u32,
+
+    /// The key to send in the `Hello` packet
+    pub key: Vec<u8>,
+}
+
+/// A stored reconnect target, and the client it was sent to
+struct Entry {
+    target: ReconnectTarget,
+    client: IpAddr,
+    created: Instant,
+}
+
+/// Reconnect targets waiting for the client to reconnect, by token
+///
+/// The token is sent to the client as the reconnect key, which the client
+/// sends back in the `Hello` packet of its next connection. Tokens are random,
+/// can only be used by the client they were sent to, and only work once.
+#[derive(Default)]
+pub struct ReconnectStore {
+    targets: Mutex<HashMap<[u8; TOKEN_LEN], Entry>>,
+}
+
+impl ReconnectStore {
+    /// Store a reconnect target sent to the given client, returning the key to
+    /// give the client
+    pub fn insert(&self, client: IpAddr, target: ReconnectTarget) -> Vec<u8> {
+        let token: [u8; TOKEN_LEN] = rand::random();
+        let now = Instant::now();
+
+        let mut targets = self.targets.lock().unwrap();
+        targets.retain(|_, entry| now.duration_since(entry.created) < TARGET_LIFETIME);
+        targets.insert(
+            token,
+            Entry {
+                target,
+                client,
+                created: now,
+            },
+        );
+
+        let mut key = TOKEN_PREFIX.to_vec();
+        key.extend_from_slice(&token);
+        key
+    }
+
+    /// Remove and return the reconnect target for a key sent by the client, if
+    /// it's a token for one that was sent to the same client and hasn't expired
+    pub fn take(&self, key: &[u8], client: IpAddr) -> Option<ReconnectTarget> {
+        if key.len() != TOKEN_PREFIX.len() + TOKEN_LEN || !key.starts_with(TOKEN_PREFIX) {
+            return None;
+        }
+        let token: [u8; TOKEN_LEN] = key[TOKEN_PREFIX.len()..].try_into().unwrap();
+
+        let mut targets = self.targets.lock().unwrap();
+        match targets.get(&token) {
+            Some(entry) if entry.client == client => {}
+            _ => return None,
+        }
+        targets
+            .remove(&token)
+            .filter(|entry| entry.created.elapsed() < TARGET_LIFETIME)
+            .map(|entry| entry.target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReconnectStore, ReconnectTarget};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_reconnect_store() {
+        let store = ReconnectStore::default();
+        let client = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let target = ReconnectTarget {
+            host: "127.0.0.2".to_string(),
+            port: 2050,
+            key_time: 1234,
+            key: vec![1, 2, 3],
+        };
+
+        let token = store.insert(client, target.clone());
+        let other = store.insert(
+            client,
+            ReconnectTarget {
+                port: 2051,
+                ..target.clone()
+            },
+        );
+        assert_ne!(token, other);
+
+        // targets can only be used by the same client, and only once
+        assert_eq!(store.take(&token, Ipv4Addr::LOCALHOST.into()), None);
+        assert_eq!(store.take(&token, client), Some(target));
+        assert_eq!(store.take(&token, client), None);
+        assert_eq!(store.take(&other, client).unwrap().port, 2051);
+
+        // real keys aren't mistaken for tokens
+        assert_eq!(store.take(&[], client), None);
+        assert_eq!(store.take(&[1, 2, 3], client), None);
+    }
+}
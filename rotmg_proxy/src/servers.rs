@@ -1,10 +1,11 @@
 use anyhow::Context;
-use log::info;
-use serde::Deserialize;
-use std::collections::HashMap;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs::{read_to_string, write};
 
-#[derive(Debug, Deserialize)]
-struct Server {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Server {
     /// The user-readable name of the server
     #[serde(alias = "Name")]
     pub name: String,
@@ -27,19 +28,55 @@ impl Server {
             .replace("mid", "m")
             .replace("australia", "aus")
     }
+
+    /// Check whether this server has the given name or abbreviation, ignoring
+    /// case
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.abbreviated_name().eq_ignore_ascii_case(name)
+    }
 }
 
 pub struct ServerList {
-    /// Map of server names/abbreviations -> address
-    map: HashMap<String, String>,
+    servers: Vec<Server>,
 
-    /// Default server address
-    default: String,
+    /// Index of the default server
+    default: usize,
 }
 
 impl ServerList {
-    /// Load the official ROTMG server list
-    pub async fn load(default: &str) -> anyhow::Result<Self> {
+    /// Load the official ROTMG server list, caching it in the given directory
+    ///
+    /// If the list can't be downloaded, or in offline mode, the cached list is
+    /// used instead.
+    pub async fn load(data_dir: &Path, default: &str, offline: bool) -> anyhow::Result<Self> {
+        let servers = Self::fetch(data_dir, offline).await?;
+        info!("Loaded {} servers", servers.len());
+        Self::new(servers, default)
+    }
+
+    /// Get the official ROTMG servers, caching them in the given directory
+    pub async fn fetch(data_dir: &Path, offline: bool) -> anyhow::Result<Vec<Server>> {
+        let file = data_dir.join("servers.json");
+
+        if offline {
+            Self::load_cached(&file).await
+        } else {
+            match Self::download().await {
+                Ok(servers) => {
+                    write(&file, serde_json::to_string_pretty(&servers)?)
+                        .await
+                        .context("saving server list")?;
+                    Ok(servers)
+                }
+                Err(e) => {
+                    warn!("Using cached server list: {:#}", e);
+                    Self::load_cached(&file).await
+                }
+            }
+        }
+    }
+
+    async fn download() -> anyhow::Result<Vec<Server>> {
         let list = reqwest::get("https://realmofthemadgodhrd.appspot.com/char/list")
             .await
             .context("getting server list")?
@@ -60,32 +97,72 @@ impl ServerList {
         }
 
         let list: Chars = serde_xml_rs::from_str(&list).context("parsing server list")?;
+        Ok(list.servers.server)
+    }
 
-        info!("Loaded {} servers", list.servers.server.len());
+    async fn load_cached(file: &Path) -> anyhow::Result<Vec<Server>> {
+        let list = read_to_string(file).await.with_context(|| {
+            format!(
+                "no cached server list at {}, run the proxy online once",
+                file.display()
+            )
+        })?;
+        serde_json::from_str(&list).context("parsing cached server list")
+    }
 
-        let mut map = HashMap::new();
-        for server in list.servers.server {
-            let abbr = server.abbreviated_name();
-            let Server { name, host } = server;
-            map.insert(name, host.clone());
-            map.insert(abbr, host);
-        }
+    /// Create a server list, with the default server given by name or
+    /// abbreviation
+    pub fn new(servers: Vec<Server>, default: &str) -> anyhow::Result<Self> {
+        let default = servers
+            .iter()
+            .position(|s| s.matches(default))
+            .with_context(|| format!("unknown default server {}", default))?;
 
-        let default = map
-            .get(default)
-            .context("getting default server address")?
-            .to_string();
+        Ok(Self { servers, default })
+    }
+
+    /// Get a server from this list by name or abbreviation, ignoring case
+    pub fn get(&self, name: &str) -> Option<&Server> {
+        self.servers.iter().find(|s| s.matches(name))
+    }
+
+    /// Get the server with the given host, if it's in this list
+    pub fn get_by_host(&self, host: &str) -> Option<&Server> {
+        self.servers.iter().find(|s| s.host == host)
+    }
 
-        Ok(Self { map, default })
+    /// Get the default server
+    pub fn get_default(&self) -> &Server {
+        &self.servers[self.default]
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Server, ServerList};
 
-    /// Get the address of a server from this list
-    pub fn get(&self, name: &str) -> Option<&str> {
-        self.map.get(name).map(|s| s.as_str())
+    fn server(name: &str, host: &str) -> Server {
+        Server {
+            name: name.to_string(),
+            host: host.to_string(),
+        }
     }
 
-    /// Get the address of the default server
-    pub fn get_default(&self) -> &str {
-        &self.default
+    #[test]
+    fn test_server_list() {
+        let servers = vec![
+            server("USEast", "1.1.1.1"),
+            server("AsiaSouthEast", "2.2.2.2"),
+        ];
+        let list = ServerList::new(servers, "use").unwrap();
+        assert_eq!(list.get_default().name, "USEast");
+
+        assert_eq!(list.get("AsiaSouthEast").unwrap().host, "2.2.2.2");
+        assert_eq!(list.get("asiasoutheast").unwrap().host, "2.2.2.2");
+        assert_eq!(list.get("asse").unwrap().host, "2.2.2.2");
+        assert_eq!(list.get_by_host("1.1.1.1").unwrap().name, "USEast");
+        assert!(list.get("USWest").is_none());
+
+        assert!(ServerList::new(Vec::new(), "USEast").is_err());
     }
 }
@@ -0,0 +1,212 @@
+//! Subcommands for working with ROTMG data without running the proxy
+
+use crate::config::Config;
+use crate::data::{extract_params, load_params, ClientSource};
+use crate::servers::ServerList;
+use anyhow::Context;
+use log::{info, warn};
+use rotmg_networking::capture::CaptureReader;
+use rotmg_networking::stats::Direction;
+use rotmg_networking::{accept, connect, ConnectionConfig};
+use rotmg_packets::raw::RawPacket;
+use rotmg_packets::structured::packets::PacketType;
+use rotmg_packets::Parameters;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::net::TcpListener;
+use tokio::select;
+
+/// Extract client data and write it to the given file, or standard output
+pub async fn extract(source: &ClientSource, output: Option<PathBuf>) -> anyhow::Result<()> {
+    let data = extract_params(source, None).await?;
+    let json = serde_json::to_string_pretty(&data)?;
+
+    match output {
+        Some(path) => {
+            tokio::fs::write(&path, json)
+                .await
+                .context("writing client data")?;
+            info!("Wrote client data to {}", path.display());
+        }
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+/// Print the server list with the abbreviations of each server
+pub async fn servers(data_dir: &Path, offline: bool) -> anyhow::Result<()> {
+    let mut servers = ServerList::fetch(data_dir, offline).await?;
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for server in &servers {
+        println!(
+            "{:<16} {:<8} {}",
+            server.name,
+            server.abbreviated_name(),
+            server.host
+        );
+    }
+    Ok(())
+}
+
+/// Decode a packet given as hex and print it
+pub fn decode(params: &Parameters, hex: &str) -> anyhow::Result<()> {
+    let hex: String = hex.split_whitespace().collect();
+    let bytes = hex::decode(&hex).context("parsing packet hex")?;
+    let packet = RawPacket::from_slice(&bytes).context("invalid packet")?;
+
+    let (parsed, leftover) = packet
+        .parse(&params.packets)
+        .map_err(|e| anyhow::anyhow!("{}", e))
+        .context("parsing packet")?;
+    if !leftover.is_empty() {
+        warn!(
+            "{} leftover bytes, the packet definition may be out of date: {}",
+            leftover.len(),
+            hex::encode(leftover)
+        );
+    }
+
+    println!("{:#?}", parsed);
+    Ok(())
+}
+
+/// Print the packets in a capture, or replay them over the network as either
+/// end of the connection
+pub async fn replay(
+    capture: &Path,
+    connect_to: Option<SocketAddr>,
+    listen: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let mut capture = CaptureReader::open(capture).context("opening capture")?;
+    let params = capture.parameters().clone();
+    let keys = hex::decode(&params.rc4).context("parsing RC4 keys")?;
+    let config = ConnectionConfig::default();
+
+    let (mut rx, mut tx, direction) = match (connect_to, listen) {
+        (Some(addr), _) => {
+            info!("Connecting to {}", addr);
+            let (rx, tx) = connect(addr, &keys, &config)
+                .await
+                .with_context(|| format!("connecting to {}", addr))?;
+            (rx, tx, Direction::Inbound)
+        }
+        (None, Some(addr)) => {
+            let mut listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("listening on {}", addr))?;
+            info!("Waiting for a client on {}", addr);
+            let (conn, client) = listener.accept().await.context("accepting client")?;
+            info!("Client connected from {}", client);
+            let (rx, tx) = accept(conn, &keys, &config)?;
+            (rx, tx, Direction::Outbound)
+        }
+        (None, None) => return print_capture(capture, &params),
+    };
+
+    // read whatever the other end sends, so it isn't blocked while replaying
+    let mut received = 0;
+    let drain = async {
+        while rx.recv().await?.is_some() {
+            received += 1;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    select! {
+        result = capture.replay(&mut tx, direction) => result.context("replaying capture")?,
+        result = drain => {
+            result.context("receiving packets")?;
+            warn!("Connection closed before the replay finished");
+        }
+    }
+
+    info!("Replay finished, received {} packets", received);
+    Ok(())
+}
+
+/// Print each packet in a capture, parsing them when possible
+fn print_capture<R: std::io::Read>(
+    capture: CaptureReader<R>,
+    params: &Parameters,
+) -> anyhow::Result<()> {
+    for record in capture {
+        let record = record.context("reading capture")?;
+        let time = record.timestamp.as_secs_f64();
+        let sender = match record.direction {
+            Direction::Inbound => "C->S",
+            Direction::Outbound => "S->C",
+        };
+
+        match record.packet.parse(&params.packets) {
+            Ok((parsed, _)) => println!("[{:>9.3}] {} {:?}", time, sender, parsed),
+            Err(_) => println!("[{:>9.3}] {} {:?}", time, sender, record.packet),
+        }
+    }
+    Ok(())
+}
+
+/// Print the differences between the client data in two files
+pub async fn diff_mappings(old: &Path, new: &Path) -> anyhow::Result<()> {
+    let old = load_params(old).await?;
+    let new = load_params(new).await?;
+    let mut changes = Vec::new();
+
+    let mut compare = |name: &str, old: String, new: String| {
+        if old != new {
+            changes.push(format!("{}: {} -> {}", name, old, new));
+        }
+    };
+    compare("version", old.basic.version, new.basic.version);
+    compare(
+        "port",
+        old.basic.port.to_string(),
+        new.basic.port.to_string(),
+    );
+    compare(
+        "tutorial game ID",
+        old.basic.tutorial_game_id.to_string(),
+        new.basic.tutorial_game_id.to_string(),
+    );
+    compare(
+        "nexus game ID",
+        old.basic.nexus_game_id.to_string(),
+        new.basic.nexus_game_id.to_string(),
+    );
+    compare(
+        "random game ID",
+        old.basic.random_game_id.to_string(),
+        new.basic.random_game_id.to_string(),
+    );
+    compare("RC4 keys", old.rc4, new.rc4);
+
+    let mut types = PacketType::VALUES.to_vec();
+    types.sort_by_key(|t| t.name());
+    for typ in types {
+        match (old.packets.to_game(typ), new.packets.to_game(typ)) {
+            (Some(old), Some(new)) if old != new => {
+                changes.push(format!("{}: {} -> {}", typ.name(), old, new))
+            }
+            (Some(old), None) => changes.push(format!("{}: {} -> unmapped", typ.name(), old)),
+            (None, Some(new)) => changes.push(format!("{}: unmapped -> {}", typ.name(), new)),
+            _ => {}
+        }
+    }
+
+    if changes.is_empty() {
+        println!("No differences");
+    }
+    for change in changes {
+        println!("{}", change);
+    }
+    Ok(())
+}
+
+/// Check that a config file and the files it refers to are valid
+pub async fn check_config(data_dir: &Path, path: Option<PathBuf>) -> anyhow::Result<()> {
+    let path = path.unwrap_or_else(|| Config::default_path(data_dir));
+    let config = Config::load(&path, true).await?;
+    config.check(data_dir).await?;
+
+    println!("{} is valid", path.display());
+    Ok(())
+}